@@ -0,0 +1,73 @@
+// CHIP-8X color map of the VP-590 color board. The foreground color is set
+// per zone of 8x1 pixels, the background color for the whole screen.
+use serde::{Deserialize, Serialize};
+
+use super::cpu::{HEIGHT, WIDTH};
+
+const COLUMNS: usize = WIDTH / 8;
+
+// 02A0 steps through these
+const BACKGROUND_COLORS: [u32; 4] = [0x0000_0080, 0x0000_0000, 0x0000_8000, 0x0080_0000];
+
+const FOREGROUND_COLORS: [u32; 8] = [
+    0x0000_0000, // black
+    0x00FF_0000, // red
+    0x0000_00FF, // blue
+    0x00FF_00FF, // violet
+    0x0000_FF00, // green
+    0x00FF_FF00, // yellow
+    0x0000_FFFF, // aqua
+    0x00FF_FFFF, // white
+];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ColorMap {
+    background: usize,
+    zones: Vec<u8>,
+}
+
+impl Default for ColorMap {
+    fn default() -> Self {
+        // the interpreter starts with a red foreground on a blue background
+        Self {
+            background: 0,
+            zones: vec![1; COLUMNS * HEIGHT],
+        }
+    }
+}
+
+impl ColorMap {
+    // 02A0
+    pub fn next_background(&mut self) {
+        self.background = (self.background + 1) % BACKGROUND_COLORS.len();
+    }
+
+    // Bxy0, zones of 8x4 pixels, the low nibbles are the top left zone and
+    // the high nibbles the number of additional columns and rows
+    pub fn fill_area(&mut self, vx: u8, vy: u8, color: u8) {
+        let (left, right) = ((vx & 0xF) as usize, ((vx & 0xF) + (vx >> 4)) as usize);
+        let (top, bottom) = ((vy & 0xF) as usize, ((vy & 0xF) + (vy >> 4)) as usize);
+        for row in top * 4..((bottom + 1) * 4).min(HEIGHT) {
+            for col in left..(right + 1).min(COLUMNS) {
+                self.zones[row * COLUMNS + col] = color & 0x7;
+            }
+        }
+    }
+
+    // BxyN, n rows of the 8 pixel wide zone at pixel position vx, vy
+    pub fn fill_rows(&mut self, vx: u8, vy: u8, n: u8, color: u8) {
+        let col = (vx as usize / 8) % COLUMNS;
+        for row in vy as usize..vy as usize + n as usize {
+            self.zones[(row % HEIGHT) * COLUMNS + col] = color & 0x7;
+        }
+    }
+
+    // color of a lores pixel
+    pub fn color(&self, x: usize, y: usize, on: bool) -> u32 {
+        if on {
+            FOREGROUND_COLORS[self.zones[(y % HEIGHT) * COLUMNS + (x / 8) % COLUMNS] as usize]
+        } else {
+            BACKGROUND_COLORS[self.background]
+        }
+    }
+}
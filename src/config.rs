@@ -0,0 +1,1305 @@
+// The command line of ch8-rs, the arguments and how they resolve into an
+// Emulator: the interpreter variant of the rom, its quirks and speed, the
+// frontend and the debugging aids. Other frontends can offer the same options.
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{crate_authors, crate_name, crate_version, App, Arg, ArgMatches};
+
+use super::cpu::{Access, ClockMode, MemoryPolicy, OddPcPolicy, UnknownOpcodePolicy, Watchpoint};
+use super::debugger::{self, parse_addr, parse_watch, OpcodeClass};
+use super::emulator::{Emulator, FlickerReduction, Poke};
+use super::flags;
+use super::fonts::FontStyle;
+use super::frontend::{Backend, SoundIndicator};
+use super::gamepad::GamepadMap;
+use super::hotkeys::Hotkeys;
+use super::keymap::Keymap;
+use super::movie::Movie;
+use super::palette::{parse_color, Palette};
+use super::quirks::Profile;
+use super::render::{ColorAdjust, PostFilter, Scaling, TextureFilter};
+use super::savestate;
+use super::term::TermStyle;
+use super::wav::Wav;
+
+// phosphor decay of --flicker-reduction decay without --phosphor-decay
+const DEFAULT_DECAY: f64 = 0.6;
+
+fn parse_colors(input: &str) -> [u32; 4] {
+    let mut colors = [0u32; 4];
+    for (i, ccode) in input.split(',').take(4).enumerate() {
+        colors[i] = u32::from_str_radix(ccode, 16).unwrap()
+    }
+    colors
+}
+
+fn parse_segment(input: &str) -> Result<(u16, Vec<u8>), anyhow::Error> {
+    let at = input
+        .rfind('@')
+        .with_context(|| format!("Segment {} is not of the form file@addr", input))?;
+    let addr = parse_addr(&input[at + 1..])?;
+    let data = read_file(&input[..at])?;
+    Ok((addr, data))
+}
+
+fn parse_poke(input: &str) -> Result<Poke, anyhow::Error> {
+    let eq = input
+        .find('=')
+        .with_context(|| format!("Poke {} is not of the form addr=value[:frame]", input))?;
+    let addr = parse_addr(&input[..eq])?;
+    let (value, frame) = match input[eq + 1..].find(':') {
+        Some(colon) => {
+            let frame = &input[eq + 2 + colon..];
+            let frame = frame
+                .parse::<u64>()
+                .with_context(|| format!("Invalid frame {}", frame))?;
+            (&input[eq + 1..eq + 1 + colon], Some(frame))
+        }
+        None => (&input[eq + 1..], None),
+    };
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    let value =
+        u8::from_str_radix(digits, 16).with_context(|| format!("Invalid value {}", value))?;
+    Ok(Poke { addr, value, frame })
+}
+
+fn parse_size(input: &str) -> Result<(usize, usize), anyhow::Error> {
+    let x = input
+        .find('x')
+        .with_context(|| format!("Size {} is not of the form widthxheight", input))?;
+    let parse = |value: &str| {
+        value
+            .parse::<usize>()
+            .ok()
+            .filter(|value| *value > 0)
+            .with_context(|| format!("Invalid size {}", input))
+    };
+    Ok((parse(&input[..x])?, parse(&input[x + 1..])?))
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let f = File::open(path).with_context(|| format!("File {} cannot be opened", path))?;
+    let mut buf_reader = BufReader::new(f);
+    let mut data = Vec::<u8>::new();
+    buf_reader
+        .read_to_end(&mut data)
+        .with_context(|| format!("Could not read file {} to end", path))?;
+    Ok(data)
+}
+
+// The arguments of the ch8-rs binary
+pub fn app() -> App<'static, 'static> {
+    App::new(crate_name!())
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Chip 8 emulator")
+        .arg(
+            Arg::with_name("rom_path")
+                .help("Path to rom file")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .short("d")
+                .multiple(true)
+                .help("Turn debugging information on"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .help("Print the interpreter variant detected by --profile auto also for plain CHIP-8 roms"),
+        )
+        .arg(
+            Arg::with_name("debug-tui")
+                .long("debug-tui")
+                .help(
+                    "Show a debugger in the terminal (disassembly, registers, stack, memory, watches), \
+                     h switches to a memory heatmap, q closes it",
+                ),
+        )
+        .arg(
+            Arg::with_name("debugger")
+                .long("debugger")
+                .help(
+                    "Read debugger commands from stdin: pause, step, frame, continue, run ADDR, \
+                     break ADDR, delete ADDR, break-op CLASS, delete-op CLASS, mem ADDR[:LEN], \
+                     sprite ADDR [N], set REG VALUE, stack, rewind, reset, save N, load N, \
+                     goto-frame N, hot [N]",
+                ),
+        )
+        .arg(
+            Arg::with_name("start-paused")
+                .long("start-paused")
+                .help(
+                    "Pause before the first instruction, e.g. to set breakpoints with --debugger, P or F5 \
+                     continues",
+                ),
+        )
+        .arg(
+            Arg::with_name("break")
+                .long("break")
+                .value_name("ADDRS")
+                .help("Pause before executing the instructions at these comma separated addresses (hex)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("break-op")
+                .long("break-op")
+                .value_name("CLASSES")
+                .help(
+                    "Pause before executing any instruction of these comma separated classes: \
+                     a mnemonic like DRW or CALL, read-i or write-i for memory accesses through I",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("opcode-stats")
+                .long("opcode-stats")
+                .help("Print a histogram of the executed instructions and the ones never executed at exit"),
+        )
+        .arg(
+            Arg::with_name("hotspots")
+                .long("hotspots")
+                .value_name("N")
+                .help("Print the N most executed addresses with their share of all instructions at exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("crash-dump")
+                .long("crash-dump")
+                .value_name("FILE")
+                .help("Write the last executed instructions and the cpu state to FILE when the emulation fails")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("state-dir")
+                .long("state-dir")
+                .value_name("DIR")
+                .help(
+                    "Directory of the save state slots, Shift+1-9 saves and Ctrl+1-9 loads \
+                     [default: ch8-rs/states in the user data directory]",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("autosave")
+                .long("autosave")
+                .help("Save the state of the rom on exit into the state directory, for --resume"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Continue the rom where the last session with --autosave left off")
+                .conflicts_with_all(&["record", "replay"]),
+        )
+        .arg(
+            Arg::with_name("rewind")
+                .long("rewind")
+                .value_name("SECONDS")
+                .help("How far Backspace rewinds the emulation, states of the last minute are kept")
+                .takes_value(true)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("dump-memory")
+                .long("dump-memory")
+                .value_name("ADDR[:LEN]")
+                .help("Print a hex dump of LEN bytes (default 16) at ADDR (hex) whenever the debugger pauses")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace-file")
+                .long("trace-file")
+                .value_name("FILE")
+                .help("Write every executed instruction with its register values to FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("access-log")
+                .long("access-log")
+                .value_name("FILE")
+                .help("Write every memory read and write with the frame and the address of the instruction to FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("access-log-range")
+                .long("access-log-range")
+                .value_name("ADDR[:LEN]")
+                .help("Only log accesses to LEN bytes (default 1) at ADDR (hex) in the access log, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("access-log"),
+        )
+        .arg(
+            Arg::with_name("watch-read")
+                .long("watch-read")
+                .value_name("ADDR[:LEN]")
+                .help("Pause when an instruction reads one of LEN bytes (default 1) at ADDR (hex), can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("watch-write")
+                .long("watch-write")
+                .value_name("ADDR[:LEN]")
+                .help("Pause when an instruction writes one of LEN bytes (default 1) at ADDR (hex), can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .value_name("ADDR[:LEN]")
+                .help("Show LEN bytes (default 1) at memory address ADDR (hex) in the debugger, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("fps-limit")
+                .long("fps-limit")
+                .value_name("FPS")
+                .help("Limit loop that polls input and draws output")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ips-limit")
+                .long("ips-limit")
+                .value_name("IPS")
+                .help("Limits instructions per second")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ipf-limit")
+                .long("ipf-limit")
+                .value_name("IPF")
+                .help("Limits instructions per frame")
+                .takes_value(true)
+                .conflicts_with("ips-limit")
+                .requires("fps-limit"),
+        )
+        .arg(
+            Arg::with_name("turbo")
+                .long("turbo")
+                .value_name("FACTOR")
+                .help("Speeds up the instructions, the frames and the timers by FACTOR while Tab is held")
+                .takes_value(true)
+                .default_value("8"),
+        )
+        .arg(
+            Arg::with_name("slow-motion")
+                .long("slow-motion")
+                .value_name("FACTOR")
+                .help("Slows down the instructions, the frames and the timers to FACTOR, M toggles it")
+                .takes_value(true)
+                .default_value("0.25"),
+        )
+        .arg(
+            Arg::with_name("timer-mode")
+                .long("timer-mode")
+                .value_name("MODE")
+                .help(
+                    "Count down the delay and sound timers in real time (wall-clock) or once every \
+                     IPF executed instructions (instructions) for deterministic runs",
+                )
+                .takes_value(true)
+                .possible_values(&["wall-clock", "instructions"])
+                .default_value("wall-clock"),
+        )
+        .arg(
+            Arg::with_name("timer-hz")
+                .long("timer-hz")
+                .value_name("HZ")
+                .help("Frequency of the delay and sound timers, e.g. 50 for PAL machines")
+                .takes_value(true)
+                .default_value("60"),
+        )
+        .arg(
+            Arg::with_name("colors")
+                .long("colors")
+                .value_name("COLORS")
+                .help(
+                    "Four or two colors provided as four or two 6 digit hex numbers separated with a comma. \
+                     For chip8 and super-chip8 programs, two colors can be specified, representing the background \
+                     and foreground colors.\n\
+                     Example: 000000,FFFFFF sets the background color to black and the foreground color to white.\n\
+                     For xo-chip programs, four colors can be provided for the four possible combinations \
+                     of values in the two drawing planes.\n\
+                     Example: 000000,FF0000,00FF00,0000FF sets the colors for \"background, plane1, plane2, both planes blended\" or \
+                     in other words: it sets the \"background, fill1, fill2, blend\" colors\n",
+                )
+                .takes_value(true)
+                .default_value("00AA4400,00FFAA00,00AAAAAA,00000000"),
+        )
+        .arg(
+            Arg::with_name("palette-file")
+                .long("palette-file")
+                .value_name("FILE")
+                .help(
+                    "Load the colors from a file with lines like \"fill1 FFFFFF\" for background, fill1, fill2, \
+                     blend, buzzer and quiet, or from Octo options JSON. Buzzer and quiet color the bars around \
+                     the display while the sound timer runs and otherwise. --colors takes precedence",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("palette")
+                .long("palette")
+                .value_name("NAME")
+                .help(
+                    "Use a built-in palette, deuteranopia, protanopia and tritanopia keep the four XO-CHIP \
+                     colors apart for color blind players. --colors takes precedence",
+                )
+                .takes_value(true)
+                .possible_values(&["default", "octo", "deuteranopia", "protanopia", "tritanopia"])
+                .conflicts_with("palette-file"),
+        )
+        .arg(
+            Arg::with_name("border-color")
+                .long("border-color")
+                .value_name("COLOR")
+                .help(
+                    "Color of the bars around the display as 6 digit hex number, so that the background of \
+                     the display stays visible [default: 000000]",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("texture-filter")
+                .long("texture-filter")
+                .value_name("FILTER")
+                .help("Scale the display with sharp (nearest) or smoothed (linear) pixels, F2 toggles at runtime")
+                .takes_value(true)
+                .possible_values(&["nearest", "linear"])
+                .default_value("nearest"),
+        )
+        .arg(
+            Arg::with_name("record-gif")
+                .long("record-gif")
+                .value_name("FILE")
+                .help("Record the display into an animated GIF until exit, F9 starts and stops other recordings")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record-video")
+                .long("record-video")
+                .value_name("FILE")
+                .help(
+                    "Record every timer frame into an uncompressed .y4m video or let ffmpeg encode \
+                     other file types, also works without window with --verify-replay",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .value_name("N")
+                .help("Open the window at N times the 64x32 display size")
+                .takes_value(true)
+                .default_value("16"),
+        )
+        .arg(
+            Arg::with_name("fullscreen")
+                .long("fullscreen")
+                .help("Start in fullscreen, Alt+Enter or F11 switch between window and fullscreen"),
+        )
+        .arg(
+            Arg::with_name("keypad")
+                .long("keypad")
+                .help("Show the CHIP-8 keypad and the keys it is mapped to in the window, F1 hides it"),
+        )
+        .arg(
+            Arg::with_name("keymap")
+                .long("keymap")
+                .value_name("LAYOUT")
+                .help(
+                    "Keyboard layout of the 4x4 block of keys for the CHIP-8 keys, natural uses the keys \
+                     0 - 9 and A - F. The SDL2 and wgpu windows read the block by position in all layouts",
+                )
+                .takes_value(true)
+                .possible_values(&["qwerty", "azerty", "qwertz", "colemak", "natural"])
+                .default_value("qwerty"),
+        )
+        .arg(
+            Arg::with_name("gamepad-map")
+                .long("gamepad-map")
+                .value_name("FILE")
+                .help(
+                    "Load the CHIP-8 keys of game controller buttons in the SDL2 window from a file with lines \
+                     like \"dpup 2\" or \"a 5\", the d-pad presses 2, 4, 6 and 8 and A presses 5 by default",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hotkeys")
+                .long("hotkeys")
+                .value_name("FILE")
+                .help(
+                    "Load the keys of the pause, reset, rewind, turbo, slow-motion and record hotkeys \
+                     and the modifiers of save-state and load-state from a file with lines like \
+                     \"pause space\" or \"save-state alt\"",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote-input")
+                .long("remote-input")
+                .value_name("ADDR")
+                .help(
+                    "Listen on ADDR, e.g. 127.0.0.1:8456, for other programs that press CHIP-8 keys \
+                     over TCP with lines like \"down 5\" and \"up 5\"",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("term")
+                .long("term")
+                .conflicts_with_all(&["debug-tui", "debugger", "fullscreen"])
+                .help(
+                    "Show the display with colored characters in the terminal instead of a window, \
+                     Escape exits",
+                ),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .help(
+                    "Library of the window, sdl2 and wgpu need a build with --features sdl2 or \
+                     --features wgpu-backend",
+                )
+                .takes_value(true)
+                .possible_values(&["minifb", "sdl2", "wgpu"])
+                .default_value("minifb"),
+        )
+        .arg(
+            Arg::with_name("term-style")
+                .long("term-style")
+                .value_name("STYLE")
+                .help(
+                    "Draw 1x2 pixels per character as half blocks or 2x4 pixels as the dots of Braille \
+                     characters in the terminal, or the display as image in terminals that support sixel or \
+                     the Kitty graphics protocol",
+                )
+                .takes_value(true)
+                .possible_values(&["half-block", "braille", "sixel", "kitty"])
+                .default_value("half-block"),
+        )
+        .arg(
+            Arg::with_name("screen-size")
+                .long("screen-size")
+                .value_name("WIDTHxHEIGHT")
+                .help(
+                    "Size of the borderless window that stands in for fullscreen with the minifb \
+                     backend, which cannot ask the screen for its size, sdl2 and wgpu use the size \
+                     of the monitor",
+                )
+                .takes_value(true)
+                .default_value("1920x1080"),
+        )
+        .arg(
+            Arg::with_name("scaling")
+                .long("scaling")
+                .value_name("MODE")
+                .help(
+                    "Stretch the display over the window, keep its aspect ratio (aspect) or also only \
+                     scale by whole numbers (integer), with bars around it in the border color",
+                )
+                .takes_value(true)
+                .possible_values(&["stretch", "aspect", "integer"])
+                .default_value("aspect"),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("FILTER")
+                .help("Imitate an old screen with scanlines, a pixel grid or a curved picture, F3 cycles at runtime")
+                .takes_value(true)
+                .possible_values(&["none", "scanlines", "grid", "curvature"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("phosphor-decay")
+                .long("phosphor-decay")
+                .value_name("FACTOR")
+                .help(
+                    "Let pixels fade out like on a CRT to hide flickering sprites, FACTOR (between 0 \
+                     and 1, e.g. 0.6) is the brightness left after one frame",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("blend-frames")
+                .long("blend-frames")
+                .value_name("N")
+                .help("Show the average of the last N (2 to 4) displays to remove the flicker of XOR drawing")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("brightness")
+                .long("brightness")
+                .value_name("FACTOR")
+                .help("Multiply the display colors by FACTOR (0.1 to 3), F7 and F8 change it at runtime")
+                .takes_value(true)
+                .default_value("1.0"),
+        )
+        .arg(
+            Arg::with_name("gamma")
+                .long("gamma")
+                .value_name("GAMMA")
+                .help("Gamma correction of the display colors, values above 1 brighten the mid tones")
+                .takes_value(true)
+                .default_value("1.0"),
+        )
+        .arg(
+            Arg::with_name("invert")
+                .long("invert")
+                .help("Invert the display colors, e.g. for a light background, F4 toggles at runtime"),
+        )
+        .arg(
+            Arg::with_name("no-skip-frames")
+                .long("no-skip-frames")
+                .help("Do not skip frames - Frames are skipped by default. Same as --flicker-reduction none"),
+        )
+        .arg(
+            Arg::with_name("flicker-reduction")
+                .long("flicker-reduction")
+                .value_name("STRATEGY")
+                .help(
+                    "When the display is presented: none or off (on every display change), \
+                     sample (whenever the window is ready, skips inbetween states), \
+                     tick (once per 60 Hz timer tick) or decay (like sample with fading pixels, \
+                     see --phosphor-decay)",
+                )
+                .takes_value(true)
+                .possible_values(&["none", "off", "sample", "tick", "decay"])
+                .default_value("sample")
+                .conflicts_with("no-skip-frames"),
+        )
+        .arg(
+            Arg::with_name("entry")
+                .long("entry")
+                .value_name("ADDR")
+                .help("Start execution at ADDR (hex, e.g. 0x2A0) instead of the load address 0x200")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("load")
+                .long("load")
+                .value_name("FILE@ADDR")
+                .help("Load FILE into memory at ADDR (hex) before execution starts, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("patch")
+                .long("patch")
+                .value_name("FILE")
+                .help("Apply an IPS or BPS patch to the rom before loading it, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("odd-pc")
+                .long("odd-pc")
+                .value_name("POLICY")
+                .help(
+                    "What to do when the program counter is odd: allow (execute the unaligned \
+                     instruction), round-down (to the previous even address) or error (stop the program)",
+                )
+                .takes_value(true)
+                .possible_values(&["allow", "round-down", "error"])
+                .default_value("allow"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("PROFILE")
+                .help(
+                    "Use the quirks and speed of an interpreter variant, auto detects it from the \
+                     opcodes in the rom, --quirks and --ips-limit override single settings",
+                )
+                .takes_value(true)
+                .possible_values(&["auto", "chip8", "schip", "xochip", "megachip", "chip8x", "hires"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("quirks")
+                .long("quirks")
+                .value_name("LIST")
+                .help(
+                    "Comma separated quirks to enable, prefix with no- to disable: shift-vy, \
+                     increment-i, jump-vx, clip, vf-reset, display-wait, key-release, \
+                     fx1e-carry",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unknown-opcode")
+                .long("unknown-opcode")
+                .value_name("POLICY")
+                .help(
+                    "What to do on an unknown opcode: stop (halt the cpu and keep the window open), \
+                     skip (log it and continue) or error (exit with an error)",
+                )
+                .takes_value(true)
+                .possible_values(&["stop", "skip", "error"])
+                .default_value("error"),
+        )
+        .arg(
+            Arg::with_name("memory-bounds")
+                .long("memory-bounds")
+                .value_name("POLICY")
+                .help(
+                    "What to do when an access relative to I runs past the end of memory: wrap (continue \
+                     at 0), saturate (clamp to the last byte) or error (exit with an error)",
+                )
+                .takes_value(true)
+                .possible_values(&["wrap", "saturate", "error"])
+                .default_value("error"),
+        )
+        .arg(
+            Arg::with_name("no-persist-flags")
+                .long("no-persist-flags")
+                .help("Do not load and save the SCHIP flag registers (Fx75/Fx85) in the user data directory"),
+        )
+        .arg(
+            Arg::with_name("exit-on-loop")
+                .long("exit-on-loop")
+                .help("Exit successfully when the program jumps to itself, as test roms do when they are done"),
+        )
+        .arg(
+            Arg::with_name("loop-threshold")
+                .long("loop-threshold")
+                .value_name("N")
+                .help("Number of consecutive jumps to itself that count as an infinite loop (default 2)")
+                .takes_value(true)
+                .requires("exit-on-loop"),
+        )
+        .arg(
+            Arg::with_name("font-style")
+                .long("font-style")
+                .value_name("STYLE")
+                .help("Use the font of a historical interpreter, --font and --hires-font override it")
+                .takes_value(true)
+                .possible_values(&["default", "vip", "dream6800", "eti660", "octo"])
+                .default_value("default"),
+        )
+        .arg(
+            Arg::with_name("font")
+                .long("font")
+                .value_name("FILE")
+                .help("Replace the built-in font with an 80 byte file (16 characters of 4x5 pixels)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hires-font")
+                .long("hires-font")
+                .value_name("FILE")
+                .help("Replace the built-in SCHIP font with a 100 byte file (10 digits of 8x10 pixels)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed the random number generator for reproducible runs")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("poke")
+                .long("poke")
+                .value_name("ADDR=VALUE[:FRAME]")
+                .help(
+                    "Write the byte VALUE (hex) to memory address ADDR (hex) at startup \
+                     or when FRAME (60 Hz frames since start) is reached, can be repeated",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("verify-sha1")
+                .long("verify-sha1")
+                .value_name("HASH")
+                .help("Refuse to run if the SHA-1 of the rom file does not match HASH")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sha1-warn-only")
+                .long("sha1-warn-only")
+                .help("Only print a warning if --verify-sha1 does not match")
+                .requires("verify-sha1"),
+        )
+        .arg(
+            Arg::with_name("info")
+                .long("info")
+                .help("Print information about the rom (size, SHA-1) and exit"),
+        )
+        .arg(
+            Arg::with_name("verify-replay")
+                .long("verify-replay")
+                .value_name("MOVIE")
+                .help(
+                    "Play back the input movie without window and audio and check the state \
+                     checksums stored in it, exits with an error on divergence",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export-frame")
+                .long("export-frame")
+                .value_name("FILE")
+                .help(
+                    "Run the rom without window and audio and write the display at the end into a .png \
+                     or .ppm image",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export-frames")
+                .long("export-frames")
+                .value_name("N")
+                .help("Number of frames run by --export-frame")
+                .takes_value(true)
+                .default_value("60"),
+        )
+        .arg(
+            Arg::with_name("export-instructions")
+                .long("export-instructions")
+                .value_name("N")
+                .help("Number of instructions run by --export-frame instead of --export-frames")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("headless")
+                .long("headless")
+                .help(
+                    "Run the rom without window and audio as fast as possible with the keys of \
+                     --replay, then print the instructions per second",
+                )
+                .conflicts_with("record"),
+        )
+        .arg(
+            Arg::with_name("headless-frames")
+                .long("headless-frames")
+                .value_name("N")
+                .help("Stop --headless after N frames instead of when the program exits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sprites")
+                .long("sprites")
+                .value_name("FILE")
+                .help(
+                    "List the sprites the rom probably draws, write them into a .png or .ppm image \
+                     and exit",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sprite-height")
+                .long("sprite-height")
+                .value_name("N")
+                .help(
+                    "Cut the whole rom into sprites of N rows (0 for 16x16) for --sprites instead of \
+                     finding the drawn ones",
+                )
+                .takes_value(true)
+                .requires("sprites"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("MOVIE")
+                .help(
+                    "Record the pressed keys into an input movie, uses instruction counted timers \
+                     and a fixed seed so that the movie can be replayed",
+                )
+                .takes_value(true)
+                .conflicts_with("replay"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("MOVIE")
+                .help("Play back the keys of an input movie instead of the keyboard")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("diff-quirks")
+                .long("diff-quirks")
+                .value_name("LIST")
+                .help(
+                    "Run the rom without window twice, the second time with these quirk changes, \
+                     and report the first instruction after which the states differ",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verify-determinism")
+                .long("verify-determinism")
+                .help(
+                    "Run the rom without window twice with the same seed and keys and fail at the \
+                     first instruction after which the states differ",
+                ),
+        )
+        .arg(
+            Arg::with_name("diff-frames")
+                .long("diff-frames")
+                .value_name("N")
+                .help("Number of frames compared by --diff-quirks and --verify-determinism")
+                .takes_value(true)
+                .default_value("600"),
+        )
+        .arg(
+            Arg::with_name("diff-movie")
+                .long("diff-movie")
+                .value_name("MOVIE")
+                .help(
+                    "Feed the keys and seed of the input movie to both runs of --diff-quirks and \
+                     --verify-determinism",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("perf-counter")
+                .long("perf-counter")
+                .short("p")
+                .help("Show the instructions and frames per second in the top left corner of the window"),
+        )
+        .arg(
+            Arg::with_name("no-audio")
+                .long("no-audio")
+                .help("Do not open an audio device, the sound timer is silent"),
+        )
+        .arg(
+            Arg::with_name("buzzer-wav")
+                .long("buzzer-wav")
+                .value_name("FILE")
+                .help("Loop the sound of this WAV file while the sound timer runs instead of the tone")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lowpass")
+                .long("lowpass")
+                .value_name("HZ")
+                .help(
+                    "Soften the sound above this frequency, e.g. 3000 for the harsh edges of the \
+                     XO-CHIP patterns",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sound-indicator")
+                .long("sound-indicator")
+                .value_name("STYLE")
+                .help(
+                    "Show while the sound timer runs, as bars around the display in the buzzer color \
+                     or as a note in the top right corner, the terminal always shows the note",
+                )
+                .takes_value(true)
+                .possible_values(&["border", "icon"]),
+        )
+}
+
+// Builds the emulator for the rom from the parsed arguments, --profile auto looks at the
+// opcodes of the rom to pick the variant
+pub fn emulator(matches: &ArgMatches, code: &[u8]) -> Result<Emulator, anyhow::Error> {
+    let debug = matches.occurrences_of("debug");
+    let fps_limit = matches
+        .value_of("fps-limit")
+        .and_then(|string| string.parse::<f64>().ok());
+    let mut ips_limit = matches
+        .value_of("ips-limit")
+        .and_then(|string| string.parse::<f64>().ok());
+    let ipf_limit = matches
+        .value_of("ipf-limit")
+        .and_then(|string| string.parse::<f64>().ok());
+    let flicker_reduction = if matches.is_present("no-skip-frames") {
+        FlickerReduction::None
+    } else {
+        matches
+            .value_of("flicker-reduction")
+            .unwrap()
+            .parse::<FlickerReduction>()?
+    };
+
+    if let Some(ipf_limit) = ipf_limit {
+        if let Some(fps_limit) = fps_limit {
+            ips_limit = Some(fps_limit * ipf_limit);
+        }
+    }
+    let turbo = matches
+        .value_of("turbo")
+        .unwrap()
+        .parse::<f64>()
+        .ok()
+        .filter(|factor| *factor > 0.0)
+        .context("Invalid turbo factor, expected a number above 0")?;
+    let slow_motion = matches
+        .value_of("slow-motion")
+        .unwrap()
+        .parse::<f64>()
+        .ok()
+        .filter(|factor| *factor > 0.0)
+        .context("Invalid slow motion factor, expected a number above 0")?;
+
+    let seed = matches
+        .value_of("seed")
+        .map(|seed| seed.parse::<u64>().context("Invalid seed"))
+        .transpose()?;
+
+    let entry = matches.value_of("entry").map(parse_addr).transpose()?;
+    let breakpoints = matches
+        .value_of("break")
+        .map(|list| {
+            list.split(',')
+                .map(parse_addr)
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let opcode_breaks = matches
+        .value_of("break-op")
+        .map(|list| {
+            list.split(',')
+                .map(|class| class.parse::<OpcodeClass>())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let color_adjust = ColorAdjust {
+        gamma: matches
+            .value_of("gamma")
+            .unwrap()
+            .parse::<f64>()
+            .ok()
+            .filter(|gamma| *gamma > 0.0)
+            .context("Invalid gamma, expected a positive number")?,
+        brightness: matches
+            .value_of("brightness")
+            .unwrap()
+            .parse::<f64>()
+            .ok()
+            .filter(|brightness| (0.1..=3.0).contains(brightness))
+            .context("Invalid brightness, expected 0.1 to 3")?,
+        invert: matches.is_present("invert"),
+    };
+
+    let border_color = matches
+        .value_of("border-color")
+        .map(parse_color)
+        .transpose()?;
+
+    let term_style = matches
+        .value_of("term-style")
+        .unwrap()
+        .parse::<TermStyle>()?;
+
+    let palette = match (
+        matches.value_of("palette-file"),
+        matches.value_of("palette"),
+    ) {
+        (Some(path), _) => Some(Palette::load(path)?),
+        (None, Some(name)) => Some(Palette::preset(name)?),
+        (None, None) => None,
+    };
+    // --colors has a default value, it only replaces the palette when given
+    let colors = match &palette {
+        Some(palette) if matches.occurrences_of("colors") == 0 => Some(palette.colors),
+        _ => matches.value_of("colors").map(parse_colors),
+    };
+
+    let segments = matches
+        .values_of("load")
+        .map(|values| values.map(parse_segment).collect::<Result<Vec<_>, _>>())
+        .transpose()?
+        .unwrap_or_default();
+
+    let pokes = matches
+        .values_of("poke")
+        .map(|values| values.map(parse_poke).collect::<Result<Vec<_>, _>>())
+        .transpose()?
+        .unwrap_or_default();
+
+    let watches = matches
+        .values_of("watch")
+        .map(|values| values.map(parse_watch).collect::<Result<Vec<_>, _>>())
+        .transpose()?
+        .unwrap_or_default();
+
+    let mut watchpoints = Vec::new();
+    for (arg, access) in [("watch-read", Access::Read), ("watch-write", Access::Write)].iter() {
+        for value in matches.values_of(arg).into_iter().flatten() {
+            let watch = parse_watch(value)?;
+            watchpoints.push(Watchpoint {
+                addr: watch.addr as usize,
+                len: watch.len as usize,
+                access: *access,
+            });
+        }
+    }
+
+    let profile = match matches.value_of("profile").unwrap() {
+        "auto" => {
+            let profile = Profile::detect(code);
+            // other variants change the speed and the quirks, tell how to undo that
+            if profile != Profile::Chip8 || matches.is_present("verbose") {
+                println!("Detected {} rom, --profile overrides it", profile);
+            }
+            profile
+        }
+        profile => profile.parse::<Profile>()?,
+    };
+    if ips_limit.is_none() {
+        ips_limit = Some(profile.ipf() * 60.0);
+    }
+    let timer_hz = matches
+        .value_of("timer-hz")
+        .unwrap()
+        .parse::<f64>()
+        .ok()
+        .filter(|hz| *hz > 0.0)
+        .context("Invalid timer frequency")?;
+    let clock_mode = match matches.value_of("timer-mode").unwrap() {
+        "instructions" => {
+            let ipf = ipf_limit.unwrap_or_else(|| ips_limit.unwrap() / timer_hz);
+            ClockMode::Instructions(ipf.round() as u64)
+        }
+        _ => ClockMode::WallClock,
+    };
+
+    let mut quirks = profile.quirks();
+    if let Some(list) = matches.value_of("quirks") {
+        quirks.apply_list(list)?;
+    }
+
+    let font = matches.value_of("font").map(read_file).transpose()?;
+    let hires_font = matches.value_of("hires-font").map(read_file).transpose()?;
+    let buzzer_wav = matches
+        .value_of("buzzer-wav")
+        .map(|path| {
+            Wav::parse(&read_file(path)?).with_context(|| format!("Could not load {}", path))
+        })
+        .transpose()?;
+
+    let exit_on_loop = if matches.is_present("exit-on-loop") {
+        let threshold = matches.value_of("loop-threshold").unwrap_or("2");
+        Some(threshold.parse::<u64>().context("Invalid loop threshold")?)
+    } else {
+        None
+    };
+
+    let keymap = matches.value_of("keymap").unwrap().parse::<Keymap>()?;
+    // the defaults step aside for the keys of the keymap, chosen keys have to fit
+    let hotkeys = match matches.value_of("hotkeys") {
+        Some(path) => {
+            let hotkeys = Hotkeys::load(path)?;
+            hotkeys
+                .check(keymap)
+                .with_context(|| format!("Invalid hotkeys {}", path))?;
+            hotkeys
+        }
+        None => Hotkeys::for_keymap(keymap),
+    };
+
+    let flags_file = if matches.is_present("no-persist-flags") {
+        None
+    } else {
+        flags::path_for_rom(code)
+    };
+
+    Ok(Emulator::new()
+        .with_flicker_reduction(flicker_reduction)
+        .with_fps_limit(fps_limit)
+        .with_ips_limit(ips_limit)
+        .with_turbo(turbo)
+        .with_slow_motion(slow_motion)
+        .with_clock_mode(clock_mode)
+        .with_timer_hz(timer_hz)
+        .with_colors(colors)
+        .with_buzzer_colors(
+            palette.and_then(|palette| palette.buzzer),
+            border_color.or_else(|| palette.and_then(|palette| palette.quiet)),
+        )
+        .with_entry(entry)
+        .with_segments(segments)
+        .with_pokes(pokes)
+        .with_quirks(quirks)
+        .with_chip8x(profile == Profile::Chip8X)
+        .with_two_page_hires(profile == Profile::HiresChip8)
+        .with_odd_pc_policy(matches.value_of("odd-pc").unwrap().parse::<OddPcPolicy>()?)
+        .with_seed(seed)
+        .with_record_movie(matches.value_of("record").map(PathBuf::from))
+        .with_replay_movie(matches.value_of("replay").map(Movie::load).transpose()?)
+        .with_flags_file(flags_file)
+        .with_exit_on_loop(exit_on_loop)
+        .with_font_style(
+            matches
+                .value_of("font-style")
+                .unwrap()
+                .parse::<FontStyle>()?,
+        )
+        .with_font(font)
+        .with_hires_font(hires_font)
+        .with_memory_policy(
+            matches
+                .value_of("memory-bounds")
+                .unwrap()
+                .parse::<MemoryPolicy>()?,
+        )
+        .with_unknown_opcode_policy(
+            matches
+                .value_of("unknown-opcode")
+                .unwrap()
+                .parse::<UnknownOpcodePolicy>()?,
+        )
+        .with_texture_filter(
+            matches
+                .value_of("texture-filter")
+                .unwrap()
+                .parse::<TextureFilter>()?,
+        )
+        .with_record_gif(matches.value_of("record-gif").map(PathBuf::from))
+        .with_record_video(matches.value_of("record-video").map(PathBuf::from))
+        .with_window_scale(
+            matches
+                .value_of("scale")
+                .unwrap()
+                .parse::<usize>()
+                .ok()
+                .filter(|scale| *scale > 0)
+                .context("Invalid window scale")?,
+        )
+        .with_keypad(matches.is_present("keypad"))
+        .with_keymap(keymap)
+        .with_hotkeys(hotkeys)
+        .with_remote_input(matches.value_of("remote-input").map(String::from))
+        .with_gamepad_map(match matches.value_of("gamepad-map") {
+            Some(path) => GamepadMap::load(path)?,
+            None => GamepadMap::default(),
+        })
+        .with_perf_overlay(matches.is_present("perf-counter"))
+        .with_audio(!matches.is_present("no-audio"))
+        .with_buzzer_wav(buzzer_wav)
+        .with_lowpass(
+            matches
+                .value_of("lowpass")
+                .map(|cutoff| {
+                    cutoff
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|cutoff| *cutoff > 0.0)
+                        .context("Invalid low-pass cutoff frequency")
+                })
+                .transpose()?,
+        )
+        .with_sound_indicator(
+            matches
+                .value_of("sound-indicator")
+                .map(|style| style.parse::<SoundIndicator>())
+                .transpose()?,
+        )
+        .with_term(if matches.is_present("term") {
+            Some(term_style)
+        } else {
+            None
+        })
+        .with_backend(matches.value_of("backend").unwrap().parse::<Backend>()?)
+        .with_fullscreen(
+            matches.is_present("fullscreen"),
+            parse_size(matches.value_of("screen-size").unwrap())?,
+        )
+        .with_scaling(matches.value_of("scaling").unwrap().parse::<Scaling>()?)
+        .with_post_filter(matches.value_of("filter").unwrap().parse::<PostFilter>()?)
+        .with_phosphor_decay(
+            matches
+                .value_of("phosphor-decay")
+                .map(|decay| {
+                    decay
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|decay| *decay > 0.0 && *decay < 1.0)
+                        .context("Invalid phosphor decay, expected a factor between 0 and 1")
+                })
+                .transpose()?
+                .or(if flicker_reduction == FlickerReduction::Decay {
+                    Some(DEFAULT_DECAY)
+                } else {
+                    None
+                }),
+        )
+        .with_blend_frames(
+            matches
+                .value_of("blend-frames")
+                .map(|count| {
+                    count
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|count| (2..=4).contains(count))
+                        .context("Invalid number of blended frames, expected 2 to 4")
+                })
+                .transpose()?,
+        )
+        .with_color_adjust(color_adjust)
+        .with_debug_tui(matches.is_present("debug-tui"))
+        .with_debugger(matches.is_present("debugger"))
+        .with_start_paused(matches.is_present("start-paused"))
+        .with_breakpoints(breakpoints)
+        .with_opcode_breaks(opcode_breaks)
+        .with_watchpoints(watchpoints)
+        .with_hotspots(
+            matches
+                .value_of("hotspots")
+                .map(|top| top.parse::<usize>().context("Invalid hotspot count"))
+                .transpose()?,
+        )
+        .with_opcode_stats(matches.is_present("opcode-stats"))
+        .with_crash_dump(matches.value_of("crash-dump").map(PathBuf::from))
+        .with_state_dir(
+            matches
+                .value_of("state-dir")
+                .map(PathBuf::from)
+                .or_else(savestate::default_dir),
+        )
+        .with_autosave(matches.is_present("autosave"))
+        .with_resume(matches.is_present("resume"))
+        .with_rewind_seconds(
+            matches
+                .value_of("rewind")
+                .unwrap()
+                .parse::<f64>()
+                .context("Invalid rewind duration")?,
+        )
+        .with_dump_memory(
+            matches
+                .value_of("dump-memory")
+                .map(debugger::parse_range)
+                .transpose()?,
+        )
+        .with_trace_file(matches.value_of("trace-file").map(PathBuf::from))
+        .with_access_log(
+            matches.value_of("access-log").map(PathBuf::from),
+            matches
+                .values_of("access-log-range")
+                .into_iter()
+                .flatten()
+                .map(parse_watch)
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+        .with_watches(watches)
+        .with_debug(debug))
+}
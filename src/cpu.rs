@@ -1,48 +1,322 @@
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fmt;
+use std::str::FromStr;
 use std::time::{Instant, Duration};
 
-use super::sound::Sound;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+use super::chip8x::ColorMap;
+use super::fonts::FontStyle;
+use super::heatmap::Heatmap;
+use super::megachip::{BlendMode, MegaScreen, MEGA_HEIGHT, MEGA_WIDTH};
+use super::quirks::Quirks;
+use super::sound::{Sound, Synth};
 
 pub const WIDTH: usize = 64;
 pub const HEIGHT: usize = 32;
 pub const MEMSIZE: usize = 65536;
+// Mega-CHIP addresses 24 bits
+pub const MEGA_MEMSIZE: usize = 1 << 24;
+// background, fill1, fill2, blend
+pub const DEFAULT_COLORS: [u32; 4] = [0x00AA4400, 0x00FFAA00, 0x00AAAAAA, 0x00000000];
+// sample rate of the sound returned by step_frame
+pub const FRAME_SAMPLE_RATE: f64 = 44100.0;
 
-pub struct Timer {
+#[derive(Debug, PartialEq)]
+pub enum CpuError {
+    OddPc(u16),
+    UnknownOpcode { pc: u16, instr: u16 },
+    MemoryOutOfBounds { pc: u16, addr: usize },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::OddPc(pc) => write!(f, "program counter is not even: {:#X}", pc),
+            CpuError::UnknownOpcode { pc, instr } => {
+                write!(f, "unknown opcode {:#06X} at {:#X}", instr, pc)
+            }
+            CpuError::MemoryOutOfBounds { pc, addr } => write!(
+                f,
+                "memory access at {:#X} is out of bounds (instruction at {:#X})",
+                addr, pc
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+// What happens when the program counter points to an odd address
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OddPcPolicy {
+    // execute the unaligned instruction, some roms rely on it
+    Allow,
+    // round the program counter down to the previous even address
+    RoundDown,
+    // stop with CpuError::OddPc
+    Error,
+}
+
+impl FromStr for OddPcPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(OddPcPolicy::Allow),
+            "round-down" => Ok(OddPcPolicy::RoundDown),
+            "error" => Ok(OddPcPolicy::Error),
+            _ => anyhow::bail!(
+                "Unknown odd pc policy {}, expected allow, round-down or error",
+                s
+            ),
+        }
+    }
+}
+
+// What happens when the cpu fetches an instruction it does not know
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UnknownOpcodePolicy {
+    // log the opcode and halt the cpu, the window stays open
+    Stop,
+    // log the opcode and continue with the next instruction
+    SkipAndWarn,
+    // stop with CpuError::UnknownOpcode
+    Error,
+}
+
+impl FromStr for UnknownOpcodePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(UnknownOpcodePolicy::Stop),
+            "skip" => Ok(UnknownOpcodePolicy::SkipAndWarn),
+            "error" => Ok(UnknownOpcodePolicy::Error),
+            _ => anyhow::bail!("Unknown opcode policy {}, expected stop, skip or error", s),
+        }
+    }
+}
+
+// What happens when an I relative access runs past the end of memory
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MemoryPolicy {
+    // continue at address 0
+    Wrap,
+    // clamp the address to the last byte of memory
+    Saturate,
+    // stop with CpuError::MemoryOutOfBounds
+    Error,
+}
+
+impl FromStr for MemoryPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(MemoryPolicy::Wrap),
+            "saturate" => Ok(MemoryPolicy::Saturate),
+            "error" => Ok(MemoryPolicy::Error),
+            _ => anyhow::bail!("Unknown memory policy {}, expected wrap, saturate or error", s),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "Read"),
+            Access::Write => write!(f, "Write"),
+        }
+    }
+}
+
+// Memory range that pauses the debugger when an instruction accesses it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub addr: usize,
+    pub len: usize,
+    pub access: Access,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct WatchHit {
+    pub pc: u16,
+    pub addr: usize,
+    pub value: u8,
+    pub access: Access,
+}
+
+// Memory accesses inside the ranges, collected for the access log and taken by the emulator
+#[derive(Clone, Default)]
+pub struct AccessLog {
+    // start and end address, no ranges log all accesses
+    pub ranges: Vec<(usize, usize)>,
+    pub accesses: Vec<WatchHit>,
+}
+
+// What one frame of step_frame produced
+pub struct FrameOutput {
+    // the display as red, green, blue and alpha bytes, row by row
+    pub rgba: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    // mono samples at FRAME_SAMPLE_RATE for the length of the frame
+    pub audio_samples: Vec<i16>,
+    // the program executed 00FD or an unknown opcode stopped the cpu
+    pub exit: bool,
+}
+
+// Copy of the machine state, Mega-CHIP screens are not included
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedState {
+    memory: Vec<u8>,
+    v: [u8; 16],
+    pc: u16,
+    sp: u8,
+    stack: [u16; 16],
+    i: u32,
+    repl: [u8; 16],
+    timers: (u8, u8),
+    clock_steps: u64,
+    rng: ChaCha12Rng,
+    sound_memory: [u8; 16],
+    pitch: u8,
+    planes: Vec<Plane>,
+    width: usize,
+    height: usize,
+    extended: bool,
+    active_planes: u8,
+    two_page: bool,
+    color_map: Option<ColorMap>,
+    quirks: Quirks,
+}
+
+impl SavedState {
+    pub fn clock_steps(&self) -> u64 {
+        self.clock_steps
+    }
+}
+
+// Called for 0nnn (SYS nnn) with the routine address, returns false if the
+// routine is not emulated. Lets embedders emulate VIP machine code routines.
+pub type SysHook = Box<dyn FnMut(&mut Cpu, u16) -> bool + Send>;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ClockMode {
+    // timers count down in real time
+    WallClock,
+    // timers count down once every n executed instructions
+    Instructions(u64),
+}
+
+pub struct Clock {
     start: Instant,
-    last_update: Instant,
-    freq_hz: f64,
-    multi: f64,
-    _reg_value: u8,
+    // timer steps before start, kept when the speed changes
+    start_ticks: f64,
+    paused_at: Option<Instant>,
+    pub freq_hz: f64,
+    pub multi: f64,
+    pub mode: ClockMode,
 }
 
-impl Timer {
+impl Clock {
     fn new() -> Self {
-        let time = Instant::now();
         Self {
-            start: time,
-            last_update: time,
+            start: Instant::now(),
+            start_ticks: 0.0,
+            paused_at: None,
             freq_hz: 60.0,
             multi: 1.0,
+            mode: ClockMode::WallClock,
+        }
+    }
+
+    // number of timer steps since the clock was started
+    fn ticks(&self, clock_steps: u64) -> u64 {
+        match self.mode {
+            ClockMode::WallClock => self.wall_ticks() as u64,
+            ClockMode::Instructions(n) => clock_steps / n.max(1),
+        }
+    }
+
+    // timer steps of the wall clock with the part of the current one
+    fn wall_ticks(&self) -> f64 {
+        let until_now = self.now() - self.start;
+        self.start_ticks + until_now.as_secs_f64() * self.freq_hz * self.multi
+    }
+
+    // real time until the next timer step, instruction counted clocks never wait
+    pub fn time_to_next_tick(&self) -> Duration {
+        match self.mode {
+            ClockMode::WallClock => {
+                let part = self.wall_ticks().fract();
+                Duration::from_secs_f64((1.0 - part) / (self.freq_hz * self.multi))
+            }
+            ClockMode::Instructions(..) => Duration::from_secs(0),
+        }
+    }
+
+    // a paused clock stands still, e.g. while the debugger holds the cpu
+    fn now(&self) -> Instant {
+        self.paused_at.unwrap_or_else(Instant::now)
+    }
+
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(Instant::now());
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.start += Instant::now() - paused_at;
+        }
+    }
+
+    // Speeds the timers up or slows them down from now on, the steps counted so
+    // far stay the same
+    pub fn set_multi(&mut self, multi: f64) {
+        self.start_ticks = self.wall_ticks();
+        self.start = self.now();
+        self.multi = multi;
+    }
+
+    // timer steps per second of real time
+    pub fn rate(&self) -> f64 {
+        self.freq_hz * self.multi
+    }
+}
+
+pub struct Timer {
+    last_update: u64,
+    _reg_value: u8,
+}
+
+impl Timer {
+    fn new() -> Self {
+        Self {
+            last_update: 0,
             _reg_value: 0,
         }
     }
 
-    fn set_reg(&mut self, val: u8) {
-        self.last_update = Instant::now();
+    fn set_reg(&mut self, val: u8, ticks: u64) {
+        self.last_update = ticks;
         self._reg_value = val;
     }
 
-    fn get_reg(&self) -> u8 {
-        if self._reg_value == 0 {
-            return 0;
-        }
-        let until_now = Instant::now() - self.start;
-        let until_last_update = self.last_update - self.start;
-        let steps_now = until_now.as_secs_f64() * self.freq_hz * self.multi;
-        let steps_last_update = until_last_update.as_secs_f64() * self.freq_hz * self.multi;
-        // cast to int to make the divisions above integer divisions
-        let diff = steps_now as u64 - steps_last_update as u64;
+    fn get_reg(&self, ticks: u64) -> u8 {
+        let diff = ticks.saturating_sub(self.last_update);
         if (self._reg_value as u64) < diff {
             0
         } else {
@@ -50,11 +324,11 @@ impl Timer {
         }
     }
 
-    fn time_left(&self) -> Option<Duration> {
+    fn time_left(&self, freq_hz: f64) -> Option<Duration> {
         if self._reg_value == 0 {
             return None;
         }
-        Some(Duration::from_secs_f64(self._reg_value as f64 / self.freq_hz))
+        Some(Duration::from_secs_f64(self._reg_value as f64 / freq_hz))
     }
 }
 
@@ -68,6 +342,11 @@ pub enum VKey {
 pub struct Keyboard {
     pub keys: [VKey; 16],
     pub prev_keys: [VKey; 16],
+    // key pressed during Fx0A that still has to be released
+    pub held_key: Option<usize>,
+    // the number of presses so far when each key was last pressed
+    press_order: [u64; 16],
+    presses: u64,
 }
 
 impl Default for Keyboard {
@@ -75,10 +354,35 @@ impl Default for Keyboard {
         Self {
             keys: [VKey::Up; 16],
             prev_keys: [VKey::Up; 16],
+            held_key: None,
+            press_order: [0; 16],
+            presses: 0,
         }
     }
 }
 
+impl Keyboard {
+    // Sets the keys that are down and remembers the order of the presses
+    pub fn set_keys(&mut self, keys: [VKey; 16]) {
+        for (index, key) in keys.iter().enumerate() {
+            if *key == VKey::Down && self.keys[index] == VKey::Up {
+                self.presses += 1;
+                self.press_order[index] = self.presses;
+            }
+        }
+        self.keys = keys;
+    }
+
+    // The most recently pressed of the keys that were up at the last Fx0A,
+    // a key that was already held does not hide a newer one
+    fn newest_press(&self) -> Option<usize> {
+        (0..16)
+            .filter(|key| self.keys[*key] == VKey::Down && self.prev_keys[*key] == VKey::Up)
+            .max_by_key(|key| self.press_order[*key])
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Plane {
     // x: 0 - 63 (or 127) pixels are stored in 0-7 (or 15) bytes
     // y: 0 - 31 (or 63) bytes
@@ -136,15 +440,24 @@ impl Plane {
         }
     }
 
-    fn write_sprite(&mut self, sprite: &[u8], x: u8, y: u8) -> bool {
+    fn write_sprite(&mut self, sprite: &[u8], x: u8, y: u8, clip: bool) -> bool {
         let mut collision = false;
         let x = x % self.width as u8;
         let y = y % self.height as u8;
+        // columns that would wrap to the left edge are dropped when clipping
+        let mask = if clip && x as usize + 8 > self.width {
+            0xFFu8 << (x as usize + 8 - self.width)
+        } else {
+            0xFF
+        };
         for (i, _) in sprite.iter().enumerate() {
+            if clip && y as usize + i >= self.height {
+                break;
+            }
             let y_roll = ((y as usize + i) % self.height) as u8;
             let cur_val = self.get_byte(x, y_roll);
-            let new_val = cur_val ^ sprite[i];
-            let cleared = cur_val & sprite[i];
+            let new_val = cur_val ^ (sprite[i] & mask);
+            let cleared = cur_val & sprite[i] & mask;
             self.set_byte(x, y_roll, new_val);
             if cleared != 0 {
                 collision = true;
@@ -153,7 +466,7 @@ impl Plane {
         collision
     }
 
-    fn write_sprite16(&mut self, sprite: &[u8; 32], x: u8, y: u8) -> bool {
+    fn write_sprite16(&mut self, sprite: &[u8; 32], x: u8, y: u8, clip: bool) -> bool {
         let mut collision = false;
         let mut left = [0u8; 16];
         let mut right = [0u8; 16];
@@ -163,8 +476,11 @@ impl Plane {
         for (src, dest) in sprite.iter().skip(1).step_by(2).zip(right.iter_mut()) {
             *dest = *src;
         }
-        collision |= self.write_sprite(&left[..], x, y);
-        collision |= self.write_sprite(&right[..], x + 8, y);
+        let x = x % self.width as u8;
+        collision |= self.write_sprite(&left[..], x, y, clip);
+        if !clip || (x as usize + 8) < self.width {
+            collision |= self.write_sprite(&right[..], x + 8, y, clip);
+        }
         collision
     }
 
@@ -199,8 +515,12 @@ pub struct Display {
     pub updates: u64,
     pub updated: bool,
     pub extended: bool,
+    // 64x64 screen of the two-page hires CHIP-8 interpreter
+    pub two_page: bool,
     pub colors: [u32; 4],
     pub active_planes: u8,
+    pub mega: Option<MegaScreen>,
+    pub color_map: Option<ColorMap>,
 }
 
 impl Display {
@@ -212,12 +532,41 @@ impl Display {
             updates: 0,
             updated: true,
             extended: false,
-            colors: [0x00AA4400, 0x00FFAA00, 0x00AAAAAA, 0x00000000],
+            two_page: false,
+            colors: DEFAULT_COLORS,
             active_planes: 0x1,
+            mega: None,
+            color_map: None,
         }
     }
 
+    fn set_megachip(&mut self, enabled: bool) {
+        if enabled {
+            self.width = MEGA_WIDTH;
+            self.height = MEGA_HEIGHT;
+            self.mega = Some(MegaScreen::default());
+        } else {
+            self.mega = None;
+            self.set_extended(false);
+        }
+        self.flag_updated();
+    }
+
+    fn set_two_page(&mut self) {
+        self.height = HEIGHT * 2;
+        self.width = WIDTH;
+        self.two_page = true;
+        self.planes = vec![
+            Plane::new(self.height, self.width),
+            Plane::new(self.height, self.width),
+        ];
+        self.flag_updated();
+    }
+
     fn set_extended(&mut self, ext: bool) {
+        if self.mega.is_some() || self.two_page {
+            return;
+        }
         if ext {
             self.height = HEIGHT * 2;
             self.width = WIDTH * 2;
@@ -238,6 +587,9 @@ impl Display {
     }
 
     pub fn to_buf(&self) -> Vec<u32> {
+        if let Some(mega) = self.mega.as_ref() {
+            return mega.to_buf();
+        }
         let cells1 = &self.planes[0].cells;
         let cells2 = &self.planes[1].cells;
         let mut buf = Vec::<u32>::with_capacity(self.height * self.width);
@@ -247,7 +599,11 @@ impl Display {
                     let mut bitplane = 0;
                     bitplane |= (cells1[y * (self.width / 8) + x] >> (7 - bit)) & 0x1;
                     bitplane |= ((cells2[y * (self.width / 8) + x] >> (7 - bit)) & 0x1) << 1;
-                    buf.push(self.colors[bitplane as usize]);
+                    let color = match self.color_map.as_ref() {
+                        Some(map) => map.color(x * 8 + bit, y, bitplane != 0),
+                        None => self.colors[bitplane as usize],
+                    };
+                    buf.push(color);
                 }
             }
         }
@@ -255,6 +611,10 @@ impl Display {
     }
 
     fn scroll_down(&mut self, n: u8) {
+        if let Some(mega) = self.mega.as_mut() {
+            mega.scroll(0, n as isize);
+            return;
+        }
         for (i, plane) in self.planes.iter_mut().enumerate() {
             if (self.active_planes >> i as u8) & 0x1 == 1 {
                 plane.scroll_down(n);
@@ -264,6 +624,10 @@ impl Display {
     }
 
     fn scroll_up(&mut self, n: u8) {
+        if let Some(mega) = self.mega.as_mut() {
+            mega.scroll(0, -(n as isize));
+            return;
+        }
         for (i, plane) in self.planes.iter_mut().enumerate() {
             if (self.active_planes >> i as u8) & 0x1 == 1 {
                 plane.scroll_up(n);
@@ -273,6 +637,10 @@ impl Display {
     }
 
     fn scroll_right(&mut self) {
+        if let Some(mega) = self.mega.as_mut() {
+            mega.scroll(4, 0);
+            return;
+        }
         for (i, plane) in self.planes.iter_mut().enumerate() {
             if (self.active_planes >> i as u8) & 0x1 == 1 {
                 plane.scroll_right();
@@ -282,6 +650,10 @@ impl Display {
     }
 
     fn scroll_left(&mut self) {
+        if let Some(mega) = self.mega.as_mut() {
+            mega.scroll(-4, 0);
+            return;
+        }
         for (i, plane) in self.planes.iter_mut().enumerate() {
             if (self.active_planes >> i as u8) & 0x1 == 1 {
                 plane.scroll_left();
@@ -291,6 +663,11 @@ impl Display {
     }
 
     fn clear(&mut self) {
+        if let Some(mega) = self.mega.as_mut() {
+            mega.present_and_clear();
+            self.flag_updated();
+            return;
+        }
         for (i, plane) in self.planes.iter_mut().enumerate() {
             if (self.active_planes >> i as u8) & 0x1 == 1 {
                 plane.clear();
@@ -299,18 +676,18 @@ impl Display {
         self.flag_updated();
     }
 
-    fn write_sprite(&mut self, sprite: &[u8], x: u8, y: u8) -> bool {
+    fn write_sprite(&mut self, sprite: &[u8], x: u8, y: u8, clip: bool) -> bool {
         let mut collision = false;
         match self.active_planes {
             0x3 => {
                 let length = sprite.len();
-                collision |= self.planes[0].write_sprite(&sprite[..length / 2], x, y);
-                collision |= self.planes[1].write_sprite(&sprite[length / 2..], x, y);
+                collision |= self.planes[0].write_sprite(&sprite[..length / 2], x, y, clip);
+                collision |= self.planes[1].write_sprite(&sprite[length / 2..], x, y, clip);
             }
             _ => {
                 for (i, plane) in self.planes.iter_mut().enumerate() {
                     if (self.active_planes >> i as u8) & 0x1 == 1 {
-                        collision |= plane.write_sprite(sprite, x, y);
+                        collision |= plane.write_sprite(sprite, x, y, clip);
                     }
                 }
             }
@@ -319,20 +696,26 @@ impl Display {
         collision
     }
 
-    fn write_sprite16(&mut self, sprite: &[u8], x: u8, y: u8) -> Result<bool, anyhow::Error> {
+    fn write_sprite16(
+        &mut self,
+        sprite: &[u8],
+        x: u8,
+        y: u8,
+        clip: bool,
+    ) -> Result<bool, anyhow::Error> {
         let mut collision = false;
         match self.active_planes {
             0x3 => {
                 let length = sprite.len();
                 collision |=
-                    self.planes[0].write_sprite16(&sprite[..length / 2].try_into()?, x, y);
+                    self.planes[0].write_sprite16(&sprite[..length / 2].try_into()?, x, y, clip);
                 collision |=
-                    self.planes[1].write_sprite16(&sprite[length / 2..].try_into()?, x, y);
+                    self.planes[1].write_sprite16(&sprite[length / 2..].try_into()?, x, y, clip);
             }
             _ => {
                 for (i, plane) in self.planes.iter_mut().enumerate() {
                     if (self.active_planes >> i as u8) & 0x1 == 1 {
-                        collision |= plane.write_sprite16(sprite.try_into().unwrap(), x, y);
+                        collision |= plane.write_sprite16(sprite.try_into().unwrap(), x, y, clip);
                     }
                 }
             }
@@ -371,16 +754,47 @@ pub struct Cpu {
     pub keyboard: Keyboard,
     pub sound: Sound,
     pub sound_memory: [u8; 16],
+    pub pitch: u8,
+    pub clock: Clock,
     pub dt: Timer,
     pub st: Timer,
-    pub memory: [u8; MEMSIZE],
+    // at least MEMSIZE bytes, grows for large roms and Mega-CHIP
+    pub memory: Vec<u8>,
     pub v: [u8; 16],
     pub pc: u16,
     pub sp: u8,
     pub stack: [u16; 16],
-    pub i: u16,
+    pub i: u32,
     pub clock_steps: u64,
-    pub repl: [u8; 8],
+    // flag registers, SCHIP uses 8 of them, XO-CHIP all 16
+    pub repl: [u8; 16],
+    pub odd_pc_policy: OddPcPolicy,
+    pub unknown_opcode_policy: UnknownOpcodePolicy,
+    pub memory_policy: MemoryPolicy,
+    // frame in which the last sprite was drawn while the display wait quirk is active
+    pub vblank_wait: Option<u64>,
+    // random numbers for Cxkk, seed it for reproducible runs. The generator of
+    // StdRng, which can be saved with the machine state.
+    pub rng: ChaCha12Rng,
+    // set when an unknown opcode stopped the cpu
+    pub halted: bool,
+    pub quirks: Quirks,
+    // set by 00FD, the program asked the interpreter to exit
+    pub exited: bool,
+    // number of consecutive jumps to the jump instruction itself
+    pub jump_to_self_count: u64,
+    sys_hook: Option<SysHook>,
+    // machine code routines that were reported as skipped
+    skipped_sys: HashSet<u16>,
+    pub watchpoints: Vec<Watchpoint>,
+    // first watched access of the last instruction, taken by the debugger
+    pub watch_hit: Option<WatchHit>,
+    pub access_log: Option<AccessLog>,
+    // recent memory accesses for the debugger ui
+    pub heatmap: Option<Heatmap>,
+    // renders the sound of step_frame, the fraction of a sample is carried over
+    frame_synth: Option<Synth>,
+    frame_samples: f64,
 }
 
 impl Default for Cpu {
@@ -390,16 +804,35 @@ impl Default for Cpu {
             keyboard: Keyboard::default(),
             sound: Sound::new(4000.0),
             sound_memory: [0xAAu8; 16],
+            pitch: 64,
+            clock: Clock::new(),
             dt: Timer::new(),
             st: Timer::new(),
-            memory: [0u8; MEMSIZE],
+            memory: vec![0u8; MEMSIZE],
             v: [0u8; 16],
             pc: 0x200,
             sp: 0,
             stack: [0u16; 16],
             i: 0,
             clock_steps: 0,
-            repl: [0u8; 8],
+            repl: [0u8; 16],
+            odd_pc_policy: OddPcPolicy::Allow,
+            unknown_opcode_policy: UnknownOpcodePolicy::Error,
+            memory_policy: MemoryPolicy::Error,
+            vblank_wait: None,
+            rng: ChaCha12Rng::from_entropy(),
+            halted: false,
+            quirks: Quirks::default(),
+            exited: false,
+            jump_to_self_count: 0,
+            sys_hook: None,
+            skipped_sys: HashSet::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            access_log: None,
+            heatmap: None,
+            frame_synth: None,
+            frame_samples: 0.0,
         }
     }
 }
@@ -409,7 +842,7 @@ impl fmt::Debug for Cpu {
         fmt.debug_struct("Foo")
             .field("pc", &format_args!("{:#X}", self.pc))
             .field("i", &format_args!("{:#X}", self.i))
-            .field("dt", &self.dt.get_reg())
+            .field("dt", &self.dt.get_reg(self.frame()))
             .field("v", &self.v)
             .field("sp", &self.sp)
             .field("stack", &self.stack)
@@ -421,40 +854,395 @@ impl fmt::Debug for Cpu {
 impl Cpu {
     pub fn new(code: &[u8], multi: f64) -> Self {
         let mut cpu = Self::default();
-        cpu.dt.multi = multi;
-        cpu.st.multi = multi;
+        cpu.clock.multi = multi;
         cpu.memory[0..80].copy_from_slice(&cpu.display.std_sprites());
         cpu.memory[80..180].copy_from_slice(&cpu.display.hires_sprites());
+        if 0x200 + code.len() > cpu.memory.len() {
+            cpu.memory.resize(0x200 + code.len(), 0);
+        }
         cpu.memory[0x200..0x200 + code.len()].copy_from_slice(code);
         cpu.pc = 0x200;
         cpu
     }
 
+    // CHIP-8X programs start at 0x300 behind the larger interpreter and get the color map
+    pub fn enable_chip8x(&mut self, code: &[u8]) -> Result<(), anyhow::Error> {
+        for byte in self.memory[0x200..0x200 + code.len()].iter_mut() {
+            *byte = 0;
+        }
+        self.load(0x300, code)?;
+        self.pc = 0x300;
+        self.display.color_map = Some(ColorMap::default());
+        Ok(())
+    }
+
+    // The two-page hires interpreter replaces the code before 0x2C0, which only
+    // holds the 0x1260 trampoline and machine code in such roms
+    pub fn enable_two_page_hires(&mut self) {
+        self.display.set_two_page();
+        self.pc = 0x2C0;
+    }
+
+    pub fn set_sys_hook(&mut self, hook: SysHook) {
+        self.sys_hook = Some(hook);
+    }
+
+    // Loads one of the built-in font sets
+    pub fn set_font_style(&mut self, style: FontStyle) {
+        let font = style.font().unwrap_or_else(|| self.display.std_sprites());
+        let hires_font = style
+            .hires_font()
+            .unwrap_or_else(|| self.display.hires_sprites());
+        self.memory[0..80].copy_from_slice(&font);
+        self.memory[80..180].copy_from_slice(&hires_font);
+    }
+
+    // Replaces the 4x5 font used by Fx29, 16 characters of 5 bytes
+    pub fn load_font(&mut self, font: &[u8]) -> Result<(), anyhow::Error> {
+        if font.len() != 80 {
+            anyhow::bail!(
+                "Font has {} bytes, expected 80 (16 characters of 5 bytes)",
+                font.len()
+            );
+        }
+        self.memory[0..80].copy_from_slice(font);
+        Ok(())
+    }
+
+    // Replaces the 8x10 font used by Fx30, 10 characters of 10 bytes
+    pub fn load_hires_font(&mut self, font: &[u8]) -> Result<(), anyhow::Error> {
+        if font.len() != 100 {
+            anyhow::bail!(
+                "Hires font has {} bytes, expected 100 (10 characters of 10 bytes)",
+                font.len()
+            );
+        }
+        self.memory[80..180].copy_from_slice(font);
+        Ok(())
+    }
+
+    pub fn load(&mut self, addr: u16, data: &[u8]) -> Result<(), anyhow::Error> {
+        let start = addr as usize;
+        let end = start + data.len();
+        if end > self.memory.len() {
+            anyhow::bail!(
+                "{} bytes at {:#X} do not fit into {} bytes of memory",
+                data.len(),
+                addr,
+                self.memory.len()
+            );
+        }
+        self.memory[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn memory_addr(&self, addr: u32, offset: usize) -> Result<usize, CpuError> {
+        let addr = addr as usize + offset;
+        let size = self.memory.len();
+        if addr < size {
+            return Ok(addr);
+        }
+        match self.memory_policy {
+            MemoryPolicy::Wrap => Ok(addr % size),
+            MemoryPolicy::Saturate => Ok(size - 1),
+            MemoryPolicy::Error => Err(CpuError::MemoryOutOfBounds { pc: self.pc, addr }),
+        }
+    }
+
+    // number of bytes in a Mega-CHIP sprite, None outside of Mega-CHIP mode
+    fn mega_sprite_size(&self) -> Option<usize> {
+        self.display
+            .mega
+            .as_ref()
+            .map(|mega| mega.sprite_width * mega.sprite_height)
+    }
+
+    // reads len bytes starting at addr, the end of memory is handled by the memory policy
+    pub fn read_bytes(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, CpuError> {
+        let addrs = (0..len)
+            .map(|offset| self.memory_addr(addr, offset))
+            .collect::<Result<Vec<_>, _>>()?;
+        for addr in addrs.iter() {
+            self.check_access(*addr, Access::Read);
+        }
+        Ok(addrs.into_iter().map(|addr| self.memory[addr]).collect())
+    }
+
+    // writes data starting at addr, nothing is written if the access fails
+    pub fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<(), CpuError> {
+        let addrs = (0..data.len())
+            .map(|offset| self.memory_addr(addr, offset))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (addr, byte) in addrs.into_iter().zip(data) {
+            self.memory[addr] = *byte;
+            self.check_access(addr, Access::Write);
+        }
+        Ok(())
+    }
+
+    fn check_access(&mut self, addr: usize, access: Access) {
+        if let Some(heatmap) = self.heatmap.as_mut() {
+            heatmap.record(addr, access, self.clock.ticks(self.clock_steps));
+        }
+        if let Some(log) = self.access_log.as_mut() {
+            let logged = log.ranges.is_empty()
+                || log
+                    .ranges
+                    .iter()
+                    .any(|(start, end)| addr >= *start && addr < *end);
+            if logged {
+                log.accesses.push(WatchHit {
+                    pc: self.pc,
+                    addr,
+                    value: self.memory[addr],
+                    access,
+                });
+            }
+        }
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let watched = self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.access == access
+                && addr >= watchpoint.addr
+                && addr < watchpoint.addr + watchpoint.len
+        });
+        if watched {
+            self.watch_hit = Some(WatchHit {
+                pc: self.pc,
+                addr,
+                value: self.memory[addr],
+                access,
+            });
+        }
+    }
+
+    // the 16 bit operand following the instruction at pc
+    fn read_operand(&mut self) -> u16 {
+        let addr = self.pc.wrapping_add(2);
+        self.check_access(addr as usize, Access::Read);
+        self.check_access(addr.wrapping_add(1) as usize, Access::Read);
+        read_memory(&self.memory, addr)
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = ChaCha12Rng::seed_from_u64(seed);
+    }
+
     pub fn start_audio(&mut self) -> Result<(), anyhow::Error> {
         self.sound.start()?;
         Ok(())
     }
 
+    // number of 60 Hz frames since the cpu was created
+    pub fn frame(&self) -> u64 {
+        self.clock.ticks(self.clock_steps)
+    }
+
+    // current values of the delay and sound timer
+    pub fn timers(&self) -> (u8, u8) {
+        let ticks = self.frame();
+        (self.dt.get_reg(ticks), self.st.get_reg(ticks))
+    }
+
+    // Plays the rest of the sound timer, after a pause of the debugger cut the tone
+    pub fn resume_sound(&mut self) {
+        let st = self.timers().1;
+        if st > 0 {
+            let duration = Duration::from_secs_f64(st as f64 / self.clock.rate());
+            self.sound.play_samples_1bit(&self.sound_memory[..], duration);
+        }
+    }
+
+    pub fn set_timers(&mut self, dt: u8, st: u8) {
+        let ticks = self.frame();
+        self.dt.set_reg(dt, ticks);
+        self.st.set_reg(st, ticks);
+        if st == 0 {
+            self.sound.silence();
+        }
+    }
+
+    pub fn save_state(&self) -> SavedState {
+        SavedState {
+            memory: self.memory.clone(),
+            v: self.v,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            i: self.i,
+            repl: self.repl,
+            timers: self.timers(),
+            clock_steps: self.clock_steps,
+            rng: self.rng.clone(),
+            sound_memory: self.sound_memory,
+            pitch: self.pitch,
+            planes: self.display.planes.clone(),
+            width: self.display.width,
+            height: self.display.height,
+            extended: self.display.extended,
+            active_planes: self.display.active_planes,
+            two_page: self.display.two_page,
+            color_map: self.display.color_map.clone(),
+            quirks: self.quirks,
+        }
+    }
+
+    pub fn load_state(&mut self, state: &SavedState) {
+        self.memory = state.memory.clone();
+        self.v = state.v;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.stack = state.stack;
+        self.i = state.i;
+        self.repl = state.repl;
+        self.clock_steps = state.clock_steps;
+        self.set_timers(state.timers.0, state.timers.1);
+        self.rng = state.rng.clone();
+        self.sound_memory = state.sound_memory;
+        self.pitch = state.pitch;
+        self.vblank_wait = None;
+        self.display.planes = state.planes.clone();
+        self.display.width = state.width;
+        self.display.height = state.height;
+        self.display.extended = state.extended;
+        self.display.active_planes = state.active_planes;
+        self.display.two_page = state.two_page;
+        self.display.color_map = state.color_map.clone();
+        self.display.flag_updated();
+        self.quirks = state.quirks;
+    }
+
+    // FNV-1a hash over the complete machine state, used to compare runs
+    pub fn state_checksum(&self) -> u64 {
+        let mut hash = 0xCBF2_9CE4_8422_2325u64;
+        let mut feed = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01B3);
+            }
+        };
+        feed(&self.memory);
+        feed(&self.v);
+        // only Mega-CHIP uses more than 16 bits, keeps older checksums valid
+        feed(&(self.i as u16).to_be_bytes());
+        if self.i > 0xFFFF {
+            feed(&[(self.i >> 16) as u8]);
+        }
+        feed(&self.pc.to_be_bytes());
+        feed(&[self.sp]);
+        for addr in self.stack.iter() {
+            feed(&addr.to_be_bytes());
+        }
+        let (dt, st) = self.timers();
+        feed(&[dt, st]);
+        for plane in self.display.planes.iter() {
+            feed(&plane.cells);
+        }
+        hash
+    }
+
     pub fn next_instruction(&self) -> u16 {
         read_memory(&self.memory, self.pc)
     }
 
     pub fn skip_instruction(&mut self) {
-        self.pc += 2;
-        if self.next_instruction() == 0xF000 {
-            self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+        let next = self.next_instruction();
+        if next == 0xF000 || (self.display.mega.is_some() && next & 0xFF00 == 0x0100) {
+            self.pc = self.pc.wrapping_add(2);
         }
     }
 
-    pub fn tick(&mut self) -> Result<u16, anyhow::Error> {
+    // Executes one instruction and returns it
+    pub fn step(&mut self) -> Result<u16, anyhow::Error> {
+        if self.halted {
+            return Ok(0);
+        }
+        if let Some(frame) = self.vblank_wait {
+            // idle cycles still count so that instruction counted clocks advance
+            if self.frame() == frame {
+                self.clock_steps += 1;
+                return Ok(0);
+            }
+            self.vblank_wait = None;
+        }
+        // slipperyslope jumps to uneven instruction (level-unpack at 0x265 (0x65 in file))
+        // so odd addresses are allowed by default
+        if self.pc % 2 == 1 {
+            match self.odd_pc_policy {
+                OddPcPolicy::Allow => {}
+                OddPcPolicy::RoundDown => self.pc -= 1,
+                OddPcPolicy::Error => return Err(CpuError::OddPc(self.pc).into()),
+            }
+        }
         let instr = self.next_instruction();
+        let pc = self.pc;
+        if let Some(heatmap) = self.heatmap.as_mut() {
+            heatmap.execute(pc as usize, self.clock.ticks(self.clock_steps));
+        }
         self.process_instruction(instr)?;
+        // test roms usually end with 1nnn jumping to itself
+        if instr & 0xF000 == 0x1000 && self.pc == pc {
+            self.jump_to_self_count += 1;
+        } else {
+            self.jump_to_self_count = 0;
+        }
         self.clock_steps += 1;
-        //assert!((self.pc % 2) == 0, "program counter is not even");
-        // slipperyslope jumps to uneven instruction (level-unpack at 0x265 (0x65 in file))
         Ok(instr)
     }
 
+    // Executes the instructions up to the start of the next frame or until the
+    // program exits or halts. The wall clock ends the frame after 1/60 s, with
+    // ClockMode::Instructions it is a fixed number of instructions.
+    pub fn run_frame(&mut self) -> Result<(), anyhow::Error> {
+        let frame = self.frame();
+        while self.frame() == frame && !self.exited && !self.halted {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // Runs a frame of ipf instructions and returns the display and the sound of
+    // it, for embedders that call it from their own 60 Hz loop instead of
+    // using the threads of the Emulator
+    pub fn step_frame(&mut self, ipf: u64) -> Result<FrameOutput, anyhow::Error> {
+        let mode = ClockMode::Instructions(ipf.max(1));
+        if self.clock.mode != mode {
+            // the frame number changes with ipf, the timers keep their values
+            let (dt, st) = self.timers();
+            self.clock.mode = mode;
+            let frame = self.frame();
+            self.dt.set_reg(dt, frame);
+            self.st.set_reg(st, frame);
+            self.vblank_wait = None;
+        }
+        self.run_frame()?;
+
+        let mut rgba = Vec::with_capacity(self.display.width * self.display.height * 4);
+        for color in self.display.to_buf() {
+            rgba.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8, 0xFF]);
+        }
+
+        self.frame_samples += FRAME_SAMPLE_RATE / self.clock.rate();
+        let mut audio_samples = vec![0i16; self.frame_samples as usize];
+        self.frame_samples = self.frame_samples.fract();
+        if self.frame_synth.is_none() {
+            self.frame_synth = Some(self.sound.synth(FRAME_SAMPLE_RATE));
+        }
+        if let Some(synth) = self.frame_synth.as_mut() {
+            synth.render(&mut audio_samples);
+        }
+
+        Ok(FrameOutput {
+            rgba,
+            width: self.display.width,
+            height: self.display.height,
+            audio_samples,
+            exit: self.exited || self.halted,
+        })
+    }
+
     fn process_instruction(&mut self, instr: u16) -> Result<usize, anyhow::Error>{
         let mut nibbles = [0u8; 4];
         nibbles[0] = ((instr >> 12) & 0xF) as u8;
@@ -467,6 +1255,85 @@ impl Cpu {
         let kk: u8 = (instr & 0xFF) as u8;
 
         match (nibbles[0], nibbles[1], nibbles[2], nibbles[3]) {
+            (0x0, 0x2, 0x3, 0x0) if self.display.two_page => {
+                // 0230 - CLS in the two-page hires interpreter
+                self.display.clear();
+            }
+            (0x0, 0x2, 0xA, 0x0) if self.display.color_map.is_some() => {
+                // 02A0 - Step the background color (CHIP-8X)
+                if let Some(map) = self.display.color_map.as_mut() {
+                    map.next_background();
+                }
+                self.display.flag_updated();
+            }
+            (0x0, 0x0, 0x1, 0x0) => {
+                // 0010 - Disable Mega-CHIP mode
+                self.display.set_megachip(false);
+            }
+            (0x0, 0x0, 0x1, 0x1) => {
+                // 0011 - Enable Mega-CHIP mode
+                if self.memory.len() < MEGA_MEMSIZE {
+                    self.memory.resize(MEGA_MEMSIZE, 0);
+                }
+                self.display.set_megachip(true);
+            }
+            (0x0, 0x0, 0xB, _) if self.display.mega.is_some() => {
+                // 00BN - Scroll display N lines up (Mega-CHIP)
+                self.display.scroll_up(nibbles[3]);
+            }
+            (0x0, 0x1, ..) if self.display.mega.is_some() => {
+                // 01NN NNNN - Load 24 bit address to I
+                self.i = (kk as u32) << 16 | self.read_operand() as u32;
+                self.pc = self.pc.wrapping_add(2);
+            }
+            (0x0, 0x2, ..) if self.display.mega.is_some() => {
+                // 02NN - Load NN palette colors (ARGB) from I
+                let colors = self.read_bytes(self.i, kk as usize * 4)?;
+                if let Some(mega) = self.display.mega.as_mut() {
+                    mega.load_palette(&colors);
+                }
+            }
+            (0x0, 0x3, ..)
+            | (0x0, 0x4, ..)
+            | (0x0, 0x5, ..)
+            | (0x0, 0x8, 0x0, _)
+            | (0x0, 0x9, ..)
+                if self.display.mega.is_some() =>
+            {
+                // 03NN sprite width, 04NN sprite height (0 means 256), 05NN screen alpha,
+                // 080N blend mode, 09NN collision color
+                let size = if kk == 0 { 256 } else { kk as usize };
+                if let Some(mega) = self.display.mega.as_mut() {
+                    match nibbles[1] {
+                        0x3 => mega.sprite_width = size,
+                        0x4 => mega.sprite_height = size,
+                        0x5 => mega.alpha = kk,
+                        0x8 => mega.blend = BlendMode::from_nibble(nibbles[3]),
+                        _ => mega.collision_color = kk,
+                    }
+                }
+            }
+            (0x0, 0x6, 0x0, _) if self.display.mega.is_some() => {
+                // 060N - Play digitized sound at I (rate, length, 8 bit samples), played once
+                let header = self.read_bytes(self.i, 5)?;
+                let rate = (header[0] as u32) << 8 | header[1] as u32;
+                let len =
+                    (header[2] as usize) << 16 | (header[3] as usize) << 8 | header[4] as usize;
+                let samples: Vec<i16> = self
+                    .read_bytes(self.i + 6, len)?
+                    .iter()
+                    .map(|sample| (*sample as i16 - 128) * 256)
+                    .collect();
+                if rate > 0 && !samples.is_empty() {
+                    self.sound.set_rate(rate as f64);
+                    let duration = Duration::from_secs_f64(len as f64 / rate as f64);
+                    self.sound.play_samples(&samples, duration);
+                }
+            }
+            (0x0, 0x7, 0x0, 0x0) if self.display.mega.is_some() => {
+                // 0700 - Stop digitized sound
+                self.sound.silence();
+            }
             (0x0, 0x0, 0xC, _) => {
                 // 0x00CN Scroll display N lines down
                 self.display.scroll_down(nibbles[3]);
@@ -492,7 +1359,8 @@ impl Cpu {
             }
             (0x0, 0x0, 0xF, 0xD) => {
                 // Exit CHIP interpreter
-                println!("TODO: EXIT");
+                self.exited = true;
+                return Ok(0);
             }
             (0x0, 0x0, 0xF, 0xE) => {
                 // Disable extended screen mode
@@ -502,6 +1370,10 @@ impl Cpu {
                 // Enable extended screen mode
                 self.display.set_extended(true);
             }
+            (0x0, ..) if nnn != 0 => {
+                // 0nnn - SYS addr, machine code routine of the host cpu
+                self.sys(nnn);
+            }
             (0x1, ..) => {
                 // JP addr
                 self.pc = nnn;
@@ -532,21 +1404,22 @@ impl Cpu {
                     self.skip_instruction();
                 }
             }
+            (0x5, _, _, 1) if self.display.color_map.is_some() => {
+                // 5xy1 - ADD Vx, Vy nibble wise, each nibble modulo 8 (CHIP-8X)
+                let (vx, vy) = (self.v[x], self.v[y]);
+                self.v[x] = ((vx & 0x70) + (vy & 0x70)) & 0x70 | ((vx & 0x7) + (vy & 0x7)) & 0x7;
+            }
             (0x5, _, _, 2) => {
                 // 5xy2 - LD [I], Vx-Vy
-                let i = self.i as usize;
-                let range = y - x;
-                let memslice = &mut self.memory[i..i + range + 1];
-                memslice.copy_from_slice(&self.v[x..y + 1]);
-                //self.i += x as u16 + 1;
+                let values: Vec<u8> = register_range(x, y).map(|r| self.v[r]).collect();
+                self.write_bytes(self.i, &values)?;
             }
             (0x5, _, _, 3) => {
                 // 5xy3 - LD Vx-Vy, [I]
-                let i = self.i as usize;
-                let range = y - x;
-                let memslice = &self.memory[i..i + range + 1];
-                self.v[x..y + 1].copy_from_slice(memslice);
-                //self.i += x as u16 + 1;
+                let values = self.read_bytes(self.i, x.max(y) - x.min(y) + 1)?;
+                for (r, value) in register_range(x, y).zip(values) {
+                    self.v[r] = value;
+                }
             }
             (0x6, ..) => {
                 // 6xkk - LD Vx, byte
@@ -564,14 +1437,23 @@ impl Cpu {
             (0x8, _, _, 0x1) => {
                 // 8xy1 - OR Vx, Vy
                 self.v[x] |= self.v[y];
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
             }
             (0x8, _, _, 0x2) => {
                 // 8xy2 - AND Vx, Vy
                 self.v[x] &= self.v[y];
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
             }
             (0x8, _, _, 0x3) => {
                 // 8xy3 - XOR Vx, Vy
                 self.v[x] ^= self.v[y];
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
             }
             (0x8, _, _, 0x4) => {
                 // 8xy4 - ADD Vx, Vy
@@ -596,11 +1478,9 @@ impl Cpu {
             }
             (0x8, _, _, 0x6) => {
                 // 8xy6 - SHR Vx {, Vy}
-                // quirk - original
-                //let vf = self.v[x] & 0x1;
-                //self.v[x] = self.v[x] >> 1;
-                let vf = self.v[y] & 0x1;
-                self.v[x] = self.v[y] >> 1;
+                let src = if self.quirks.shift_uses_vy { y } else { x };
+                let vf = self.v[src] & 0x1;
+                self.v[x] = self.v[src] >> 1;
                 self.v[0xF] = vf;
             }
             (0x8, _, _, 0x7) => {
@@ -617,12 +1497,9 @@ impl Cpu {
             }
             (0x8, _, _, 0xE) => {
                 // 8xyE - SHL Vx {, Vy}
-
-                // quirk - original
-                //self.v[0xF] = self.v[x] >> 7 & 0x1;
-                //self.v[x] = self.v[x] << 1;
-                self.v[0xF] = self.v[y] >> 7 & 0x1;
-                self.v[x] = self.v[y] << 1;
+                let src = if self.quirks.shift_uses_vy { y } else { x };
+                self.v[0xF] = self.v[src] >> 7 & 0x1;
+                self.v[x] = self.v[src] << 1;
             }
             (0x9, ..) => {
                 // 9xy0 - SNE Vx, Vy
@@ -632,42 +1509,84 @@ impl Cpu {
             }
             (0xA, ..) => {
                 // Annn - LD I, addr
-                self.i = nnn;
+                self.i = nnn as u32;
+            }
+            (0xB, ..) if self.display.color_map.is_some() => {
+                // Bxy0 - Color an area of 8x4 zones, BxyN - color N rows of 8x1 zones (CHIP-8X)
+                // the position is in Vx and Vx+1, the color in Vy
+                let (vx, vy, color) = (self.v[x], self.v[(x + 1) % 16], self.v[y]);
+                if let Some(map) = self.display.color_map.as_mut() {
+                    if nibbles[3] == 0 {
+                        map.fill_area(vx, vy, color);
+                    } else {
+                        map.fill_rows(vx, vy, nibbles[3], color);
+                    }
+                }
+                self.display.flag_updated();
             }
             (0xB, ..) => {
-                // Bnnn - JP V0, addr
-                //self.pc = nnn + self.v[x] as u16;
-                self.pc = nnn + self.v[0] as u16;
+                // Bnnn - JP V0, addr (BXnn - JP Vx, addr with the jump quirk)
+                let offset = if self.quirks.jump_with_vx {
+                    self.v[x]
+                } else {
+                    self.v[0]
+                };
+                self.pc = nnn + offset as u16;
                 return Ok(1);
             }
             (0xC, ..) => {
                 // Cxkk - RND Vx, byte
-                let rnd = rand::random::<u8>();
+                let rnd = self.rng.gen::<u8>();
                 self.v[x] = rnd & kk;
             }
             (0xD, ..) => {
                 // Dxyn - DRW Vx, Vy, nibble
-                let start = self.i as usize;
-                if nibbles[3] == 0 {
-                    let end = if self.display.active_planes == 0x3 {
-                        start + 64 as usize
+                if let Some(size) = self.mega_sprite_size() {
+                    // Mega-CHIP draws palette indexed sprites, except for the fonts
+                    let (vx, vy) = (self.v[x], self.v[y]);
+                    let font = self.i < 180;
+                    let len = if font { nibbles[3] as usize } else { size };
+                    let sprite = self.read_bytes(self.i, len)?;
+                    let mega = self.display.mega.as_mut().unwrap();
+                    let collision = if font {
+                        mega.draw_mono(&sprite, vx, vy)
                     } else {
-                        start + 32 as usize
+                        mega.draw_sprite(&sprite, vx, vy)
                     };
-                    let sprites = &self.memory[start..end];
-                    let collision = self.display.write_sprite16(sprites, self.v[x], self.v[y])?;
+                    self.v[0xF] = if collision { 1 } else { 0 };
+                } else if nibbles[3] == 0 {
+                    let len = if self.display.active_planes == 0x3 {
+                        64
+                    } else {
+                        32
+                    };
+                    let sprites = self.read_bytes(self.i, len)?;
+                    let collision = self.display.write_sprite16(
+                        &sprites,
+                        self.v[x],
+                        self.v[y],
+                        self.quirks.clip_sprites,
+                    )?;
                     self.v[0xF] = if collision { 1 } else { 0 };
                 } else {
-                    let end = if self.display.active_planes == 0x3 {
-                        start + nibbles[3] as usize * 2
+                    let len = if self.display.active_planes == 0x3 {
+                        nibbles[3] as usize * 2
                     } else {
-                        start + nibbles[3] as usize
+                        nibbles[3] as usize
                     };
 
-                    let sprites = &self.memory[start..end];
-                    let collision = self.display.write_sprite(sprites, self.v[x], self.v[y]);
+                    let sprites = self.read_bytes(self.i, len)?;
+                    let collision = self.display.write_sprite(
+                        &sprites,
+                        self.v[x],
+                        self.v[y],
+                        self.quirks.clip_sprites,
+                    );
                     self.v[0xF] = if collision { 1 } else { 0 };
                 }
+                if self.quirks.display_wait {
+                    self.vblank_wait = Some(self.frame());
+                }
             }
             (0xE, _, 0x9, 0xE) => {
                 // Ex9E - SKP Vx
@@ -681,10 +1600,17 @@ impl Cpu {
                     self.skip_instruction();
                 }
             }
+            (0xE, _, 0xF, 0x2) if self.display.color_map.is_some() => {
+                // ExF2 - SKP Vx on the second keypad, which is never attached (CHIP-8X)
+            }
+            (0xE, _, 0xF, 0x5) if self.display.color_map.is_some() => {
+                // ExF5 - SKNP Vx on the second keypad (CHIP-8X)
+                self.skip_instruction();
+            }
             (0xF, 0x0, 0x0, 0x0) => {
                 // F000 NNNN - load NNNN to i
-                self.pc += 2;
-                self.i = read_memory(&self.memory, self.pc);
+                self.i = self.read_operand() as u32;
+                self.pc = self.pc.wrapping_add(2);
             }
             (0xF, _, 0x0, 0x1) => {
                 // 0xFN01 Select drawing plane by bitmask (0 <= n <= 3)
@@ -692,23 +1618,33 @@ impl Cpu {
             }
             (0xF, 0x0, 0x0, 0x2) => {
                 // 0xF002 - Store 16 bytes starting at i in the audio pattern buffer.
-                let i = self.i as usize;
-                let samples = &self.memory[i..i+16];
-                self.sound_memory.copy_from_slice(samples);
+                let samples = self.read_bytes(self.i, 16)?;
+                self.sound_memory.copy_from_slice(&samples);
+                // a playing tone changes to the new pattern
+                self.sound.set_pattern_1bit(&self.sound_memory);
             }
             (0xF, _, 0x0, 0x7) => {
                 // Fx07 - LD Vx, DT
-                self.v[x] = self.dt.get_reg();
+                self.v[x] = self.dt.get_reg(self.frame());
             }
             (0xF, _, 0x0, 0xA) => {
                 // Fx0A - LD Vx, K
-                let pressed_key = self.keyboard.keys.iter().position(|&v| v == VKey::Down);
+                let pressed_key = self.keyboard.newest_press();
                 let mut key_change = false;
-                if let Some(pressed_key) = pressed_key {
-                    if self.keyboard.prev_keys[pressed_key] == VKey::Up {
-                        self.v[x] = pressed_key as u8;
-                        key_change = true
+                if self.quirks.key_wait_release {
+                    // the COSMAC VIP only continues once the pressed key is released again
+                    match self.keyboard.held_key {
+                        Some(held_key) if self.keyboard.keys[held_key] == VKey::Up => {
+                            self.v[x] = held_key as u8;
+                            self.keyboard.held_key = None;
+                            key_change = true;
+                        }
+                        Some(..) => {}
+                        None => self.keyboard.held_key = pressed_key,
                     }
+                } else if let Some(pressed_key) = pressed_key {
+                    self.v[x] = pressed_key as u8;
+                    key_change = true
                 }
                 self.keyboard.prev_keys = self.keyboard.keys;
                 if !key_change {
@@ -717,49 +1653,66 @@ impl Cpu {
             }
             (0xF, _, 0x1, 0x5) => {
                 // Fx15 - LD DT, Vx
-                self.dt.set_reg(self.v[x]);
+                self.dt.set_reg(self.v[x], self.frame());
             }
             (0xF, _, 0x1, 0x8) => {
                 // Fx18 - LD ST, Vx
-                self.st.set_reg(self.v[x]);
-                if let Some(duration) = self.st.time_left() {
-                    self.sound.play_samples_1bit(&self.sound_memory[..], duration);
+                self.st.set_reg(self.v[x], self.frame());
+                match self.st.time_left(self.clock.rate()) {
+                    Some(duration) => self.sound.play_samples_1bit(&self.sound_memory[..], duration),
+                    // ST = 0 cuts a playing tone
+                    None => self.sound.silence(),
                 }
             }
             (0xF, _, 0x1, 0xE) => {
                 // Fx1E - ADD I, Vx
-                self.i += self.v[x] as u16;
+                self.i = (self.i + self.v[x] as u32) & self.addr_mask();
+                if self.quirks.fx1e_carry {
+                    self.v[0xF] = if self.i > 0x0FFF { 1 } else { 0 };
+                }
             }
             (0xF, _, 0x2, 0x9) => {
                 // Fx29 - LD F, Vx
-                self.i = self.v[x] as u16 * 5;
+                self.i = self.v[x] as u32 * 5;
             }
             (0xF, _, 0x3, 0x0) => {
                 // Fx30 - LD (Hires)F, Vx
-                self.i = self.v[x] as u16 * 10 + 16 * 5;
+                self.i = self.v[x] as u32 * 10 + 16 * 5;
+            }
+            (0xF, _, 0x3, 0xA) if self.display.color_map.is_some() => {
+                // Fx3A - OUT Vx to the tone generator of the VP-595 sound board,
+                // which plays 27535 / (Vx + 1) Hz (CHIP-8X)
+                self.pitch = self.v[x];
+                let frequency = 27535.0 / (self.pitch as f64 + 1.0);
+                // the default pattern toggles on every sample
+                self.sound.set_rate(2.0 * frequency);
+            }
+            (0xF, _, 0x3, 0xA) => {
+                // Fx3A - LD PITCH, Vx
+                self.pitch = self.v[x];
+                let rate = 4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0);
+                self.sound.set_rate(rate);
             }
             (0xF, _, 0x3, 0x3) => {
                 // Fx33 - LD B, Vx
-                let i = self.i as usize;
                 let vx = self.v[x];
-                let memslice = &mut self.memory[i..i + 3];
-                memslice[0] = vx / 100;
-                memslice[1] = (vx / 10) % 10;
-                memslice[2] = vx % 10;
+                self.write_bytes(self.i, &[vx / 100, (vx / 10) % 10, vx % 10])?;
             }
             (0xF, _, 0x5, 0x5) => {
                 // Fx55 - LD [I], Vx
-                let i = self.i as usize;
-                let memslice = &mut self.memory[i..i + x + 1];
-                memslice.copy_from_slice(&self.v[0..x + 1]);
-                //self.i += x as u16 + 1;
+                let values = self.v[0..x + 1].to_vec();
+                self.write_bytes(self.i, &values)?;
+                if self.quirks.load_store_increment_i {
+                    self.i = (self.i + x as u32 + 1) & self.addr_mask();
+                }
             }
             (0xF, _, 0x6, 0x5) => {
                 //Fx65 - LD Vx, [I]
-                let i = self.i as usize;
-                let memslice = &self.memory[i..i + x + 1];
-                self.v[0..x + 1].copy_from_slice(memslice);
-                //self.i += x as u16 + 1;
+                let values = self.read_bytes(self.i, x + 1)?;
+                self.v[0..x + 1].copy_from_slice(&values);
+                if self.quirks.load_store_increment_i {
+                    self.i = (self.i + x as u32 + 1) & self.addr_mask();
+                }
             }
             (0xF, _, 0x7, 0x5) => {
                 // Fx75 - LD repl, Vx
@@ -768,17 +1721,77 @@ impl Cpu {
             }
             (0xF, _, 0x8, 0x5) => {
                 // Fx85 - LD Vx, repl
-                let memslice = &self.memory[0..x + 1];
-                self.v[0..x + 1].copy_from_slice(memslice);
+                let repl_slice = &self.repl[0..x + 1];
+                self.v[0..x + 1].copy_from_slice(repl_slice);
+            }
+            (0xF, _, 0xF, 0x8) if self.display.color_map.is_some() => {
+                // FxF8 - OUT Vx, no device is attached to the I/O port (CHIP-8X)
+            }
+            (0xF, _, 0xF, 0xB) if self.display.color_map.is_some() => {
+                // FxFB - IN Vx, reads 0 from the unconnected I/O port (CHIP-8X)
+                self.v[x] = 0;
             }
 
-            _ => panic!("unknown opcode: {}", instr),
+            _ => return self.unknown_opcode(instr),
         }
-        self.pc += 2;
+        // the program counter wraps around at the end of memory
+        self.pc = self.pc.wrapping_add(2);
         Ok(1)
     }
 }
 
-fn read_memory(mem: &[u8; MEMSIZE], addr: u16) -> u16 {
-    (mem[addr as usize] as u16) << 8 | mem[addr as usize + 1] as u16
+impl Cpu {
+    // I wraps at 16 bits, or at 24 bits in Mega-CHIP mode
+    fn addr_mask(&self) -> u32 {
+        if self.display.mega.is_some() {
+            0xFF_FFFF
+        } else {
+            0xFFFF
+        }
+    }
+
+    fn sys(&mut self, addr: u16) {
+        // the hook is taken out while it runs so that it can borrow the cpu
+        let handled = match self.sys_hook.take() {
+            Some(mut hook) => {
+                let handled = hook(self, addr);
+                self.sys_hook = Some(hook);
+                handled
+            }
+            None => false,
+        };
+        if !handled && self.skipped_sys.insert(addr) {
+            eprintln!("Skipping machine code routine at {:#05X}", addr);
+        }
+    }
+
+    fn unknown_opcode(&mut self, instr: u16) -> Result<usize, anyhow::Error> {
+        let error = CpuError::UnknownOpcode { pc: self.pc, instr };
+        match self.unknown_opcode_policy {
+            UnknownOpcodePolicy::Stop => {
+                eprintln!("Stopping: {}", error);
+                self.halted = true;
+                Ok(0)
+            }
+            UnknownOpcodePolicy::SkipAndWarn => {
+                eprintln!("Skipping {}", error);
+                self.pc = self.pc.wrapping_add(2);
+                Ok(1)
+            }
+            UnknownOpcodePolicy::Error => Err(error.into()),
+        }
+    }
+}
+
+fn read_memory(mem: &[u8], addr: u16) -> u16 {
+    (mem[addr as usize] as u16) << 8 | mem[(addr as usize + 1) % MEMSIZE] as u16
+}
+
+// The registers Vx to Vy of 5xy2 and 5xy3, in reverse order when x is larger
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
 }
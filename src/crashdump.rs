@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use super::cpu::{Cpu, MEMSIZE};
+use super::debugger::{call_stack, format_memory_line};
+use super::disasm::disassemble;
+
+// number of instructions kept for the crash dump
+const HISTORY_LEN: usize = 64;
+
+// registers before an instruction was executed
+struct Entry {
+    pc: u16,
+    instr: u16,
+    v: [u8; 16],
+    i: u32,
+}
+
+// The last executed instructions, written to a file when the cpu fails
+pub struct History {
+    entries: VecDeque<Entry>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    // Called before every instruction
+    pub fn record(&mut self, cpu: &Cpu) {
+        if self.entries.len() == HISTORY_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            pc: cpu.pc,
+            instr: cpu.next_instruction(),
+            v: cpu.v,
+            i: cpu.i,
+        });
+    }
+
+    pub fn write_dump(
+        &self,
+        path: &Path,
+        cpu: &Cpu,
+        error: &anyhow::Error,
+    ) -> Result<(), anyhow::Error> {
+        let file = File::create(path)
+            .with_context(|| format!("Could not create crash dump {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        writeln!(out, "Error: {:#}", error)?;
+
+        writeln!(out, "\nLast instructions, oldest first:")?;
+        for (index, entry) in self.entries.iter().enumerate() {
+            // registers after the instruction, the last one is still the current state
+            let (v, i) = match self.entries.get(index + 1) {
+                Some(next) => (next.v, next.i),
+                None => (cpu.v, cpu.i),
+            };
+            let mut changes: Vec<String> = (0..16)
+                .filter(|x| entry.v[*x] != v[*x])
+                .map(|x| format!("V{:X} {:02X}->{:02X}", x, entry.v[x], v[x]))
+                .collect();
+            if entry.i != i {
+                changes.push(format!("I {:04X}->{:04X}", entry.i, i));
+            }
+            writeln!(
+                out,
+                "{:04X}  {:04X}  {:<20}  {}",
+                entry.pc,
+                entry.instr,
+                disassemble(entry.instr),
+                changes.join(" ")
+            )?;
+        }
+
+        let (dt, st) = cpu.timers();
+        writeln!(out, "\nRegisters:")?;
+        for x in 0..8 {
+            writeln!(
+                out,
+                "V{:X} {:02X}    V{:X} {:02X}",
+                x,
+                cpu.v[x],
+                x + 8,
+                cpu.v[x + 8]
+            )?;
+        }
+        writeln!(out, "PC {:04X}  I {:04X}  SP {}", cpu.pc, cpu.i, cpu.sp)?;
+        writeln!(out, "DT {:02X}    ST {:02X}", dt, st)?;
+        writeln!(out, "frame {}  cycles {}", cpu.frame(), cpu.clock_steps)?;
+
+        writeln!(out, "\nCall stack:")?;
+        for line in call_stack(cpu) {
+            writeln!(out, "{}", line)?;
+        }
+
+        writeln!(out, "\nMemory, lines with only zeros are left out:")?;
+        let memory = &cpu.memory[..MEMSIZE.min(cpu.memory.len())];
+        for (line, bytes) in memory.chunks(16).enumerate() {
+            if bytes.iter().any(|byte| *byte != 0) {
+                writeln!(out, "{}", format_memory_line(line * 16, bytes))?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
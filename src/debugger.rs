@@ -0,0 +1,572 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use anyhow::{bail, Context};
+
+use super::cpu::{Cpu, WatchHit};
+use super::disasm::{disassemble, instruction_class, INSTRUCTIONS};
+use super::sprites;
+use super::tui::Watch;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Dt,
+    St,
+}
+
+impl FromStr for Register {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        match name.as_str() {
+            "i" => Ok(Register::I),
+            "pc" => Ok(Register::Pc),
+            "dt" => Ok(Register::Dt),
+            "st" => Ok(Register::St),
+            _ if name.len() == 2 && name.starts_with('v') => {
+                let x = usize::from_str_radix(&name[1..], 16)
+                    .with_context(|| format!("Unknown register {}", s))?;
+                Ok(Register::V(x))
+            }
+            _ => bail!("Unknown register {}, expected v0-vf, i, pc, dt or st", s),
+        }
+    }
+}
+
+// Group of instructions the debugger can break on, independent of their address
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OpcodeClass {
+    // all instructions with this mnemonic, e.g. DRW or CALL
+    Mnemonic(&'static str),
+    // instructions that read memory through I
+    ReadI,
+    // instructions that write memory through I
+    WriteI,
+}
+
+impl OpcodeClass {
+    pub fn matches(self, instr: u16) -> bool {
+        match self {
+            OpcodeClass::Mnemonic(name) => {
+                instruction_class(instr).map(|class| mnemonic(INSTRUCTIONS[class].2)) == Some(name)
+            }
+            // DXYN, 5XY3, F002, FX65
+            OpcodeClass::ReadI => {
+                instr & 0xF000 == 0xD000
+                    || instr & 0xF00F == 0x5003
+                    || instr == 0xF002
+                    || instr & 0xF0FF == 0xF065
+            }
+            // 5XY2, FX33, FX55
+            OpcodeClass::WriteI => {
+                instr & 0xF00F == 0x5002 || instr & 0xF0FF == 0xF033 || instr & 0xF0FF == 0xF055
+            }
+        }
+    }
+}
+
+// "DXYN DRW" -> "DRW"
+fn mnemonic(name: &'static str) -> &'static str {
+    name.split_whitespace().nth(1).unwrap_or(name)
+}
+
+impl FromStr for OpcodeClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "read-i" => Ok(OpcodeClass::ReadI),
+            "write-i" => Ok(OpcodeClass::WriteI),
+            _ => INSTRUCTIONS
+                .iter()
+                .map(|(_, _, name)| mnemonic(name))
+                .find(|name| name.eq_ignore_ascii_case(s))
+                .map(OpcodeClass::Mnemonic)
+                .with_context(|| {
+                    format!(
+                        "Unknown instruction class {}, expected a mnemonic like DRW, read-i or write-i",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+impl fmt::Display for OpcodeClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpcodeClass::Mnemonic(name) => write!(f, "{}", name),
+            OpcodeClass::ReadI => write!(f, "read-i"),
+            OpcodeClass::WriteI => write!(f, "write-i"),
+        }
+    }
+}
+
+// Commands sent from the window and the console to the cpu thread
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Command {
+    Pause,
+    // pauses a running cpu and continues a paused one, the P hotkey
+    TogglePause,
+    Step,
+    StepFrame,
+    RunTo(u16),
+    Resume,
+    Break(u16),
+    Delete(u16),
+    BreakOpcode(OpcodeClass),
+    DeleteOpcode(OpcodeClass),
+    Dump(Watch),
+    // n rows of the sprite at the address, 0 for 16x16
+    Sprite(u16, usize),
+    Set(Register, u16),
+    CallStack,
+    // handled by the emulator, which holds the saved states and counts
+    Rewind,
+    // starts the rom again with a new machine
+    Reset,
+    // factor of the instruction rate and the timers, the turbo and slow motion
+    // keys
+    Speed(f64),
+    // machine state slots 1 - 9 on disk
+    SaveState(u8),
+    LoadState(u8),
+    GotoFrame(u64),
+    Hotspots(usize),
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, anyhow::Error> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let arg = words.next();
+        let value = words.next();
+        let addr = || -> Result<u16, anyhow::Error> { parse_addr(arg.context("Missing address")?) };
+        let command = match name {
+            "p" | "pause" => Command::Pause,
+            "s" | "step" => Command::Step,
+            "f" | "frame" => Command::StepFrame,
+            "bt" | "stack" => Command::CallStack,
+            "rewind" => Command::Rewind,
+            "reset" => Command::Reset,
+            "save" | "load" => {
+                let slot = arg.context("Missing slot")?;
+                let slot = slot
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|slot| (1..=9).contains(slot))
+                    .with_context(|| format!("Invalid slot {}, expected 1-9", slot))?;
+                if name == "save" {
+                    Command::SaveState(slot)
+                } else {
+                    Command::LoadState(slot)
+                }
+            }
+            "g" | "goto-frame" => {
+                let frame = arg.context("Missing frame")?;
+                Command::GotoFrame(
+                    frame
+                        .parse::<u64>()
+                        .with_context(|| format!("Invalid frame {}", frame))?,
+                )
+            }
+            "hot" | "hotspots" => {
+                let top = arg.unwrap_or("10");
+                Command::Hotspots(
+                    top.parse::<usize>()
+                        .with_context(|| format!("Invalid count {}", top))?,
+                )
+            }
+            "c" | "continue" => Command::Resume,
+            "r" | "run" => Command::RunTo(addr()?),
+            "b" | "break" => Command::Break(addr()?),
+            "d" | "delete" => Command::Delete(addr()?),
+            "bo" | "break-op" => {
+                Command::BreakOpcode(arg.context("Missing instruction class")?.parse()?)
+            }
+            "do" | "delete-op" => {
+                Command::DeleteOpcode(arg.context("Missing instruction class")?.parse()?)
+            }
+            "m" | "mem" => Command::Dump(parse_range(arg.context("Missing address")?)?),
+            "sprite" => {
+                let rows = value.unwrap_or("8");
+                let n = rows
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n < 16)
+                    .with_context(|| format!("Invalid number of rows {}, expected 0-15", rows))?;
+                Command::Sprite(addr()?, n)
+            }
+            "set" => {
+                let register = arg.context("Missing register")?.parse::<Register>()?;
+                let value = parse_addr(value.context("Missing value")?)?;
+                Command::Set(register, value)
+            }
+            command => bail!("Unknown command {}", command),
+        };
+        Ok(command)
+    }
+}
+
+// Holds the cpu thread between instructions while paused
+#[derive(Default)]
+pub struct Debugger {
+    paused: bool,
+    step: bool,
+    stepped: bool,
+    run_to: Option<u16>,
+    breakpoints: BTreeSet<u16>,
+    opcode_breaks: Vec<OpcodeClass>,
+    // address the cpu was paused at, continuing from it does not break again
+    paused_at: Option<u16>,
+    // memory range that is dumped whenever the cpu pauses
+    dump: Option<Watch>,
+    // frame a frame step runs to
+    frame_step: Option<u64>,
+    redraw: bool,
+}
+
+impl Debugger {
+    pub fn new(breakpoints: &[u16], opcode_breaks: &[OpcodeClass], dump: Option<Watch>) -> Self {
+        Self {
+            breakpoints: breakpoints.iter().copied().collect(),
+            opcode_breaks: opcode_breaks.to_vec(),
+            dump,
+            ..Self::default()
+        }
+    }
+
+    pub fn handle(&mut self, command: Command, cpu: &mut Cpu) {
+        match command {
+            Command::Pause => self.pause(cpu),
+            Command::TogglePause if self.paused => {
+                self.run_to = None;
+                self.resume(cpu);
+            }
+            Command::TogglePause => self.pause(cpu),
+            Command::Step => {
+                self.pause(cpu);
+                self.step = self.frame_step.is_none();
+            }
+            Command::StepFrame => {
+                self.pause(cpu);
+                if self.frame_step.is_none() {
+                    // the clock has to run for the timers to reach the next frame
+                    self.frame_step = Some(cpu.frame() + 1);
+                    cpu.clock.resume();
+                }
+            }
+            Command::RunTo(addr) => {
+                self.run_to = Some(addr);
+                self.resume(cpu);
+            }
+            Command::Resume => {
+                self.run_to = None;
+                self.resume(cpu);
+            }
+            Command::Break(addr) => {
+                self.breakpoints.insert(addr);
+                print_breakpoints(&self.breakpoints);
+            }
+            Command::Delete(addr) => {
+                self.breakpoints.remove(&addr);
+                print_breakpoints(&self.breakpoints);
+            }
+            Command::BreakOpcode(class) => {
+                if !self.opcode_breaks.contains(&class) {
+                    self.opcode_breaks.push(class);
+                }
+                print_opcode_breaks(&self.opcode_breaks);
+            }
+            Command::DeleteOpcode(class) => {
+                self.opcode_breaks.retain(|other| *other != class);
+                print_opcode_breaks(&self.opcode_breaks);
+            }
+            Command::Dump(range) => print_memory(cpu, range),
+            Command::Sprite(addr, n) => {
+                let start = (addr as usize).min(cpu.memory.len());
+                for line in sprites::ascii(&cpu.memory[start..], n) {
+                    println!("{}", line);
+                }
+            }
+            Command::CallStack => {
+                for line in call_stack(cpu) {
+                    println!("{}", line);
+                }
+            }
+            Command::Rewind
+            | Command::Reset
+            | Command::Speed(..)
+            | Command::SaveState(..)
+            | Command::LoadState(..)
+            | Command::GotoFrame(..)
+            | Command::Hotspots(..) => {}
+            Command::Set(register, value) => {
+                if !self.paused {
+                    println!("Registers can only be changed while paused");
+                    return;
+                }
+                if let Err(err) = set_register(cpu, register, value) {
+                    println!("{:#}", err);
+                    return;
+                }
+                print_location(cpu);
+            }
+        }
+    }
+
+    // Called before every instruction, returns false while the cpu is held
+    pub fn should_run(&mut self, cpu: &mut Cpu) -> bool {
+        if self.run_to == Some(cpu.pc) {
+            self.run_to = None;
+            self.pause(cpu);
+        }
+        if !self.paused && self.breakpoints.contains(&cpu.pc) && self.paused_at != Some(cpu.pc) {
+            println!("Breakpoint hit");
+            self.pause(cpu);
+        }
+        if !self.paused && self.paused_at != Some(cpu.pc) {
+            let instr = cpu.next_instruction();
+            if let Some(class) = self.opcode_breaks.iter().find(|class| class.matches(instr)) {
+                println!("Breakpoint on {} hit", class);
+                self.pause(cpu);
+            }
+        }
+        if !self.paused {
+            self.paused_at = None;
+            return true;
+        }
+        if let Some(frame) = self.frame_step {
+            if cpu.frame() < frame {
+                return true;
+            }
+            self.frame_step = None;
+            self.stepped = true;
+            cpu.clock.pause();
+        }
+        if self.stepped {
+            self.stepped = false;
+            self.paused_at = Some(cpu.pc);
+            self.redraw = true;
+            self.report(cpu);
+        }
+        if self.step {
+            self.step = false;
+            self.stepped = true;
+            return true;
+        }
+        false
+    }
+
+    pub fn watch_hit(&mut self, hit: WatchHit, cpu: &mut Cpu) {
+        println!(
+            "{} of {:02X} at {:04X} by the instruction at {:04X}",
+            hit.access, hit.value, hit.addr, hit.pc
+        );
+        if self.frame_step.take().is_some() {
+            cpu.clock.pause();
+        }
+        self.pause(cpu);
+    }
+
+    // The emulator replaced the machine state, shows it if paused
+    pub fn state_replaced(&mut self, cpu: &Cpu) {
+        if self.paused {
+            self.paused_at = Some(cpu.pc);
+            self.redraw = true;
+            self.report(cpu);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // true once after a step, the display has to be shown although the cpu is paused
+    pub fn take_redraw(&mut self) -> bool {
+        std::mem::replace(&mut self.redraw, false)
+    }
+
+    fn pause(&mut self, cpu: &mut Cpu) {
+        if !self.paused {
+            self.paused = true;
+            self.paused_at = Some(cpu.pc);
+            cpu.clock.pause();
+            cpu.sound.silence();
+            self.report(cpu);
+        }
+    }
+
+    fn report(&self, cpu: &Cpu) {
+        print_location(cpu);
+        if let Some(range) = self.dump {
+            print_memory(cpu, range);
+        }
+    }
+
+    fn resume(&mut self, cpu: &mut Cpu) {
+        if self.paused {
+            self.paused = false;
+            self.step = false;
+            self.stepped = false;
+            self.frame_step = None;
+            cpu.clock.resume();
+            cpu.resume_sound();
+            println!("Running");
+        }
+    }
+}
+
+fn print_location(cpu: &Cpu) {
+    let instr = cpu.next_instruction();
+    println!(
+        "Paused at {:04X}  {:04X}  {}",
+        cpu.pc,
+        instr,
+        disassemble(instr)
+    );
+}
+
+// Current instruction followed by the calls that lead to it, innermost first
+pub fn call_stack(cpu: &Cpu) -> Vec<String> {
+    let instr = cpu.next_instruction();
+    let mut lines = vec![format!("#0  {:04X}  {}", cpu.pc, disassemble(instr))];
+    for level in (1..=cpu.sp as usize).rev() {
+        // the stack holds the address of the CALL instruction
+        let addr = cpu.stack[level];
+        let instr = u16::from_be_bytes([
+            cpu.memory[addr as usize],
+            cpu.memory[(addr as usize + 1) % cpu.memory.len()],
+        ]);
+        lines.push(format!(
+            "#{}  {:04X}  {}",
+            cpu.sp as usize + 1 - level,
+            addr,
+            disassemble(instr)
+        ));
+    }
+    lines
+}
+
+// Hex and ASCII dump with 16 bytes per line
+fn print_memory(cpu: &Cpu, range: Watch) {
+    let start = range.addr as usize;
+    let end = (start + range.len as usize).min(cpu.memory.len());
+    for line in (start..end).step_by(16) {
+        println!(
+            "{}",
+            format_memory_line(line, &cpu.memory[line..(line + 16).min(end)])
+        );
+    }
+}
+
+// One line of the hex dump, up to 16 bytes starting at addr
+pub fn format_memory_line(addr: usize, bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    let ascii: String = bytes
+        .iter()
+        .map(|byte| {
+            if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    format!("{:04X}  {:<47}  {}", addr, hex.join(" "), ascii)
+}
+
+// hex with or without 0x
+pub fn parse_addr(input: &str) -> Result<u16, anyhow::Error> {
+    let digits = input.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).with_context(|| format!("Invalid address {}", input))
+}
+
+// ADDR[:LEN] of --watch-read and --watch-write, LEN defaults to one byte
+pub fn parse_watch(input: &str) -> Result<Watch, anyhow::Error> {
+    match input.find(':') {
+        Some(colon) => {
+            let len = &input[colon + 1..];
+            let len = len
+                .parse::<u16>()
+                .with_context(|| format!("Invalid watch length {}", len))?;
+            Ok(Watch {
+                addr: parse_addr(&input[..colon])?,
+                len,
+            })
+        }
+        None => Ok(Watch {
+            addr: parse_addr(input)?,
+            len: 1,
+        }),
+    }
+}
+
+// ADDR[:LEN] like --watch, but LEN defaults to one line of the dump
+pub fn parse_range(input: &str) -> Result<Watch, anyhow::Error> {
+    let mut range = parse_watch(input)?;
+    if !input.contains(':') {
+        range.len = 16;
+    }
+    Ok(range)
+}
+
+fn set_register(cpu: &mut Cpu, register: Register, value: u16) -> Result<(), anyhow::Error> {
+    let byte =
+        || u8::try_from(value).with_context(|| format!("{:#X} does not fit into a byte", value));
+    let (dt, st) = cpu.timers();
+    match register {
+        Register::V(x) => cpu.v[x] = byte()?,
+        Register::I => cpu.i = value as u32,
+        Register::Pc => cpu.pc = value,
+        Register::Dt => cpu.set_timers(byte()?, st),
+        Register::St => cpu.set_timers(dt, byte()?),
+    }
+    Ok(())
+}
+
+fn print_breakpoints(breakpoints: &BTreeSet<u16>) {
+    let addrs: Vec<String> = breakpoints
+        .iter()
+        .map(|addr| format!("{:04X}", addr))
+        .collect();
+    println!("Breakpoints: {}", addrs.join(" "));
+}
+
+fn print_opcode_breaks(classes: &[OpcodeClass]) {
+    let names: Vec<String> = classes.iter().map(|class| class.to_string()).collect();
+    println!("Instruction breakpoints: {}", names.join(" "));
+}
+
+// Reads debugger commands from stdin, the thread ends with stdin or the cpu thread
+pub fn spawn_console(tx: Sender<Command>) {
+    println!("Debugger commands: pause, step, frame, continue, run ADDR, break ADDR, delete ADDR, break-op CLASS, delete-op CLASS, mem ADDR[:LEN], sprite ADDR [N], set REG VALUE, stack, rewind, reset, save N, load N, goto-frame N, hot [N]");
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(..) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Command::parse(&line) {
+                Ok(command) => {
+                    if tx.send(command).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => println!("{:#}", err),
+            }
+        }
+    });
+}
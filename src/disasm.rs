@@ -0,0 +1,178 @@
+// Disassembles a single instruction into its mnemonic, e.g. "DRW V2, V3, 4"
+pub fn disassemble(instr: u16) -> String {
+    format_instruction(instr, &|x| format!("V{:X}", x))
+}
+
+// Same as disassemble, with the current register values, e.g. "DRW V2(12), V3(5), 4"
+pub fn disassemble_with_registers(instr: u16, v: &[u8; 16]) -> String {
+    format_instruction(instr, &|x| format!("V{:X}({})", x, v[x]))
+}
+
+// (mask, value, name) of every implemented instruction, specific patterns first
+pub const INSTRUCTIONS: &[(u16, u16, &str)] = &[
+    (0xFFFF, 0x00E0, "00E0 CLS"),
+    (0xFFFF, 0x00EE, "00EE RET"),
+    (0xFFF0, 0x00B0, "00BN SCU"),
+    (0xFFF0, 0x00C0, "00CN SCD"),
+    (0xFFF0, 0x00D0, "00DN SCU"),
+    (0xFFFF, 0x00FB, "00FB SCR"),
+    (0xFFFF, 0x00FC, "00FC SCL"),
+    (0xFFFF, 0x00FD, "00FD EXIT"),
+    (0xFFFF, 0x00FE, "00FE LOW"),
+    (0xFFFF, 0x00FF, "00FF HIGH"),
+    (0xFFFF, 0x0010, "0010 MEGAOFF"),
+    (0xFFFF, 0x0011, "0011 MEGAON"),
+    (0xFF00, 0x0100, "01NN LDHI"),
+    (0xFFFF, 0x0230, "0230 CLS (hires)"),
+    (0xFFFF, 0x02A0, "02A0 BGCOL"),
+    (0xFF00, 0x0200, "02NN LDPAL"),
+    (0xFF00, 0x0300, "03NN SPRW"),
+    (0xFF00, 0x0400, "04NN SPRH"),
+    (0xFF00, 0x0500, "05NN ALPHA"),
+    (0xFFF0, 0x0600, "060N DIGISND"),
+    (0xFFFF, 0x0700, "0700 STOPSND"),
+    (0xFFF0, 0x0800, "080N BMODE"),
+    (0xFF00, 0x0900, "09NN CCOL"),
+    (0xF000, 0x0000, "0NNN SYS"),
+    (0xF000, 0x1000, "1NNN JP"),
+    (0xF000, 0x2000, "2NNN CALL"),
+    (0xF000, 0x3000, "3XKK SE"),
+    (0xF000, 0x4000, "4XKK SNE"),
+    (0xF00F, 0x5000, "5XY0 SE"),
+    (0xF00F, 0x5001, "5XY1 ADDN"),
+    (0xF00F, 0x5002, "5XY2 LD [I], Vx-Vy"),
+    (0xF00F, 0x5003, "5XY3 LD Vx-Vy, [I]"),
+    (0xF000, 0x6000, "6XKK LD"),
+    (0xF000, 0x7000, "7XKK ADD"),
+    (0xF00F, 0x8000, "8XY0 LD"),
+    (0xF00F, 0x8001, "8XY1 OR"),
+    (0xF00F, 0x8002, "8XY2 AND"),
+    (0xF00F, 0x8003, "8XY3 XOR"),
+    (0xF00F, 0x8004, "8XY4 ADD"),
+    (0xF00F, 0x8005, "8XY5 SUB"),
+    (0xF00F, 0x8006, "8XY6 SHR"),
+    (0xF00F, 0x8007, "8XY7 SUBN"),
+    (0xF00F, 0x800E, "8XYE SHL"),
+    (0xF00F, 0x9000, "9XY0 SNE"),
+    (0xF000, 0xA000, "ANNN LD I"),
+    (0xF000, 0xB000, "BNNN JP V0"),
+    (0xF000, 0xC000, "CXKK RND"),
+    (0xF00F, 0xD000, "DXY0 DRW 16x16"),
+    (0xF000, 0xD000, "DXYN DRW"),
+    (0xF0FF, 0xE09E, "EX9E SKP"),
+    (0xF0FF, 0xE0A1, "EXA1 SKNP"),
+    (0xF0FF, 0xE0F2, "EXF2 SKP2"),
+    (0xF0FF, 0xE0F5, "EXF5 SKNP2"),
+    (0xFFFF, 0xF000, "F000 LD I, LONG"),
+    (0xFFFF, 0xF002, "F002 AUDIO"),
+    (0xF0FF, 0xF001, "FN01 PLANE"),
+    (0xF0FF, 0xF007, "FX07 LD Vx, DT"),
+    (0xF0FF, 0xF00A, "FX0A LD Vx, K"),
+    (0xF0FF, 0xF015, "FX15 LD DT"),
+    (0xF0FF, 0xF018, "FX18 LD ST"),
+    (0xF0FF, 0xF01E, "FX1E ADD I"),
+    (0xF0FF, 0xF029, "FX29 LD F"),
+    (0xF0FF, 0xF030, "FX30 LD HF"),
+    (0xF0FF, 0xF033, "FX33 LD B"),
+    (0xF0FF, 0xF03A, "FX3A LD PITCH"),
+    (0xF0FF, 0xF055, "FX55 LD [I], Vx"),
+    (0xF0FF, 0xF065, "FX65 LD Vx, [I]"),
+    (0xF0FF, 0xF075, "FX75 LD R"),
+    (0xF0FF, 0xF085, "FX85 LD Vx, R"),
+    (0xF0FF, 0xF0F8, "FXF8 OUT"),
+    (0xF0FF, 0xF0FB, "FXFB IN"),
+];
+
+// Index into INSTRUCTIONS, None for words that are not an instruction
+pub fn instruction_class(instr: u16) -> Option<usize> {
+    INSTRUCTIONS
+        .iter()
+        .position(|(mask, value, _)| instr & mask == *value)
+}
+
+fn format_instruction(instr: u16, reg: &dyn Fn(usize) -> String) -> String {
+    let nibbles = (
+        ((instr >> 12) & 0xF) as u8,
+        ((instr >> 8) & 0xF) as u8,
+        ((instr >> 4) & 0xF) as u8,
+        (instr & 0xF) as u8,
+    );
+    let x = nibbles.1 as usize;
+    let y = nibbles.2 as usize;
+    let n = nibbles.3;
+    let nnn = instr & 0xFFF;
+    let kk = (instr & 0xFF) as u8;
+
+    match nibbles {
+        (0x0, 0x0, 0x1, 0x0) => "MEGAOFF".to_string(),
+        (0x0, 0x0, 0x1, 0x1) => "MEGAON".to_string(),
+        (0x0, 0x0, 0xB, _) => format!("SCU {}", n),
+        (0x0, 0x1, ..) => format!("LDHI I, {:#04X}....", kk),
+        (0x0, 0x2, 0xA, 0x0) => "BGCOL".to_string(),
+        (0x0, 0x2, ..) => format!("LDPAL {}", kk),
+        (0x0, 0x3, ..) => format!("SPRW {}", kk),
+        (0x0, 0x4, ..) => format!("SPRH {}", kk),
+        (0x0, 0x5, ..) => format!("ALPHA {:#04X}", kk),
+        (0x0, 0x6, 0x0, _) => format!("DIGISND {}", n),
+        (0x0, 0x7, 0x0, 0x0) => "STOPSND".to_string(),
+        (0x0, 0x8, 0x0, _) => format!("BMODE {}", n),
+        (0x0, 0x9, ..) => format!("CCOL {:#04X}", kk),
+        (0x0, 0x0, 0xC, _) => format!("SCD {}", n),
+        (0x0, 0x0, 0xD, _) => format!("SCU {}", n),
+        (0x0, 0x0, 0xE, 0x0) => "CLS".to_string(),
+        (0x0, 0x0, 0xE, 0xE) => "RET".to_string(),
+        (0x0, 0x0, 0xF, 0xB) => "SCR".to_string(),
+        (0x0, 0x0, 0xF, 0xC) => "SCL".to_string(),
+        (0x0, 0x0, 0xF, 0xD) => "EXIT".to_string(),
+        (0x0, 0x0, 0xF, 0xE) => "LOW".to_string(),
+        (0x0, 0x0, 0xF, 0xF) => "HIGH".to_string(),
+        (0x0, ..) => format!("SYS {:#05X}", nnn),
+        (0x1, ..) => format!("JP {:#05X}", nnn),
+        (0x2, ..) => format!("CALL {:#05X}", nnn),
+        (0x3, ..) => format!("SE {}, {:#04X}", reg(x), kk),
+        (0x4, ..) => format!("SNE {}, {:#04X}", reg(x), kk),
+        (0x5, _, _, 0x0) => format!("SE {}, {}", reg(x), reg(y)),
+        (0x5, _, _, 0x1) => format!("ADDN {}, {}", reg(x), reg(y)),
+        (0x5, _, _, 0x2) => format!("LD [I], {}-{}", reg(x), reg(y)),
+        (0x5, _, _, 0x3) => format!("LD {}-{}, [I]", reg(x), reg(y)),
+        (0x6, ..) => format!("LD {}, {:#04X}", reg(x), kk),
+        (0x7, ..) => format!("ADD {}, {:#04X}", reg(x), kk),
+        (0x8, _, _, 0x0) => format!("LD {}, {}", reg(x), reg(y)),
+        (0x8, _, _, 0x1) => format!("OR {}, {}", reg(x), reg(y)),
+        (0x8, _, _, 0x2) => format!("AND {}, {}", reg(x), reg(y)),
+        (0x8, _, _, 0x3) => format!("XOR {}, {}", reg(x), reg(y)),
+        (0x8, _, _, 0x4) => format!("ADD {}, {}", reg(x), reg(y)),
+        (0x8, _, _, 0x5) => format!("SUB {}, {}", reg(x), reg(y)),
+        (0x8, _, _, 0x6) => format!("SHR {}, {}", reg(x), reg(y)),
+        (0x8, _, _, 0x7) => format!("SUBN {}, {}", reg(x), reg(y)),
+        (0x8, _, _, 0xE) => format!("SHL {}, {}", reg(x), reg(y)),
+        (0x9, _, _, 0x0) => format!("SNE {}, {}", reg(x), reg(y)),
+        (0xA, ..) => format!("LD I, {:#05X}", nnn),
+        (0xB, ..) => format!("JP {}, {:#05X}", reg(0), nnn),
+        (0xC, ..) => format!("RND {}, {:#04X}", reg(x), kk),
+        (0xD, ..) => format!("DRW {}, {}, {}", reg(x), reg(y), n),
+        (0xE, _, 0x9, 0xE) => format!("SKP {}", reg(x)),
+        (0xE, _, 0xA, 0x1) => format!("SKNP {}", reg(x)),
+        (0xE, _, 0xF, 0x2) => format!("SKP2 {}", reg(x)),
+        (0xE, _, 0xF, 0x5) => format!("SKNP2 {}", reg(x)),
+        (0xF, 0x0, 0x0, 0x0) => "LD I, LONG".to_string(),
+        (0xF, _, 0x0, 0x1) => format!("PLANE {}", x),
+        (0xF, 0x0, 0x0, 0x2) => "AUDIO".to_string(),
+        (0xF, _, 0x0, 0x7) => format!("LD {}, DT", reg(x)),
+        (0xF, _, 0x0, 0xA) => format!("LD {}, K", reg(x)),
+        (0xF, _, 0x1, 0x5) => format!("LD DT, {}", reg(x)),
+        (0xF, _, 0x1, 0x8) => format!("LD ST, {}", reg(x)),
+        (0xF, _, 0x1, 0xE) => format!("ADD I, {}", reg(x)),
+        (0xF, _, 0x2, 0x9) => format!("LD F, {}", reg(x)),
+        (0xF, _, 0x3, 0x0) => format!("LD HF, {}", reg(x)),
+        (0xF, _, 0x3, 0x3) => format!("LD B, {}", reg(x)),
+        (0xF, _, 0x3, 0xA) => format!("LD PITCH, {}", reg(x)),
+        (0xF, _, 0x5, 0x5) => format!("LD [I], {}", reg(x)),
+        (0xF, _, 0x6, 0x5) => format!("LD {}, [I]", reg(x)),
+        (0xF, _, 0x7, 0x5) => format!("LD R, {}", reg(x)),
+        (0xF, _, 0x8, 0x5) => format!("LD {}, R", reg(x)),
+        (0xF, _, 0xF, 0x8) => format!("OUT {}", reg(x)),
+        (0xF, _, 0xF, 0xB) => format!("IN {}", reg(x)),
+        _ => format!("DW {:#06X}", instr),
+    }
+}
@@ -1,34 +1,267 @@
-use std::sync::mpsc::{self, RecvError, SendError, TryRecvError, TrySendError};
+use std::any::Any;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{
+    self, Receiver, RecvError, SendError, Sender, SyncSender, TryRecvError, TrySendError,
+};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use super::cpu::{Cpu, VKey, HEIGHT, WIDTH};
+use super::cpu::{
+    AccessLog, ClockMode, Cpu, MemoryPolicy, OddPcPolicy, UnknownOpcodePolicy, VKey, Watchpoint,
+    HEIGHT, MEMSIZE, WIDTH,
+};
+use super::crashdump::History;
+use super::debugger::{self, Command, Debugger, OpcodeClass};
+use super::disasm::{disassemble, disassemble_with_registers};
+use super::flags;
+use super::fonts::FontStyle;
+use super::frontend::{Action, Backend, Frame, Frontend, SoundIndicator};
+use super::gamepad::GamepadMap;
+use super::gif::GifRecorder;
+#[cfg(feature = "wgpu-backend")]
+use super::gpu::GpuFrontend;
+use super::heatmap::Heatmap;
+use super::hotkeys::Hotkeys;
+use super::hotspots::Hotspots;
+use super::keymap::Keymap;
+use super::movie::{keys_from_mask, Movie, MovieInput};
+use super::opstats::OpcodeStats;
+use super::palette::BUZZER_COLOR;
 use super::perf::PerfLimiter;
+use super::quirks::Quirks;
+use super::remote::RemoteKeys;
+use super::render::{adjust_color, ColorAdjust, FrameBlender, PostFilter, Scaling, TextureFilter};
+use super::rewind::Rewind;
+use super::savestate::{StateSlots, AUTOSAVE_SLOT};
+use super::screenshot;
+#[cfg(feature = "sdl2")]
+use super::sdl::SdlFrontend;
+use super::sound::NullBackend;
+use super::term::{TermScreen, TermStyle};
+use super::tui::{self, Snapshot, Watch};
+use super::video::VideoWriter;
+use super::wav::Wav;
+use super::window::WindowFrontend;
 
-use anyhow::Context;
-use minifb::{Key, Scale, Window, WindowOptions};
+use anyhow::{anyhow, bail, Context};
 
-#[derive(Copy, Clone)]
+// Decides when the display is sent to the output loop
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FlickerReduction {
+    // present every display change
+    None,
+    // present the current display whenever the output loop is ready
+    Sample,
+    // present the display once per 60 Hz timer tick
+    Tick,
+    // like Sample, erased pixels fade out in the window (see --phosphor-decay)
+    Decay,
+}
+
+impl FromStr for FlickerReduction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" | "off" => Ok(FlickerReduction::None),
+            "sample" => Ok(FlickerReduction::Sample),
+            "tick" => Ok(FlickerReduction::Tick),
+            "decay" => Ok(FlickerReduction::Decay),
+            _ => bail!(
+                "Unknown flicker reduction {}, expected none, sample, tick or decay",
+                s
+            ),
+        }
+    }
+}
+
+// Why the emulation stopped
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ExitReason {
+    // the window was closed or escape was pressed
+    Closed,
+    // the program executed 00FD
+    ProgramExit,
+    // the program jumped to itself, see Emulator::with_exit_on_loop
+    InfiniteLoop,
+    // run_headless ran all its frames
+    FrameLimit,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Poke {
+    pub addr: u16,
+    pub value: u8,
+    // None pokes the value before execution starts
+    pub frame: Option<u64>,
+}
+
+#[derive(Clone)]
 pub struct Emulator {
-    pub skip_frames: bool,
+    pub flicker_reduction: FlickerReduction,
     pub fps_limit: Option<f64>,
     pub ips_limit: Option<f64>,
+    // factor of the limits and the timers while the turbo key is held
+    pub turbo: f64,
+    // factor of the limits and the timers in slow motion
+    pub slow_motion: f64,
     pub debug: u64,
     pub colors: Option<[u32; 4]>,
+    pub buzzer_color: Option<u32>,
+    pub quiet_color: u32,
+    pub sound_indicator: Option<SoundIndicator>,
+    pub entry: Option<u16>,
+    pub segments: Vec<(u16, Vec<u8>)>,
+    pub pokes: Vec<Poke>,
+    pub debug_tui: bool,
+    pub debugger: bool,
+    pub start_paused: bool,
+    pub breakpoints: Vec<u16>,
+    pub opcode_breaks: Vec<OpcodeClass>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub trace_file: Option<PathBuf>,
+    pub access_log: Option<PathBuf>,
+    pub access_log_ranges: Vec<Watch>,
+    pub dump_memory: Option<Watch>,
+    pub rewind_seconds: f64,
+    pub crash_dump: Option<PathBuf>,
+    // directory of the save state slots, None disables them
+    pub state_dir: Option<PathBuf>,
+    // save the state on exit and load it on the next start
+    pub autosave: bool,
+    pub resume: bool,
+    pub hotspots: Option<usize>,
+    pub opcode_stats: bool,
+    pub record_movie: Option<PathBuf>,
+    pub replay_movie: Option<Movie>,
+    pub watches: Vec<Watch>,
+    pub odd_pc_policy: OddPcPolicy,
+    pub unknown_opcode_policy: UnknownOpcodePolicy,
+    pub memory_policy: MemoryPolicy,
+    pub seed: Option<u64>,
+    pub flags_file: Option<PathBuf>,
+    pub font_style: FontStyle,
+    pub exit_on_loop: Option<u64>,
+    pub font: Option<Vec<u8>>,
+    pub hires_font: Option<Vec<u8>>,
+    pub clock_mode: ClockMode,
+    pub timer_hz: f64,
+    pub texture_filter: TextureFilter,
+    pub post_filter: PostFilter,
+    pub scaling: Scaling,
+    pub fullscreen: bool,
+    pub term: Option<TermStyle>,
+    pub backend: Backend,
+    pub show_keypad: bool,
+    pub keymap: Keymap,
+    pub gamepad_map: GamepadMap,
+    pub hotkeys: Hotkeys,
+    pub remote_input: Option<String>,
+    pub perf_overlay: bool,
+    pub audio: bool,
+    pub buzzer_wav: Option<Wav>,
+    pub lowpass: Option<f64>,
+    pub screen_size: (usize, usize),
+    pub window_scale: usize,
+    pub record_gif: Option<PathBuf>,
+    pub record_video: Option<PathBuf>,
+    pub phosphor_decay: Option<f64>,
+    pub blend_frames: Option<usize>,
+    pub color_adjust: ColorAdjust,
+    pub quirks: Quirks,
+    pub chip8x: bool,
+    pub two_page_hires: bool,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Emulator {
     pub fn new() -> Self {
         Self {
-            skip_frames: false,
+            flicker_reduction: FlickerReduction::None,
             fps_limit: None,
             ips_limit: None,
+            turbo: 8.0,
+            slow_motion: 0.25,
             debug: 0,
             colors: None,
+            buzzer_color: None,
+            quiet_color: 0,
+            sound_indicator: None,
+            entry: None,
+            segments: Vec::new(),
+            pokes: Vec::new(),
+            debug_tui: false,
+            debugger: false,
+            start_paused: false,
+            breakpoints: Vec::new(),
+            opcode_breaks: Vec::new(),
+            watchpoints: Vec::new(),
+            trace_file: None,
+            access_log: None,
+            access_log_ranges: Vec::new(),
+            dump_memory: None,
+            rewind_seconds: 5.0,
+            crash_dump: None,
+            state_dir: None,
+            autosave: false,
+            resume: false,
+            hotspots: None,
+            opcode_stats: false,
+            record_movie: None,
+            replay_movie: None,
+            watches: Vec::new(),
+            odd_pc_policy: OddPcPolicy::Allow,
+            unknown_opcode_policy: UnknownOpcodePolicy::Error,
+            memory_policy: MemoryPolicy::Error,
+            seed: None,
+            flags_file: None,
+            font_style: FontStyle::Default,
+            exit_on_loop: None,
+            font: None,
+            hires_font: None,
+            clock_mode: ClockMode::WallClock,
+            timer_hz: 60.0,
+            texture_filter: TextureFilter::Nearest,
+            post_filter: PostFilter::None,
+            scaling: Scaling::Aspect,
+            fullscreen: false,
+            term: None,
+            backend: Backend::Minifb,
+            show_keypad: false,
+            keymap: Keymap::Qwerty,
+            gamepad_map: GamepadMap::default(),
+            hotkeys: Hotkeys::default(),
+            remote_input: None,
+            perf_overlay: false,
+            audio: true,
+            buzzer_wav: None,
+            lowpass: None,
+            screen_size: (1920, 1080),
+            window_scale: 16,
+            record_gif: None,
+            record_video: None,
+            phosphor_decay: None,
+            blend_frames: None,
+            color_adjust: ColorAdjust::default(),
+            quirks: Quirks::default(),
+            chip8x: false,
+            two_page_hires: false,
         }
     }
 
-    pub fn with_skip_frames(mut self, skip: bool) -> Self {
-        self.skip_frames = skip;
+    pub fn with_flicker_reduction(mut self, flicker_reduction: FlickerReduction) -> Self {
+        self.flicker_reduction = flicker_reduction;
         self
     }
 
@@ -42,175 +275,1575 @@ impl Emulator {
         self
     }
 
+    pub fn with_turbo(mut self, factor: f64) -> Self {
+        self.turbo = factor;
+        self
+    }
+
+    pub fn with_slow_motion(mut self, factor: f64) -> Self {
+        self.slow_motion = factor;
+        self
+    }
+
     pub fn with_colors(mut self, colors: Option<[u32; 4]>) -> Self {
         self.colors = colors;
         self
     }
 
+    // colors of the bars around the display while the sound timer runs and otherwise,
+    // the bars are black without a quiet color
+    pub fn with_buzzer_colors(mut self, buzzer: Option<u32>, quiet: Option<u32>) -> Self {
+        self.buzzer_color = buzzer;
+        self.quiet_color = quiet.unwrap_or(0);
+        self
+    }
+
+    // shows in the picture that the sound timer runs
+    pub fn with_sound_indicator(mut self, indicator: Option<SoundIndicator>) -> Self {
+        self.sound_indicator = indicator;
+        self
+    }
+
+    pub fn with_entry(mut self, entry: Option<u16>) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    pub fn with_segments(mut self, segments: Vec<(u16, Vec<u8>)>) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    pub fn with_pokes(mut self, pokes: Vec<Poke>) -> Self {
+        self.pokes = pokes;
+        self
+    }
+
+    pub fn with_debug_tui(mut self, debug_tui: bool) -> Self {
+        self.debug_tui = debug_tui;
+        self
+    }
+
+    // read pause, step, continue and run commands from stdin
+    pub fn with_debugger(mut self, debugger: bool) -> Self {
+        self.debugger = debugger;
+        self
+    }
+
+    // hold the cpu before the first instruction, e.g. to set breakpoints
+    pub fn with_start_paused(mut self, paused: bool) -> Self {
+        self.start_paused = paused;
+        self
+    }
+
+    // pause before executing instructions of these opcode classes
+    pub fn with_opcode_breaks(mut self, classes: Vec<OpcodeClass>) -> Self {
+        self.opcode_breaks = classes;
+        self
+    }
+
+    // pause before executing the instructions at these addresses
+    pub fn with_breakpoints(mut self, breakpoints: Vec<u16>) -> Self {
+        self.breakpoints = breakpoints;
+        self
+    }
+
+    // write every memory read and write to this file, only of the ranges if there are any
+    pub fn with_access_log(mut self, path: Option<PathBuf>, ranges: Vec<Watch>) -> Self {
+        self.access_log = path;
+        self.access_log_ranges = ranges;
+        self
+    }
+
+    // write every executed instruction with its register values to this file
+    pub fn with_trace_file(mut self, trace_file: Option<PathBuf>) -> Self {
+        self.trace_file = trace_file;
+        self
+    }
+
+    // print the given number of most executed addresses when the emulation ends
+    pub fn with_hotspots(mut self, top: Option<usize>) -> Self {
+        self.hotspots = top;
+        self
+    }
+
+    // print a histogram of the executed instructions and their coverage at exit
+    pub fn with_opcode_stats(mut self, opcode_stats: bool) -> Self {
+        self.opcode_stats = opcode_stats;
+        self
+    }
+
+    // write the last instructions and the cpu state to this file when the cpu fails
+    pub fn with_crash_dump(mut self, path: Option<PathBuf>) -> Self {
+        self.crash_dump = path;
+        self
+    }
+
+    pub fn with_state_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.state_dir = dir;
+        self
+    }
+
+    pub fn with_autosave(mut self, autosave: bool) -> Self {
+        self.autosave = autosave;
+        self
+    }
+
+    pub fn with_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    // how far Backspace steps back in time
+    pub fn with_rewind_seconds(mut self, seconds: f64) -> Self {
+        self.rewind_seconds = seconds;
+        self
+    }
+
+    // print this memory range whenever the debugger pauses
+    pub fn with_dump_memory(mut self, range: Option<Watch>) -> Self {
+        self.dump_memory = range;
+        self
+    }
+
+    // pause after an instruction accessed one of the watched memory ranges
+    pub fn with_watchpoints(mut self, watchpoints: Vec<Watchpoint>) -> Self {
+        self.watchpoints = watchpoints;
+        self
+    }
+
+    pub fn with_watches(mut self, watches: Vec<Watch>) -> Self {
+        self.watches = watches;
+        self
+    }
+
+    pub fn with_odd_pc_policy(mut self, policy: OddPcPolicy) -> Self {
+        self.odd_pc_policy = policy;
+        self
+    }
+
+    pub fn with_clock_mode(mut self, mode: ClockMode) -> Self {
+        self.clock_mode = mode;
+        self
+    }
+
+    pub fn with_timer_hz(mut self, timer_hz: f64) -> Self {
+        self.timer_hz = timer_hz;
+        self
+    }
+
+    // stop once the program jumped to itself the given number of times in a row
+    pub fn with_exit_on_loop(mut self, threshold: Option<u64>) -> Self {
+        self.exit_on_loop = threshold;
+        self
+    }
+
+    // load the rom at 0x300 and enable the CHIP-8X color opcodes
+    pub fn with_chip8x(mut self, chip8x: bool) -> Self {
+        self.chip8x = chip8x;
+        self
+    }
+
+    // run the rom on the 64x64 screen of the two-page hires interpreter
+    pub fn with_two_page_hires(mut self, two_page_hires: bool) -> Self {
+        self.two_page_hires = two_page_hires;
+        self
+    }
+
+    pub fn with_font_style(mut self, style: FontStyle) -> Self {
+        self.font_style = style;
+        self
+    }
+
+    pub fn with_font(mut self, font: Option<Vec<u8>>) -> Self {
+        self.font = font;
+        self
+    }
+
+    pub fn with_hires_font(mut self, font: Option<Vec<u8>>) -> Self {
+        self.hires_font = font;
+        self
+    }
+
+    pub fn with_flags_file(mut self, path: Option<PathBuf>) -> Self {
+        self.flags_file = path;
+        self
+    }
+
+    pub fn with_record_movie(mut self, path: Option<PathBuf>) -> Self {
+        self.record_movie = path;
+        self
+    }
+
+    pub fn with_replay_movie(mut self, movie: Option<Movie>) -> Self {
+        self.replay_movie = movie;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_memory_policy(mut self, policy: MemoryPolicy) -> Self {
+        self.memory_policy = policy;
+        self
+    }
+
+    pub fn with_unknown_opcode_policy(mut self, policy: UnknownOpcodePolicy) -> Self {
+        self.unknown_opcode_policy = policy;
+        self
+    }
+
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn with_texture_filter(mut self, filter: TextureFilter) -> Self {
+        self.texture_filter = filter;
+        self
+    }
+
+    pub fn with_fullscreen(mut self, fullscreen: bool, screen_size: (usize, usize)) -> Self {
+        self.fullscreen = fullscreen;
+        self.screen_size = screen_size;
+        self
+    }
+
+    // start with the keypad overlay shown, F1 shows and hides it
+    pub fn with_keypad(mut self, show: bool) -> Self {
+        self.show_keypad = show;
+        self
+    }
+
+    // keys of the keyboard for the CHIP-8 keys
+    pub fn with_keymap(mut self, keymap: Keymap) -> Self {
+        self.keymap = keymap;
+        self
+    }
+
+    // CHIP-8 keys of the buttons of game controllers
+    pub fn with_gamepad_map(mut self, map: GamepadMap) -> Self {
+        self.gamepad_map = map;
+        self
+    }
+
+    pub fn with_hotkeys(mut self, hotkeys: Hotkeys) -> Self {
+        self.hotkeys = hotkeys;
+        self
+    }
+
+    // address of a TCP listener for key presses of other programs
+    pub fn with_remote_input(mut self, addr: Option<String>) -> Self {
+        self.remote_input = addr;
+        self
+    }
+
+    // show the instructions and frames per second in the window instead of printing them
+    pub fn with_perf_overlay(mut self, enabled: bool) -> Self {
+        self.perf_overlay = enabled;
+        self
+    }
+
+    // play the sound timer, without audio no audio device is opened
+    pub fn with_audio(mut self, enabled: bool) -> Self {
+        self.audio = enabled;
+        self
+    }
+
+    // the sound timer plays the sample instead of the tone
+    pub fn with_buzzer_wav(mut self, wav: Option<Wav>) -> Self {
+        self.buzzer_wav = wav;
+        self
+    }
+
+    // cutoff frequency of the low-pass filter of the sound
+    pub fn with_lowpass(mut self, cutoff: Option<f64>) -> Self {
+        self.lowpass = cutoff;
+        self
+    }
+
+    // show the display in the terminal instead of a window
+    pub fn with_term(mut self, term: Option<TermStyle>) -> Self {
+        self.term = term;
+        self
+    }
+
+    // The window library, ignored with a terminal
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_record_gif(mut self, path: Option<PathBuf>) -> Self {
+        self.record_gif = path;
+        self
+    }
+
+    pub fn with_record_video(mut self, path: Option<PathBuf>) -> Self {
+        self.record_video = path;
+        self
+    }
+
+    // window size in pixels per pixel of the 64x32 display
+    pub fn with_window_scale(mut self, scale: usize) -> Self {
+        self.window_scale = scale;
+        self
+    }
+
+    pub fn with_scaling(mut self, scaling: Scaling) -> Self {
+        self.scaling = scaling;
+        self
+    }
+
+    pub fn with_post_filter(mut self, filter: PostFilter) -> Self {
+        self.post_filter = filter;
+        self
+    }
+
+    pub fn with_phosphor_decay(mut self, decay: Option<f64>) -> Self {
+        self.phosphor_decay = decay;
+        self
+    }
+
+    pub fn with_blend_frames(mut self, count: Option<usize>) -> Self {
+        self.blend_frames = count;
+        self
+    }
+
+    pub fn with_color_adjust(mut self, adjust: ColorAdjust) -> Self {
+        self.color_adjust = adjust;
+        self
+    }
+
     pub fn with_debug(mut self, debug: u64) -> Self {
         self.debug = debug;
         self
     }
 
-    pub fn run(&self, code: Vec<u8>) -> Result<(), anyhow::Error> {
-        let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT * 4];
-        let mut window_options = WindowOptions::default();
-        window_options.scale = Scale::X16;
-        window_options.resize = true;
-        let mut window = Window::new("CHIP8 - ESC to exit", WIDTH, HEIGHT, window_options)
-            .context("Could not create minifb window")?;
+    fn setup_cpu(&self, code: &[u8]) -> Result<Cpu, anyhow::Error> {
+        let mut cpu = Cpu::new(code, 1.0);
+        cpu.odd_pc_policy = self.odd_pc_policy;
+        cpu.unknown_opcode_policy = self.unknown_opcode_policy;
+        cpu.memory_policy = self.memory_policy;
+        cpu.quirks = self.quirks;
+        cpu.watchpoints = self.watchpoints.clone();
+        if self.chip8x {
+            cpu.enable_chip8x(code)?;
+        }
+        if self.two_page_hires {
+            cpu.enable_two_page_hires();
+        }
+        cpu.clock.mode = self.clock_mode;
+        cpu.clock.freq_hz = self.timer_hz;
+        if let Some(seed) = self.seed {
+            cpu.seed(seed);
+        }
+        if let Some(colors) = self.colors {
+            cpu.display.colors = colors;
+        }
+        cpu.set_font_style(self.font_style);
+        if let Some(font) = self.font.as_ref() {
+            cpu.load_font(font)?;
+        }
+        if let Some(font) = self.hires_font.as_ref() {
+            cpu.load_hires_font(font)?;
+        }
+        if let Some(wav) = self.buzzer_wav.as_ref() {
+            cpu.sound.set_buzzer(wav.clone());
+        }
+        cpu.sound.set_lowpass(self.lowpass);
+        if !self.audio {
+            cpu.sound.set_backend(Box::new(NullBackend));
+        }
+        for (addr, data) in self.segments.iter() {
+            cpu.load(*addr, data)
+                .with_context(|| format!("Could not load segment at {:#X}", addr))?;
+        }
+        if let Some(entry) = self.entry {
+            cpu.pc = entry;
+        }
+        for poke in self.pokes.iter().filter(|poke| poke.frame.is_none()) {
+            cpu.memory[poke.addr as usize] = poke.value;
+        }
+        Ok(cpu)
+    }
 
-        window.limit_update_rate(None);
+    // pokes that are applied at a given frame, sorted by descending frame
+    // so that due pokes can be popped from the end
+    fn pending_pokes(&self) -> Vec<Poke> {
+        let mut pending: Vec<Poke> = self
+            .pokes
+            .iter()
+            .filter(|poke| poke.frame.is_some())
+            .copied()
+            .collect();
+        pending.sort_by_key(|poke| std::cmp::Reverse(poke.frame));
+        pending
+    }
+
+    // Plays back a movie without window and audio, comparing the state checksums
+    // stored in the movie with the emulated state
+    pub fn verify_replay(&self, code: Vec<u8>, movie: &Movie) -> Result<(), anyhow::Error> {
+        let mut cpu = self.setup_cpu(&code)?;
+        let mut pending_pokes = self.pending_pokes();
+        cpu.clock.mode = ClockMode::Instructions(movie.ipf);
+        if let Some(seed) = movie.seed {
+            cpu.seed(seed);
+        }
+
+        let last_frame = match movie.checks.last() {
+            Some((frame, _)) => *frame,
+            None => bail!("Movie does not contain any state checksums"),
+        };
+        let mut video = self.create_video(&cpu)?;
+        let mut next_keys = 0;
+        let mut next_check = 0;
+        loop {
+            let frame = cpu.frame();
+            while next_keys < movie.keys.len() && movie.keys[next_keys].0 <= frame {
+                cpu.keyboard
+                    .set_keys(keys_from_mask(movie.keys[next_keys].1));
+                next_keys += 1;
+            }
+            apply_pokes(&mut cpu, &mut pending_pokes);
+            cpu.run_frame()?;
+            if let Some(video) = video.as_mut() {
+                video.add_frame(&cpu.display.to_buf(), cpu.display.width, cpu.display.height)?;
+            }
+            if cpu.exited {
+                bail!(
+                    "Program exited at frame {} before the end of the movie",
+                    frame
+                );
+            }
+            if cpu.halted {
+                bail!(
+                    "Program stopped at frame {} before the end of the movie",
+                    frame
+                );
+            }
+            while next_check < movie.checks.len() && movie.checks[next_check].0 <= frame {
+                let (check_frame, expected) = movie.checks[next_check];
+                let checksum = cpu.state_checksum();
+                if checksum != expected {
+                    bail!(
+                        "State diverged at frame {}: expected checksum {:016x}, got {:016x}",
+                        check_frame,
+                        expected,
+                        checksum
+                    );
+                }
+                next_check += 1;
+            }
+            if frame >= last_frame {
+                break;
+            }
+        }
+        if let Some(video) = video {
+            video.finish()?;
+        }
+        println!(
+            "Replay verified: {} checksums matched over {} frames",
+            movie.checks.len(),
+            last_frame + 1
+        );
+        Ok(())
+    }
+
+    fn create_video(&self, cpu: &Cpu) -> Result<Option<VideoWriter>, anyhow::Error> {
+        self.record_video
+            .as_ref()
+            .map(|path| {
+                VideoWriter::create(
+                    path,
+                    cpu.display.width,
+                    cpu.display.height,
+                    cpu.clock.freq_hz,
+                )
+            })
+            .transpose()
+    }
+
+    // Runs the rom twice without window and audio, the second time with other quirks,
+    // and reports the first instruction after which the two machine states differ
+    pub fn diff_quirks(
+        &self,
+        code: Vec<u8>,
+        quirks: Quirks,
+        frames: u64,
+        movie: Option<&Movie>,
+    ) -> Result<(), anyhow::Error> {
+        match self.run_lockstep(&code, quirks, frames, movie)? {
+            Lockstep::Diverged(report) => println!("States diverged in {}", report),
+            Lockstep::Stopped(frame) => {
+                println!("Program stopped in frame {} without divergence", frame)
+            }
+            Lockstep::Completed => println!("No divergence in {} frames", frames),
+        }
+        Ok(())
+    }
+
+    // Runs the rom twice with the same settings, seed and keys, any difference
+    // between the runs means that the emulation depends on something outside of them
+    pub fn verify_determinism(
+        &self,
+        code: Vec<u8>,
+        frames: u64,
+        movie: Option<&Movie>,
+    ) -> Result<(), anyhow::Error> {
+        match self.run_lockstep(&code, self.quirks, frames, movie)? {
+            Lockstep::Diverged(report) => bail!("Runs diverged in {}", report),
+            Lockstep::Stopped(frame) => println!(
+                "Runs were identical until the program stopped in frame {}",
+                frame
+            ),
+            Lockstep::Completed => println!("Runs were identical for {} frames", frames),
+        }
+        Ok(())
+    }
+
+    // Instructions per frame for runs without window, which do not wait for the wall clock
+    fn headless_ipf(&self) -> u64 {
+        match self.clock_mode {
+            ClockMode::Instructions(ipf) => ipf,
+            ClockMode::WallClock => {
+                (self.ips_limit.unwrap_or(900.0) / self.timer_hz).round() as u64
+            }
+        }
+    }
+
+    // Runs the rom without window and audio for the given number of frames, or
+    // instructions if given, and writes the display at the end into an image
+    pub fn export_frame(
+        &self,
+        code: Vec<u8>,
+        path: &Path,
+        frames: u64,
+        instructions: Option<u64>,
+    ) -> Result<(), anyhow::Error> {
+        let mut cpu = self.setup_cpu(&code)?;
+        let mut pending_pokes = self.pending_pokes();
+        cpu.clock.mode = ClockMode::Instructions(self.headless_ipf().max(1));
+        cpu.seed(self.seed.unwrap_or(0));
+        loop {
+            let done = match instructions {
+                Some(instructions) => cpu.clock_steps >= instructions,
+                None => cpu.frame() >= frames,
+            };
+            if done || cpu.exited || cpu.halted {
+                break;
+            }
+            apply_pokes(&mut cpu, &mut pending_pokes);
+            cpu.step()?;
+        }
+        screenshot::save(
+            path,
+            &cpu.display.to_buf(),
+            cpu.display.width,
+            cpu.display.height,
+        )?;
+        println!(
+            "Display after {} instructions in frame {} written to {}",
+            cpu.clock_steps,
+            cpu.frame(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    // Runs the rom without window and audio as fast as possible, for tests,
+    // fuzzing and benchmarks. The keys come from the replay movie, on_frame
+    // sees the machine after every frame and can also press keys. Ends after
+    // the given number of frames, when the program exits or with exit_on_loop.
+    pub fn run_headless(
+        &self,
+        code: &[u8],
+        frames: Option<u64>,
+        mut on_frame: impl FnMut(&mut Cpu) -> Result<(), anyhow::Error>,
+    ) -> Result<ExitReason, anyhow::Error> {
+        let mut cpu = self.setup_cpu(code)?;
+        let mut pending_pokes = self.pending_pokes();
+        let movie = self.replay_movie.as_ref();
+        match movie {
+            Some(movie) => {
+                cpu.clock.mode = ClockMode::Instructions(movie.ipf);
+                cpu.seed(movie.seed.unwrap_or(0));
+            }
+            None => {
+                cpu.clock.mode = ClockMode::Instructions(self.headless_ipf().max(1));
+                cpu.seed(self.seed.unwrap_or(0));
+            }
+        }
+        let keys = movie.map(|movie| movie.keys.as_slice()).unwrap_or(&[]);
+        let mut next_keys = 0;
+        let mut video = self.create_video(&cpu)?;
+        let start = Instant::now();
+
+        let exit_reason = loop {
+            let frame = cpu.frame();
+            if matches!(frames, Some(frames) if frame >= frames) {
+                break ExitReason::FrameLimit;
+            }
+            while next_keys < keys.len() && keys[next_keys].0 <= frame {
+                cpu.keyboard.set_keys(keys_from_mask(keys[next_keys].1));
+                next_keys += 1;
+            }
+            apply_pokes(&mut cpu, &mut pending_pokes);
+            cpu.run_frame()?;
+            if let Some(video) = video.as_mut() {
+                video.add_frame(&cpu.display.to_buf(), cpu.display.width, cpu.display.height)?;
+            }
+            on_frame(&mut cpu)?;
+            if cpu.halted {
+                bail!("Program stopped at frame {}", frame);
+            }
+            if cpu.exited {
+                break ExitReason::ProgramExit;
+            }
+            if matches!(self.exit_on_loop, Some(threshold) if cpu.jump_to_self_count >= threshold) {
+                println!("Program loops at {:#X}", cpu.pc);
+                break ExitReason::InfiniteLoop;
+            }
+        };
+        if let Some(video) = video {
+            video.finish()?;
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "Ran {} frames with {} instructions in {:.2} s ({:.0} instructions per second)",
+            cpu.frame(),
+            cpu.clock_steps,
+            secs,
+            cpu.clock_steps as f64 / secs.max(1e-9)
+        );
+        Ok(exit_reason)
+    }
+
+    // Executes two cpus instruction by instruction, the second one with the given quirks,
+    // and compares their states after every instruction
+    fn run_lockstep(
+        &self,
+        code: &[u8],
+        quirks: Quirks,
+        frames: u64,
+        movie: Option<&Movie>,
+    ) -> Result<Lockstep, anyhow::Error> {
+        let ipf = match movie {
+            Some(movie) => movie.ipf,
+            None => self.headless_ipf(),
+        };
+        let seed = movie
+            .and_then(|movie| movie.seed)
+            .or(self.seed)
+            .unwrap_or(0);
+        let mut cpus = [self.setup_cpu(code)?, self.setup_cpu(code)?];
+        cpus[1].quirks = quirks;
+        let mut pending_pokes = [self.pending_pokes(), self.pending_pokes()];
+        for cpu in cpus.iter_mut() {
+            cpu.clock.mode = ClockMode::Instructions(ipf.max(1));
+            cpu.seed(seed);
+        }
+        let keys = movie.map(|movie| movie.keys.as_slice()).unwrap_or(&[]);
+        let mut next_keys = 0;
+
+        while cpus[0].frame() < frames {
+            let frame = cpus[0].frame();
+            while next_keys < keys.len() && keys[next_keys].0 <= frame {
+                for cpu in cpus.iter_mut() {
+                    cpu.keyboard.set_keys(keys_from_mask(keys[next_keys].1));
+                }
+                next_keys += 1;
+            }
+            let pc = cpus[0].pc;
+            let instr = cpus[0].next_instruction();
+            for (cpu, pending) in cpus.iter_mut().zip(pending_pokes.iter_mut()) {
+                apply_pokes(cpu, pending);
+                cpu.step()?;
+            }
+            if let Some(difference) = state_difference(&cpus[0], &cpus[1]) {
+                return Ok(Lockstep::Diverged(format!(
+                    "frame {} after {:04X}  {:04X}  {}: {}",
+                    frame,
+                    pc,
+                    instr,
+                    disassemble(instr),
+                    difference
+                )));
+            }
+            if cpus.iter().any(|cpu| cpu.exited || cpu.halted) {
+                return Ok(Lockstep::Stopped(frame));
+            }
+        }
+        Ok(Lockstep::Completed)
+    }
+
+    pub fn run(&self, code: Vec<u8>) -> Result<ExitReason, anyhow::Error> {
+        let buzzing = Arc::new(AtomicBool::new(false));
+        let cpu_buzzing = if self.buzzer_color.is_some() || self.sound_indicator.is_some() {
+            Some(buzzing.clone())
+        } else {
+            None
+        };
+        // instructions per second as f64 bits, measured once a second
+        let ips = Arc::new(AtomicU64::new(0));
+        let cpu_ips = ips.clone();
+        // the debug output would garble the display of the terminal frontend
+        let debug = if self.term.is_some() { 0 } else { self.debug };
+        let print_ips = debug >= 1 && !self.perf_overlay;
 
         let (tx_keys, rx_keys) = mpsc::sync_channel::<[VKey; 16]>(1);
         let (tx_disp, rx_disp) = mpsc::sync_channel::<(Vec<u32>, usize, usize)>(1);
         let (tx_disp_notify, rx_disp_notify) = mpsc::sync_channel::<()>(1);
+        let (tx_debug, rx_debug) = mpsc::channel::<Command>();
+        // messages about the hotkeys, the debugger and the end of the program, the
+        // frontend shows them
+        let (tx_status, rx_status) = mpsc::channel::<String>();
+        if self.debugger {
+            debugger::spawn_console(tx_debug.clone());
+        }
+        if self.start_paused {
+            let _ = tx_debug.send(Command::Pause);
+        }
+        let paused = Arc::new(AtomicBool::new(false));
+        let cpu_paused = paused.clone();
 
-        let mut perf_io = PerfLimiter::new(self.fps_limit);
-        let mut perf_cpu = PerfLimiter::new(self.ips_limit);
+        let ips_limit = self.ips_limit;
+        let mut perf_cpu = PerfLimiter::new(ips_limit);
         let mut ticker_tps = PerfLimiter::new(Some(1.0));
-        let mut ticker_fps = PerfLimiter::new(Some(1.0));
-        let debug = self.debug;
-        let skip_frames = self.skip_frames;
+        let mut ticker_tui = PerfLimiter::new(Some(30.0));
+        let flicker_reduction = self.flicker_reduction;
+        let exit_on_loop = self.exit_on_loop;
+        let breakpoints = self.breakpoints.clone();
+        let opcode_breaks = self.opcode_breaks.clone();
+        let dump_memory = self.dump_memory;
+        let rewind_seconds = self.rewind_seconds;
+        let crash_dump = self.crash_dump.clone();
+        let state_slots = self
+            .state_dir
+            .clone()
+            .map(|dir| StateSlots::new(dir, &code));
+        let hotspots_report = self.hotspots;
+        let print_opcode_stats = self.opcode_stats;
+        let mut last_frame = 0;
+        let mut tick_pending = true;
 
-        let mut cpu = Cpu::new(&code[..], 1.0);
-        if let Some(colors) = self.colors {
-            cpu.display.colors = colors;
+        let mut cpu = self.setup_cpu(&code)?;
+        // a reset sets up a new cpu in the cpu thread
+        let emulator = self.clone();
+        let mut pending_pokes = self.pending_pokes();
+        // movies need instruction counted timers and a known seed to be replayable
+        let mut movie_input = match (self.replay_movie.as_ref(), self.record_movie.as_ref()) {
+            (Some(movie), _) => {
+                cpu.clock.mode = ClockMode::Instructions(movie.ipf);
+                if let Some(seed) = movie.seed {
+                    cpu.seed(seed);
+                }
+                Some(MovieInput::replay(movie.clone()))
+            }
+            (None, Some(path)) => {
+                let ipf = match self.clock_mode {
+                    ClockMode::Instructions(ipf) => ipf,
+                    ClockMode::WallClock => {
+                        (self.ips_limit.unwrap_or(900.0) / self.timer_hz).round() as u64
+                    }
+                }
+                .max(1);
+                let seed = self.seed.unwrap_or_else(rand::random);
+                cpu.clock.mode = ClockMode::Instructions(ipf);
+                cpu.seed(seed);
+                Some(MovieInput::record(path.clone(), ipf, seed))
+            }
+            (None, None) => None,
+        };
+        let mut window_keys = [VKey::Up; 16];
+        let flags_file = self.flags_file.clone();
+        if let Some(path) = flags_file.as_ref() {
+            if let Some(flags) = flags::load(path)? {
+                // files saved with fewer or more flags only fill the common part
+                let len = flags.len().min(cpu.repl.len());
+                cpu.repl[..len].copy_from_slice(&flags[..len]);
+            }
+        }
+        if self.resume {
+            match state_slots.as_ref() {
+                Some(slots) if slots.path(AUTOSAVE_SLOT).exists() => {
+                    cpu.load_state(&slots.load(AUTOSAVE_SLOT)?);
+                    println!("Resuming the last session");
+                }
+                Some(..) => println!("No last session of this rom, starting from the beginning"),
+                None => bail!("No directory for save states, set one with --state-dir"),
+            }
         }
+        let autosave = self.autosave;
+        let saved_flags = cpu.repl;
+        let mut trace = self
+            .trace_file
+            .as_ref()
+            .map(|path| {
+                File::create(path)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("Could not create trace file {}", path.display()))
+            })
+            .transpose()?;
+        let mut access_log = self
+            .access_log
+            .as_ref()
+            .map(|path| {
+                File::create(path)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("Could not create access log {}", path.display()))
+            })
+            .transpose()?;
+        if access_log.is_some() {
+            cpu.access_log = Some(AccessLog {
+                ranges: self
+                    .access_log_ranges
+                    .iter()
+                    .map(|range| {
+                        (
+                            range.addr as usize,
+                            range.addr as usize + range.len as usize,
+                        )
+                    })
+                    .collect(),
+                accesses: Vec::new(),
+            });
+        }
+
+        let mut video = self.create_video(&cpu)?;
+        let mut video_frame = cpu.frame();
 
-        let cpu_thread = thread::spawn(move || -> Result<(), anyhow::Error> {
+        let (mut tx_tui, tui_thread) = if self.debug_tui {
+            cpu.heatmap = Some(Heatmap::new(MEMSIZE));
+            let (tx_tui, rx_tui) = mpsc::sync_channel::<Snapshot>(1);
+            let watches = self.watches.clone();
+            let tui_thread = thread::spawn(move || tui::run(rx_tui, watches));
+            (Some(tx_tui), Some(tui_thread))
+        } else {
+            (None, None)
+        };
+
+        let cpu_thread = thread::spawn(move || -> Result<ExitReason, anyhow::Error> {
             cpu.start_audio()?;
+            let mut exit_reason = ExitReason::Closed;
+            let mut debugger = Debugger::new(&breakpoints, &opcode_breaks, dump_memory);
+            let mut rewind = Rewind::new();
+            let mut history = History::new();
+            let mut hotspots = Hotspots::new();
+            let mut speed = 1.0;
+            let mut opcode_stats = if print_opcode_stats {
+                Some(OpcodeStats::new())
+            } else {
+                None
+            };
             loop {
+                while let Ok(command) = rx_debug.try_recv() {
+                    match command {
+                        // the frames of the movie only go forwards
+                        Command::Rewind | Command::GotoFrame(..) if movie_input.is_some() => {
+                            let _ = tx_status
+                                .send("A movie cannot be rewound, it would not replay".to_string());
+                        }
+                        Command::Rewind => {
+                            let _ = tx_status.send(rewind.rewind(&mut cpu, rewind_seconds));
+                        }
+                        Command::GotoFrame(frame) => match rewind.goto_frame(&mut cpu, frame) {
+                            Ok(..) => {
+                                let _ = tx_status.send(format!("At frame {}", cpu.frame()));
+                                debugger.state_replaced(&cpu);
+                            }
+                            Err(err) => {
+                                let _ = tx_status.send(format!("{:#}", err));
+                            }
+                        },
+                        Command::Hotspots(top) => {
+                            for line in hotspots.report(&cpu, top) {
+                                println!("{}", line);
+                            }
+                        }
+                        Command::Speed(factor) => {
+                            speed = factor;
+                            perf_cpu.set_limit(ips_limit.map(|limit| limit * speed));
+                            cpu.clock.set_multi(speed);
+                        }
+                        Command::SaveState(..) | Command::LoadState(..)
+                            if cpu.display.mega.is_some() =>
+                        {
+                            let _ = tx_status.send("Mega-CHIP states cannot be saved".to_string());
+                        }
+                        Command::SaveState(..) | Command::LoadState(..)
+                            if state_slots.is_none() =>
+                        {
+                            let _ = tx_status.send(
+                                "No directory for save states, set one with --state-dir"
+                                    .to_string(),
+                            );
+                        }
+                        Command::SaveState(slot) => {
+                            let slots = state_slots.as_ref().unwrap();
+                            let status = match slots.save(slot, cpu.save_state()) {
+                                Ok(..) => {
+                                    format!("Saved slot {} to {}", slot, slots.path(slot).display())
+                                }
+                                Err(err) => format!("{:#}", err),
+                            };
+                            let _ = tx_status.send(status);
+                        }
+                        Command::LoadState(..) if movie_input.is_some() => {
+                            let _ = tx_status.send(
+                                "A movie cannot load a state, it would not replay".to_string(),
+                            );
+                        }
+                        Command::LoadState(slot) => {
+                            match state_slots.as_ref().unwrap().load(slot) {
+                                Ok(state) => {
+                                    cpu.load_state(&state);
+                                    // the saved states are from another timeline
+                                    rewind = Rewind::new();
+                                    history = History::new();
+                                    tick_pending = true;
+                                    cpu.sound.silence();
+                                    if !debugger.is_paused() {
+                                        cpu.resume_sound();
+                                    }
+                                    let _ = tx_status.send(format!("Loaded slot {}", slot));
+                                    debugger.state_replaced(&cpu);
+                                }
+                                Err(err) => {
+                                    let _ = tx_status.send(format!("{:#}", err));
+                                }
+                            }
+                        }
+                        Command::Reset if movie_input.is_some() => {
+                            let _ = tx_status
+                                .send("A movie cannot be reset, it would not replay".to_string());
+                        }
+                        Command::Reset => {
+                            let mut fresh = emulator.setup_cpu(&code)?;
+                            // the running audio stream, the flags and the debug
+                            // recordings carry over to the new machine
+                            std::mem::swap(&mut fresh.sound, &mut cpu.sound);
+                            fresh.sound.silence();
+                            fresh.repl = cpu.repl;
+                            fresh.keyboard.set_keys(cpu.keyboard.keys);
+                            fresh.access_log = cpu.access_log.take();
+                            fresh.heatmap = cpu.heatmap.as_ref().map(|_| Heatmap::new(MEMSIZE));
+                            fresh.clock.set_multi(speed);
+                            if debugger.is_paused() {
+                                fresh.clock.pause();
+                            }
+                            cpu = fresh;
+                            pending_pokes = emulator.pending_pokes();
+                            rewind = Rewind::new();
+                            history = History::new();
+                            last_frame = 0;
+                            tick_pending = true;
+                            video_frame = 0;
+                            // show the cleared display
+                            cpu.display.updated = true;
+                            let _ = tx_status.send("Reset".to_string());
+                            debugger.state_replaced(&cpu);
+                        }
+                        command => debugger.handle(command, &mut cpu),
+                    }
+                }
+                let run = debugger.should_run(&mut cpu);
+                cpu_paused.store(debugger.is_paused(), Ordering::Relaxed);
+                if !run {
+                    if debugger.take_redraw() {
+                        // show the result of a step even if it did not change the display
+                        if tx_disp_notify.send(()).is_err() {
+                            break;
+                        }
+                        let display = (cpu.display.to_buf(), cpu.display.height, cpu.display.width);
+                        if tx_disp.send(display).is_err() {
+                            break;
+                        }
+                    }
+                    match rx_keys.try_recv() {
+                        Ok(keys) => {
+                            window_keys = keys;
+                            if movie_input.is_none() {
+                                cpu.keyboard.set_keys(keys);
+                            }
+                        }
+                        Err(TryRecvError::Empty) => {}
+                        Err(TryRecvError::Disconnected) => break,
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    continue;
+                }
+                if let Some(input) = movie_input.as_mut() {
+                    input.update(&mut cpu, &window_keys);
+                }
+
                 if debug >= 2 {
                     println!("{:?}", cpu.keyboard.keys);
                     println!("{:?}", cpu);
-                    println!("Instruction: {:#X}", cpu.next_instruction());
+                    println!("{}", trace_line(&cpu));
                 }
+                if let Some(trace) = trace.as_mut() {
+                    writeln!(trace, "{}", trace_line(&cpu)).context("Could not write trace")?;
+                }
+
+                apply_pokes(&mut cpu, &mut pending_pokes);
 
                 // Calculate next instruction
-                let instructions_done = cpu.tick()?;
-
-                //this variant skips frames
-                if skip_frames {
-                    // && cpu.display.updated{
-                    // The cpu.display.updated check can be added to increase IPS.
-                    // But it increases flickering due to short inbetween states
-                    // not being skipped.
-                    match tx_disp_notify.try_send(()) {
-                        Ok(..) => {
-                            match tx_disp.send((
-                                cpu.display.to_buf(),
-                                cpu.display.height,
-                                cpu.display.width,
-                            )) {
-                                Ok(..) => {}
-                                Err(SendError(..)) => {
-                                    break;
-                                }
-                            }
-                            //cpu.display.updated = false;
+                history.record(&cpu);
+                rewind.record_keys(&cpu);
+                hotspots.record(cpu.pc);
+                if let Some(stats) = opcode_stats.as_mut() {
+                    stats.record(cpu.next_instruction());
+                }
+                let result = match panic::catch_unwind(AssertUnwindSafe(|| cpu.step())) {
+                    Ok(Ok(instructions_done)) => Ok(instructions_done),
+                    Ok(Err(err)) => Err(err),
+                    Err(payload) => Err(anyhow!("CPU panicked: {}", panic_message(&payload))),
+                };
+                let instructions_done = match result {
+                    Ok(instructions_done) => instructions_done,
+                    Err(err) => {
+                        let _ = tx_status.send("Call stack:".to_string());
+                        for line in debugger::call_stack(&cpu) {
+                            let _ = tx_status.send(format!("  {}", line));
+                        }
+                        if let Some(path) = crash_dump.as_ref() {
+                            let status = match history.write_dump(path, &cpu, &err) {
+                                Ok(..) => format!("Crash dump written to {}", path.display()),
+                                Err(dump_err) => format!("{:#}", dump_err),
+                            };
+                            let _ = tx_status.send(status);
                         }
-                        Err(TrySendError::Full(..)) => {} //skipped frame
-                        Err(TrySendError::Disconnected(..)) => break,
+                        return Err(err);
                     }
-                } else if cpu.display.updated {
-                    cpu.display.updated = false;
-                    match tx_disp.send((
-                        cpu.display.to_buf(),
-                        cpu.display.height,
-                        cpu.display.width,
-                    )) {
-                        Ok(..) => {}
-                        Err(SendError(..)) => break,
+                };
+                if let Some(out) = access_log.as_mut() {
+                    let frame = cpu.frame();
+                    if let Some(log) = cpu.access_log.as_mut() {
+                        for access in log.accesses.drain(..) {
+                            writeln!(
+                                out,
+                                "{:>8}  {:04X}  {:<5}  {:04X}  {:02X}",
+                                frame, access.pc, access.access, access.addr, access.value
+                            )
+                            .context("Could not write access log")?;
+                        }
+                    }
+                }
+                if let Some(hit) = cpu.watch_hit.take() {
+                    debugger.watch_hit(hit, &mut cpu);
+                }
+                rewind.record(&cpu);
+                if let Some(buzzing) = cpu_buzzing.as_ref() {
+                    buzzing.store(cpu.timers().1 > 0, Ordering::Relaxed);
+                }
+                if let Some(video) = video.as_mut() {
+                    // frames without a new instruction repeat the display
+                    let frame = cpu.frame();
+                    if frame > video_frame {
+                        let display = cpu.display.to_buf();
+                        for _ in video_frame..frame {
+                            video.add_frame(&display, cpu.display.width, cpu.display.height)?;
+                        }
+                        video_frame = frame;
+                    }
+                }
+                if cpu.exited {
+                    exit_reason = ExitReason::ProgramExit;
+                    break;
+                }
+                if let Some(threshold) = exit_on_loop {
+                    if cpu.jump_to_self_count >= threshold {
+                        let _ = tx_status.send(format!("Program loops at {:#X}", cpu.pc));
+                        exit_reason = ExitReason::InfiniteLoop;
+                        break;
+                    }
+                }
+
+                let present = match flicker_reduction {
+                    FlickerReduction::None => cpu.display.updated,
+                    // Sampling the display whenever the output loop is ready skips
+                    // short inbetween states, which reduces flickering.
+                    FlickerReduction::Sample | FlickerReduction::Decay => true,
+                    FlickerReduction::Tick => {
+                        let frame = cpu.frame();
+                        if frame != last_frame {
+                            last_frame = frame;
+                            tick_pending = true;
+                        }
+                        tick_pending
+                    }
+                };
+                if present {
+                    let ready = if flicker_reduction == FlickerReduction::None {
+                        // every display change has to be presented, wait for the output loop
+                        match tx_disp_notify.send(()) {
+                            Ok(..) => true,
+                            Err(SendError(..)) => break,
+                        }
+                    } else {
+                        match tx_disp_notify.try_send(()) {
+                            Ok(..) => true,
+                            Err(TrySendError::Full(..)) => false, //skipped frame
+                            Err(TrySendError::Disconnected(..)) => break,
+                        }
+                    };
+                    if ready {
+                        cpu.display.updated = false;
+                        tick_pending = false;
+                        match tx_disp.send((
+                            cpu.display.to_buf(),
+                            cpu.display.height,
+                            cpu.display.width,
+                        )) {
+                            Ok(..) => {}
+                            Err(SendError(..)) => break,
+                        }
+                    }
+                }
+
+                if let Some(tx) = tx_tui.as_ref() {
+                    if !ticker_tui.wait_nonblocking() {
+                        match tx.try_send(Snapshot::from_cpu(&cpu)) {
+                            Ok(..) | Err(TrySendError::Full(..)) => {}
+                            // the debugger ui was closed, keep the emulation running
+                            Err(TrySendError::Disconnected(..)) => tx_tui = None,
+                        }
                     }
                 }
 
                 match rx_keys.try_recv() {
                     Ok(keys) => {
-                        cpu.keyboard.keys = keys;
+                        window_keys = keys;
+                        if movie_input.is_none() {
+                            cpu.keyboard.set_keys(keys);
+                        }
                     }
                     Err(TryRecvError::Empty) => {}
                     Err(TryRecvError::Disconnected) => break,
                 }
-                if instructions_done > 0 {
+                let counted_ticks = matches!(cpu.clock.mode, ClockMode::Instructions(..));
+                if cpu.vblank_wait.is_some() && counted_ticks {
+                    // the idle steps move the timers, throttle them like instructions
+                    perf_cpu.wait();
+                } else if cpu.vblank_wait.is_some() {
+                    // display wait quirk, sleep until the next timer tick
+                    std::thread::sleep(cpu.clock.time_to_next_tick());
+                } else if instructions_done > 0 {
                     perf_cpu.wait();
                 } else {
                     // No instruction was executed, cpu is stuck waiting for key input
                     // Use hard coded delay instead of counting cpu ticks
                     std::thread::sleep(std::time::Duration::from_millis(1));
                 }
-                if !ticker_tps.wait_nonblocking() && debug >= 1 {
-                    println!("instructions per second (ips): {}", perf_cpu.get_fps());
+                if !ticker_tps.wait_nonblocking() {
+                    let ips = perf_cpu.get_fps();
+                    cpu_ips.store(ips.to_bits(), Ordering::Relaxed);
+                    if print_ips {
+                        println!("instructions per second (ips): {}", ips);
+                    }
+                }
+            }
+            if let Some(input) = movie_input.as_ref() {
+                input.finish()?;
+            }
+            if let Some(video) = video {
+                video.finish()?;
+            }
+            if let Some(trace) = trace.as_mut() {
+                trace.flush().context("Could not write trace")?;
+            }
+            if let Some(out) = access_log.as_mut() {
+                out.flush().context("Could not write access log")?;
+            }
+            if let Some(top) = hotspots_report {
+                let _ = tx_status.send("Hotspots:".to_string());
+                for line in hotspots.report(&cpu, top) {
+                    let _ = tx_status.send(line);
+                }
+            }
+            if let Some(stats) = opcode_stats.as_ref() {
+                let _ = tx_status.send("Opcode usage:".to_string());
+                for line in stats.report() {
+                    let _ = tx_status.send(line);
+                }
+            }
+            if let Some(path) = flags_file {
+                if cpu.repl != saved_flags {
+                    flags::save(&path, &cpu.repl)?;
+                }
+            }
+            // a rom that exited or got stuck is started again next time
+            let resumable = exit_reason == ExitReason::Closed && cpu.display.mega.is_none();
+            if autosave && resumable {
+                match state_slots.as_ref() {
+                    Some(slots) => slots.save(AUTOSAVE_SLOT, cpu.save_state())?,
+                    None => {
+                        let _ = tx_status.send(
+                            "No directory for save states, the session is not saved".to_string(),
+                        );
+                    }
                 }
             }
-            Ok(())
+            Ok(exit_reason)
         });
 
-        while window.is_open() && !window.is_key_down(Key::Escape) {
-            let cpu_keys = convert_keys(&window);
-            match tx_keys.try_send(cpu_keys) {
+        let io = FrontendIo {
+            tx_keys,
+            rx_disp,
+            rx_disp_notify,
+            tx_debug,
+            buzzing,
+            paused,
+            ips,
+        };
+        let log = match (self.term, self.backend) {
+            (Some(style), _) => {
+                let sound_icon = self
+                    .sound_indicator
+                    .map(|_| self.buzzer_color.unwrap_or(BUZZER_COLOR));
+                self.run_frontend(
+                    io,
+                    &rx_status,
+                    &mut TermScreen::new(style, self.keymap, &self.hotkeys, sound_icon)?,
+                )?
+            }
+            (None, Backend::Minifb) => {
+                self.run_frontend(io, &rx_status, &mut WindowFrontend::new(self)?)?
+            }
+            #[cfg(feature = "sdl2")]
+            (None, Backend::Sdl2) => {
+                self.run_frontend(io, &rx_status, &mut SdlFrontend::new(self)?)?
+            }
+            #[cfg(feature = "wgpu-backend")]
+            (None, Backend::Wgpu) => {
+                self.run_frontend(io, &rx_status, &mut GpuFrontend::new(self)?)?
+            }
+        };
+        // the terminal is restored by now
+        for text in log {
+            println!("{}", text);
+        }
+        println!("Exiting");
+        let result = cpu_thread.join().unwrap();
+        // the reports at the end of the cpu thread
+        for text in rx_status.try_iter() {
+            println!("{}", text);
+        }
+        let exit_reason = result.context("Failed in CPU thread")?;
+        if let Some(tui_thread) = tui_thread {
+            tui_thread
+                .join()
+                .unwrap()
+                .context("Failed in debugger ui thread")?;
+        }
+        Ok(exit_reason)
+    }
+
+    // Passes keys and displays between the cpu thread and the frontend until the
+    // frontend quits or the cpu thread ends, returns the messages that are left
+    // to print after the frontend closed
+    fn run_frontend(
+        &self,
+        io: FrontendIo,
+        rx_status: &Receiver<String>,
+        frontend: &mut dyn Frontend,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let mut log = Vec::new();
+        let mut blender = self.blend_frames.map(FrameBlender::new);
+        let mut display = (vec![0u32; WIDTH * HEIGHT], HEIGHT, WIDTH);
+        let mut changed = true;
+        let mut gif = match self.record_gif.as_ref() {
+            Some(path) => {
+                println!("Recording to {}", path.display());
+                Some(start_gif(path, &display)?)
+            }
+            None => None,
+        };
+        let mut remote = match self.remote_input.as_ref() {
+            Some(addr) => {
+                println!("Listening for keys on {}", addr);
+                Some(RemoteKeys::listen(addr)?)
+            }
+            None => None,
+        };
+        let mut perf_io = PerfLimiter::new(self.fps_limit);
+        let mut ticker_fps = PerfLimiter::new(Some(1.0));
+        let mut perf_text = String::new();
+        // the display with the color adjustments, gif recordings get the original
+        let mut adjust = self.color_adjust;
+        let mut adjust_table = adjust.table();
+        let mut adjusted = Vec::new();
+        let mut turbo = false;
+        let mut slow_motion = false;
+
+        loop {
+            let input = match frontend.poll()? {
+                Some(input) => input,
+                None => break,
+            };
+            let mut speed_changed = input.turbo != turbo;
+            if speed_changed {
+                turbo = input.turbo;
+                if turbo {
+                    frontend.message(format!("Turbo x{}", self.turbo));
+                }
+            }
+            // printing would garble the terminal, it shows the messages again when it closed
+            for text in rx_status.try_iter() {
+                if self.term.is_some() {
+                    log.push(text.clone());
+                } else {
+                    println!("{}", text);
+                }
+                frontend.message(text);
+            }
+            let mut keys = input.keys;
+            if let Some(remote) = remote.as_mut() {
+                remote.merge(&mut keys);
+            }
+            match io.tx_keys.try_send(keys) {
                 Ok(..) => {}
                 Err(TrySendError::Full(..)) => {} //skipped input
                 Err(TrySendError::Disconnected(..)) => break,
             }
+            for action in input.actions {
+                match action {
+                    // the cpu thread may already be gone, the loop ends below
+                    Action::Debug(command) => {
+                        if command == Command::TogglePause {
+                            let paused = io.paused.load(Ordering::Relaxed);
+                            frontend.message(if paused { "Running" } else { "Paused" }.to_string());
+                        }
+                        let _ = io.tx_debug.send(command);
+                    }
+                    Action::ToggleGif => match gif.take() {
+                        Some(recorder) => {
+                            let path = finish_gif(recorder)?;
+                            frontend.message(format!("Recording written to {}", path.display()));
+                        }
+                        None => {
+                            let secs = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .map(|time| time.as_secs())
+                                .unwrap_or(0);
+                            let path = PathBuf::from(format!("ch8-rs-{}.gif", secs));
+                            gif = Some(start_gif(&path, &display)?);
+                            frontend.message(format!("Recording to {}", path.display()));
+                        }
+                    },
+                    Action::Brighter | Action::Darker => {
+                        let step = if action == Action::Brighter {
+                            BRIGHTNESS_STEP
+                        } else {
+                            -BRIGHTNESS_STEP
+                        };
+                        // rounded to whole steps so that the limits are reached
+                        let brightness = ((adjust.brightness + step) / BRIGHTNESS_STEP).round()
+                            * BRIGHTNESS_STEP;
+                        if (0.1..=3.0).contains(&brightness) {
+                            adjust.brightness = brightness;
+                            adjust_table = adjust.table();
+                        }
+                        frontend.message(format!("Brightness {:.0}%", adjust.brightness * 100.0));
+                        changed = true;
+                    }
+                    Action::Invert => {
+                        adjust.invert = !adjust.invert;
+                        adjust_table = adjust.table();
+                        let state = if adjust.invert { "on" } else { "off" };
+                        frontend.message(format!("Invert {}", state));
+                        changed = true;
+                    }
+                    Action::ToggleSlowMotion => {
+                        slow_motion = !slow_motion;
+                        speed_changed = true;
+                        frontend.message(if slow_motion {
+                            format!("Slow motion x{}", self.slow_motion)
+                        } else {
+                            "Slow motion off".to_string()
+                        });
+                    }
+                }
+            }
+            // both together multiply
+            if speed_changed {
+                let mut speed = 1.0;
+                if turbo {
+                    speed *= self.turbo;
+                }
+                if slow_motion {
+                    speed *= self.slow_motion;
+                }
+                perf_io.set_limit(self.fps_limit.map(|limit| limit * speed));
+                let _ = io.tx_debug.send(Command::Speed(speed));
+            }
 
-            match rx_disp_notify.try_recv() {
-                Ok(..) => match rx_disp.recv() {
-                    Ok((display_buf, height, width)) => {
-                        buffer[..height * width].copy_from_slice(&display_buf[..]);
-                        window
-                            .update_with_buffer(&buffer, width, height)
-                            .context("Updating minifb display buffer failed")?;
+            match io.rx_disp_notify.try_recv() {
+                Ok(..) => match io.rx_disp.recv() {
+                    Ok(new_display) => {
+                        display = new_display;
+                        if let Some(blender) = blender.as_mut() {
+                            blender.blend(&mut display.0);
+                        }
+                        if let Some(recorder) = gif.as_mut() {
+                            recorder.add_frame(&display.0, display.2, display.1)?;
+                        }
+                        changed = true;
                     }
                     Err(RecvError) => break,
                 },
-                Err(TryRecvError::Empty) => {
-                    window.update();
-                }
+                Err(TryRecvError::Empty) => {}
                 Err(TryRecvError::Disconnected) => break,
             }
+
+            if changed && !adjust.is_identity() {
+                adjusted.clear();
+                adjusted.extend(
+                    display
+                        .0
+                        .iter()
+                        .map(|color| adjust_color(&adjust_table, *color)),
+                );
+            }
+            let shown = if adjust.is_identity() {
+                &display.0
+            } else {
+                &adjusted
+            };
+            frontend.present(&Frame {
+                display: shown,
+                width: display.2,
+                height: display.1,
+                changed,
+                buzzing: io.buzzing.load(Ordering::Relaxed),
+                perf_text: &perf_text,
+            })?;
+            changed = false;
             perf_io.wait();
-            if !ticker_fps.wait_nonblocking() && debug >= 1 {
-                println!("frames per second       (fps): {}", perf_io.get_fps());
+            if !ticker_fps.wait_nonblocking() {
+                let fps = perf_io.get_fps();
+                if self.perf_overlay {
+                    let ips = f64::from_bits(io.ips.load(Ordering::Relaxed));
+                    perf_text = format!("{:.0} IPS  {:.0} FPS", ips, fps);
+                    changed = true;
+                } else if self.debug >= 1 && self.term.is_none() {
+                    println!("frames per second       (fps): {}", fps);
+                }
             }
         }
-        println!("Exiting");
-        drop(rx_disp);
-        drop(tx_keys);
-        cpu_thread.join().unwrap().context("Failed in CPU thread")?;
-        Ok(())
+        if let Some(recorder) = gif {
+            let path = finish_gif(recorder)?;
+            log.push(format!("Recording written to {}", path.display()));
+        }
+        log.extend(rx_status.try_iter());
+        Ok(log)
     }
 }
 
-fn convert_keys(window: &Window) -> [VKey; 16] {
-    let keys = [
-        Key::X,
-        Key::Key1,
-        Key::Key2,
-        Key::Key3,
-        Key::Q,
-        Key::W,
-        Key::E,
-        Key::A,
-        Key::S,
-        Key::D,
-        Key::Z,
-        Key::C,
-        Key::Key4,
-        Key::R,
-        Key::F,
-        Key::V,
-    ];
-    let mut cpu_keys = [VKey::Up; 16];
-    keys.iter()
-        .map(|key| {
-            if window.is_key_down(*key) {
-                VKey::Down
-            } else {
-                VKey::Up
-            }
-        })
-        .zip(cpu_keys.iter_mut())
-        .for_each(|(winkey, cpukey)| *cpukey = winkey);
-    cpu_keys
+// Channels between the cpu thread and the frontend that shows the display
+struct FrontendIo {
+    tx_keys: SyncSender<[VKey; 16]>,
+    rx_disp: Receiver<(Vec<u32>, usize, usize)>,
+    rx_disp_notify: Receiver<()>,
+    tx_debug: Sender<Command>,
+    // the sound timer runs, only updated with a buzzer color or a sound indicator
+    buzzing: Arc<AtomicBool>,
+    // the debugger holds the cpu
+    paused: Arc<AtomicBool>,
+    // instructions per second as f64 bits
+    ips: Arc<AtomicU64>,
+}
+
+enum Lockstep {
+    // where and how the states differ first
+    Diverged(String),
+    // frame in which one of the programs exited or halted
+    Stopped(u64),
+    Completed,
+}
+
+// First difference between two machine states, as "what: first != second"
+fn state_difference(a: &Cpu, b: &Cpu) -> Option<String> {
+    if a.pc != b.pc {
+        return Some(format!("PC {:04X} != {:04X}", a.pc, b.pc));
+    }
+    if let Some(x) = (0..16).find(|x| a.v[*x] != b.v[*x]) {
+        return Some(format!("V{:X} {:02X} != {:02X}", x, a.v[x], b.v[x]));
+    }
+    if a.i != b.i {
+        return Some(format!("I {:04X} != {:04X}", a.i, b.i));
+    }
+    if a.sp != b.sp || a.stack != b.stack {
+        return Some(format!("stack {:?} != {:?}", a.stack, b.stack));
+    }
+    if a.timers() != b.timers() {
+        return Some(format!("timers {:?} != {:?}", a.timers(), b.timers()));
+    }
+    if let Some(addr) =
+        (0..a.memory.len().min(b.memory.len())).find(|addr| a.memory[*addr] != b.memory[*addr])
+    {
+        return Some(format!(
+            "memory at {:04X} {:02X} != {:02X}",
+            addr, a.memory[addr], b.memory[addr]
+        ));
+    }
+    if a.display.width != b.display.width || a.display.planes != b.display.planes {
+        return Some("display".to_string());
+    }
+    None
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+fn trace_line(cpu: &Cpu) -> String {
+    let instr = cpu.next_instruction();
+    format!(
+        "{:04X}  {:04X}  {}",
+        cpu.pc,
+        instr,
+        disassemble_with_registers(instr, &cpu.v)
+    )
+}
+
+// change of the brightness per hotkey press
+const BRIGHTNESS_STEP: f64 = 0.1;
+
+fn apply_pokes(cpu: &mut Cpu, pending: &mut Vec<Poke>) {
+    let frame = cpu.frame();
+    while let Some(poke) = pending.last().copied() {
+        if poke.frame > Some(frame) {
+            break;
+        }
+        cpu.memory[poke.addr as usize] = poke.value;
+        pending.pop();
+    }
+}
+
+// Starts a recording with the current display as first frame
+fn start_gif(
+    path: &Path,
+    display: &(Vec<u32>, usize, usize),
+) -> Result<GifRecorder, anyhow::Error> {
+    let (buf, height, width) = display;
+    let mut recorder = GifRecorder::create(path, *width, *height)?;
+    recorder.add_frame(buf, *width, *height)?;
+    Ok(recorder)
+}
+
+// Returns the path of the written recording
+fn finish_gif(recorder: GifRecorder) -> Result<PathBuf, anyhow::Error> {
+    let path = recorder.path().to_path_buf();
+    recorder.finish()?;
+    Ok(path)
 }
@@ -0,0 +1,34 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use super::rom::sha1_hex;
+
+// SCHIP flag registers (Fx75/Fx85) are kept per rom in the user data directory,
+// e.g. ~/.local/share/ch8-rs/flags/<rom sha1>.bin, so saved high scores survive restarts
+pub fn path_for_rom(code: &[u8]) -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| {
+        dir.join("ch8-rs")
+            .join("flags")
+            .join(format!("{}.bin", sha1_hex(code)))
+    })
+}
+
+// Returns None if no flags were saved for the rom yet
+pub fn load(path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let flags =
+        fs::read(path).with_context(|| format!("Could not read flags {}", path.display()))?;
+    Ok(Some(flags))
+}
+
+pub fn save(path: &Path, flags: &[u8]) -> Result<(), anyhow::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    }
+    fs::write(path, flags).with_context(|| format!("Could not write flags {}", path.display()))
+}
@@ -0,0 +1,105 @@
+// The part of the emulator that shows the display and reads the keys, the
+// window and the terminal implement it and share the loop in Emulator::run
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use super::cpu::VKey;
+use super::debugger::Command;
+
+// Library of the window
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Backend {
+    Minifb,
+    // needs the sdl2 feature
+    #[cfg(feature = "sdl2")]
+    Sdl2,
+    // needs the wgpu-backend feature
+    #[cfg(feature = "wgpu-backend")]
+    Wgpu,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minifb" => Ok(Backend::Minifb),
+            #[cfg(feature = "sdl2")]
+            "sdl2" => Ok(Backend::Sdl2),
+            #[cfg(not(feature = "sdl2"))]
+            "sdl2" => bail!("ch8-rs was built without SDL2, build it with --features sdl2"),
+            #[cfg(feature = "wgpu-backend")]
+            "wgpu" => Ok(Backend::Wgpu),
+            #[cfg(not(feature = "wgpu-backend"))]
+            "wgpu" => bail!("ch8-rs was built without wgpu, build it with --features wgpu-backend"),
+            _ => bail!("Unknown backend {}, expected minifb, sdl2 or wgpu", s),
+        }
+    }
+}
+
+// Shows that the sound timer runs, e.g. without sound or for the hard of hearing
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SoundIndicator {
+    // the bars around the display take the buzzer color
+    Border,
+    // a note in the top right corner
+    Icon,
+}
+
+impl FromStr for SoundIndicator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "border" => Ok(SoundIndicator::Border),
+            "icon" => Ok(SoundIndicator::Icon),
+            _ => bail!("Unknown sound indicator {}, expected border or icon", s),
+        }
+    }
+}
+
+// What the user did since the last poll
+pub struct Input {
+    pub keys: [VKey; 16],
+    pub actions: Vec<Action>,
+    // the turbo key is held
+    pub turbo: bool,
+}
+
+// Hotkeys handled by the loop, hotkeys of a single frontend stay inside it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Action {
+    Debug(Command),
+    // starts or stops a GIF recording
+    ToggleGif,
+    Brighter,
+    Darker,
+    Invert,
+    // scales the speed down by --slow-motion or back up
+    ToggleSlowMotion,
+}
+
+pub struct Frame<'a> {
+    pub display: &'a [u32],
+    pub width: usize,
+    pub height: usize,
+    // the display or the performance text changed since the last frame
+    pub changed: bool,
+    // the sound timer runs, only updated with a buzzer color or a sound indicator
+    pub buzzing: bool,
+    // instructions and frames per second, empty without --perf-counter and
+    // during the first second
+    pub perf_text: &'a str,
+}
+
+pub trait Frontend {
+    // None when the user wants to quit
+    fn poll(&mut self) -> Result<Option<Input>, anyhow::Error>;
+
+    // Called once per loop after poll, also when nothing changed
+    fn present(&mut self, frame: &Frame) -> Result<(), anyhow::Error>;
+
+    // Shows a short message, e.g. where a recording was written
+    fn message(&mut self, _text: String) {}
+}
@@ -0,0 +1,103 @@
+// Buttons of a game controller for the CHIP-8 keys, read by the SDL2 window
+use std::fs;
+
+use anyhow::{anyhow, bail, Context};
+
+// the names of the buttons in SDL2
+pub const BUTTONS: [&str; 15] = [
+    "a",
+    "b",
+    "x",
+    "y",
+    "back",
+    "guide",
+    "start",
+    "leftstick",
+    "rightstick",
+    "leftshoulder",
+    "rightshoulder",
+    "dpup",
+    "dpdown",
+    "dpleft",
+    "dpright",
+];
+
+// Loaded from a file with one button per line:
+//   dpup 2
+//   a 5
+// The key is a hex digit or - for an unused button. Buttons missing from the
+// file keep their defaults, empty lines and lines starting with # are ignored.
+#[derive(Clone, Debug)]
+pub struct GamepadMap {
+    // the key of each button of BUTTONS
+    keys: [Option<u8>; 15],
+}
+
+// The d-pad moves with 2, 4, 6 and 8 and A presses 5, the controls of many
+// CHIP-8 games
+impl Default for GamepadMap {
+    fn default() -> Self {
+        let mut map = Self { keys: [None; 15] };
+        for (button, key) in [
+            ("dpup", 2),
+            ("dpleft", 4),
+            ("dpright", 6),
+            ("dpdown", 8),
+            ("a", 5),
+        ] {
+            map.set(button, Some(key)).unwrap();
+        }
+        map
+    }
+}
+
+impl GamepadMap {
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let input = fs::read_to_string(path)
+            .with_context(|| format!("Could not read gamepad map {}", path))?;
+        Self::parse(&input).with_context(|| format!("Invalid gamepad map {}", path))
+    }
+
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let mut map = GamepadMap::default();
+        let lines = input
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        for (n, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let result = match fields[..] {
+                [button, "-"] => map.set(button, None),
+                [button, key] => match u8::from_str_radix(key, 16) {
+                    Ok(key) if key < 16 => map.set(button, Some(key)),
+                    _ => Err(anyhow!("Invalid key {}, expected a hex digit or -", key)),
+                },
+                _ => bail!("Line {} is not of the form <button> <key>", n),
+            };
+            result.with_context(|| format!("In line {}", n))?;
+        }
+        Ok(map)
+    }
+
+    fn set(&mut self, button: &str, key: Option<u8>) -> Result<(), anyhow::Error> {
+        match BUTTONS.iter().position(|name| *name == button) {
+            Some(index) => self.keys[index] = key,
+            None => bail!(
+                "Unknown button {}, expected one of {}",
+                button,
+                BUTTONS.join(", ")
+            ),
+        }
+        Ok(())
+    }
+
+    // The buttons by their SDL2 name with the CHIP-8 key they press
+    #[cfg(feature = "sdl2")]
+    pub fn buttons(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        BUTTONS
+            .iter()
+            .zip(self.keys.iter())
+            .filter_map(|(button, key)| key.map(|key| (*button, key as usize)))
+    }
+}
@@ -0,0 +1,260 @@
+// Animated GIF writer for recordings of the display. Every frame has its own
+// color table, so the 4 colors of the usual display are kept exactly.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Context;
+
+use super::render::scale_nearest;
+
+// small displays are scaled up by whole numbers to about this width
+const TARGET_WIDTH: usize = 256;
+// largest code of the LZW compression
+const MAX_CODE: u16 = 4095;
+
+pub struct GifRecorder {
+    path: PathBuf,
+    out: BufWriter<File>,
+    width: usize,
+    height: usize,
+    start: Instant,
+    // frame waiting for its delay, which is known when the next frame arrives
+    pending: Option<Vec<u32>>,
+    // centiseconds of the frames written so far
+    written: u64,
+    scaled: Vec<u32>,
+}
+
+impl GifRecorder {
+    // The size of the first display sets the size of the recording
+    pub fn create(
+        path: &Path,
+        display_width: usize,
+        display_height: usize,
+    ) -> Result<Self, anyhow::Error> {
+        let file = File::create(path)
+            .with_context(|| format!("Could not create recording {}", path.display()))?;
+        let factor = (TARGET_WIDTH / display_width).max(1);
+        let mut recorder = Self {
+            path: path.to_path_buf(),
+            out: BufWriter::new(file),
+            width: display_width * factor,
+            height: display_height * factor,
+            start: Instant::now(),
+            pending: None,
+            written: 0,
+            scaled: Vec::new(),
+        };
+        recorder.write_header()?;
+        Ok(recorder)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Adds a display, displays of another size are scaled to the recording size
+    pub fn add_frame(
+        &mut self,
+        display: &[u32],
+        display_width: usize,
+        display_height: usize,
+    ) -> Result<(), anyhow::Error> {
+        let elapsed = (self.start.elapsed().as_secs_f64() * 100.0).round() as u64;
+        if let Some(frame) = self.pending.take() {
+            // frames shown for less than a centisecond are dropped
+            if elapsed > self.written {
+                self.write_frame(&frame, (elapsed - self.written) as u16)?;
+                self.written = elapsed;
+            }
+        }
+        self.scaled.resize(self.width * self.height, 0);
+        scale_nearest(
+            display,
+            display_width,
+            display_height,
+            &mut self.scaled,
+            self.width,
+            self.height,
+        );
+        self.pending = Some(self.scaled.clone());
+        Ok(())
+    }
+
+    // Writes the last frame and the end of the file
+    pub fn finish(mut self) -> Result<(), anyhow::Error> {
+        if let Some(frame) = self.pending.take() {
+            let elapsed = (self.start.elapsed().as_secs_f64() * 100.0).round() as u64;
+            let delay = elapsed.saturating_sub(self.written).max(1);
+            self.write_frame(&frame, delay.min(u16::MAX as u64) as u16)?;
+        }
+        self.out.write_all(&[0x3B])?;
+        self.out
+            .flush()
+            .with_context(|| format!("Could not write recording {}", self.path.display()))
+    }
+
+    fn write_header(&mut self) -> Result<(), anyhow::Error> {
+        self.out.write_all(b"GIF89a")?;
+        self.out.write_all(&(self.width as u16).to_le_bytes())?;
+        self.out.write_all(&(self.height as u16).to_le_bytes())?;
+        // no global color table, background color, pixel aspect ratio
+        self.out.write_all(&[0x00, 0x00, 0x00])?;
+        // loop forever
+        self.out.write_all(&[0x21, 0xFF, 0x0B])?;
+        self.out.write_all(b"NETSCAPE2.0")?;
+        self.out.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: &[u32], delay: u16) -> Result<(), anyhow::Error> {
+        let (palette, indices) = quantize(frame);
+        let mut table_bits = 1;
+        while (1 << table_bits) < palette.len() {
+            table_bits += 1;
+        }
+
+        // graphic control extension with the delay in centiseconds
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x04])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0x00, 0x00])?;
+        // image descriptor with a local color table
+        self.out.write_all(&[0x2C, 0x00, 0x00, 0x00, 0x00])?;
+        self.out.write_all(&(self.width as u16).to_le_bytes())?;
+        self.out.write_all(&(self.height as u16).to_le_bytes())?;
+        self.out.write_all(&[0x80 | (table_bits - 1)])?;
+        for index in 0..1usize << table_bits {
+            let color = palette.get(index).copied().unwrap_or(0);
+            self.out
+                .write_all(&[(color >> 16) as u8, (color >> 8) as u8, color as u8])?;
+        }
+
+        let min_code_size = table_bits.max(2);
+        self.out.write_all(&[min_code_size])?;
+        for block in lzw_compress(&indices, min_code_size).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0x00])?;
+        Ok(())
+    }
+}
+
+// Color table and color indices of a frame, colors beyond 256 get the nearest table color
+pub fn quantize(frame: &[u32]) -> (Vec<u32>, Vec<u8>) {
+    let mut palette: Vec<u32> = Vec::new();
+    let mut lookup: HashMap<u32, u8> = HashMap::new();
+    let indices = frame
+        .iter()
+        .map(|color| {
+            let color = color & 0x00FF_FFFF;
+            if let Some(index) = lookup.get(&color) {
+                return *index;
+            }
+            let index = if palette.len() < 256 {
+                palette.push(color);
+                (palette.len() - 1) as u8
+            } else {
+                nearest(&palette, color)
+            };
+            lookup.insert(color, index);
+            index
+        })
+        .collect();
+    (palette, indices)
+}
+
+fn nearest(palette: &[u32], color: u32) -> u8 {
+    let distance = |other: u32| -> i32 {
+        [0, 8, 16]
+            .iter()
+            .map(|shift| {
+                let diff = ((color >> shift) & 0xFF) as i32 - ((other >> shift) & 0xFF) as i32;
+                diff * diff
+            })
+            .sum()
+    };
+    (0..palette.len())
+        .min_by_key(|index| distance(palette[*index]))
+        .unwrap_or(0) as u8
+}
+
+// Packs codes of varying length into bytes, starting with the lowest bit
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.bits |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+// Variable code length LZW as used by GIF
+fn lzw_compress(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut writer = BitWriter {
+        out: Vec::new(),
+        bits: 0,
+        count: 0,
+    };
+    let mut code_size = min_code_size as u32 + 1;
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end + 1;
+    writer.write(clear, code_size);
+
+    let (first, rest) = match indices.split_first() {
+        Some(split) => split,
+        None => {
+            writer.write(end, code_size);
+            return writer.finish();
+        }
+    };
+    let mut prefix = *first as u16;
+    for index in rest {
+        if let Some(code) = table.get(&(prefix, *index)) {
+            prefix = *code;
+            continue;
+        }
+        writer.write(prefix, code_size);
+        // the decoder needs longer codes once the next code does not fit anymore
+        if next_code >= 1 << code_size && code_size < 12 {
+            code_size += 1;
+        }
+        if next_code <= MAX_CODE {
+            table.insert((prefix, *index), next_code);
+            next_code += 1;
+        } else {
+            writer.write(clear, code_size);
+            table.clear();
+            next_code = end + 1;
+            code_size = min_code_size as u32 + 1;
+        }
+        prefix = *index as u16;
+    }
+    writer.write(prefix, code_size);
+    if next_code >= 1 << code_size && code_size < 12 {
+        code_size += 1;
+    }
+    writer.write(end, code_size);
+    writer.finish()
+}
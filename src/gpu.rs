@@ -0,0 +1,415 @@
+// The display in a winit window drawn with wgpu (Vulkan, Metal or DirectX 12),
+// only built with the wgpu-backend feature. The picture is the same as in the
+// minifb window and is copied into the surface without shaders.
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use winit::dpi::{LogicalSize, PhysicalSize};
+use winit::event::{ElementState, Event, KeyEvent, WindowEvent};
+use winit::event_loop::EventLoop;
+use winit::keyboard::{KeyCode, ModifiersState, PhysicalKey};
+use winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
+use winit::window::{Fullscreen, Window, WindowBuilder};
+
+use super::cpu::{VKey, HEIGHT, WIDTH};
+use super::debugger::Command;
+use super::emulator::Emulator;
+use super::frontend::{Action, Frame, Frontend, Input};
+use super::hotkeys::{BoundHotkeys, HotkeyKey, Modifier};
+use super::window::View;
+
+// the flag asks for shift to be held, F11 is left to fullscreen
+const DEBUGGER_KEYS: [(KeyCode, bool, Command, &str); 4] = [
+    (KeyCode::F5, false, Command::Resume, "Running"),
+    (KeyCode::F6, false, Command::Pause, "Paused"),
+    (KeyCode::F10, false, Command::Step, "Step"),
+    (KeyCode::F10, true, Command::StepFrame, "Step frame"),
+];
+// the save state slots 1 - 9 with the modifiers of the hotkeys
+const SLOT_KEYS: [KeyCode; 9] = [
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+    KeyCode::Digit9,
+];
+
+pub struct GpuFrontend {
+    event_loop: EventLoop<()>,
+    window: Arc<Window>,
+    surface: wgpu::Surface<'static>,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    config: wgpu::SurfaceConfiguration,
+    // the surface stores red and blue the other way round than the picture
+    swap_red_blue: bool,
+    view: View,
+    // the CHIP-8 keys 0 - F by their position on the keyboard
+    key_codes: [Option<KeyCode>; 16],
+    keys: [VKey; 16],
+    hotkeys: BoundHotkeys<KeyCode>,
+    modifiers: ModifiersState,
+    // the turbo key is held
+    turbo: bool,
+    // the picture as bytes of the surface format
+    bytes: Vec<u8>,
+}
+
+impl GpuFrontend {
+    pub fn new(emulator: &Emulator) -> Result<Self, anyhow::Error> {
+        let event_loop = EventLoop::new().context("Could not create winit event loop")?;
+        // the window is sized in logical pixels, so that it has the same size on
+        // high DPI screens, the surface always has the size in physical pixels
+        let scale = emulator.window_scale as u32;
+        let mut builder = WindowBuilder::new()
+            .with_title("CHIP8 - ESC to exit")
+            .with_inner_size(LogicalSize::new(
+                WIDTH as u32 * scale,
+                HEIGHT as u32 * scale,
+            ));
+        if emulator.fullscreen {
+            builder = builder.with_fullscreen(Some(Fullscreen::Borderless(None)));
+        }
+        let window = Arc::new(
+            builder
+                .build(&event_loop)
+                .context("Could not create winit window")?,
+        );
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let surface = instance
+            .create_surface(window.clone())
+            .context("Could not create wgpu surface")?;
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            compatible_surface: Some(&surface),
+            ..wgpu::RequestAdapterOptions::default()
+        }))
+        .context("No graphics adapter found for wgpu")?;
+        let (device, queue) =
+            pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None))
+                .context("Could not open the graphics device")?;
+
+        let capabilities = surface.get_capabilities(&adapter);
+        if !capabilities.usages.contains(wgpu::TextureUsages::COPY_DST) {
+            bail!("The wgpu surface cannot be copied to, try another backend");
+        }
+        // pixels are written as little endian 0xAARRGGBB, so blue comes first
+        let format = capabilities
+            .formats
+            .iter()
+            .copied()
+            .find(|format| {
+                matches!(
+                    format,
+                    wgpu::TextureFormat::Bgra8Unorm
+                        | wgpu::TextureFormat::Bgra8UnormSrgb
+                        | wgpu::TextureFormat::Rgba8Unorm
+                        | wgpu::TextureFormat::Rgba8UnormSrgb
+                )
+            })
+            .context("The wgpu surface has no 8 bit RGB format")?;
+        let swap_red_blue = matches!(
+            format,
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb
+        );
+        let size = window.inner_size();
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::COPY_DST,
+            format,
+            width: size.width.max(1),
+            height: size.height.max(1),
+            present_mode: wgpu::PresentMode::AutoNoVsync,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: capabilities.alpha_modes[0],
+            view_formats: Vec::new(),
+        };
+        surface.configure(&device, &config);
+
+        Ok(Self {
+            event_loop,
+            window,
+            surface,
+            device,
+            queue,
+            config,
+            swap_red_blue,
+            view: View::new(emulator),
+            key_codes: emulator.keymap.positions().map(key_code),
+            keys: [VKey::Up; 16],
+            hotkeys: emulator.hotkeys.bind(hotkey_code),
+            modifiers: ModifiersState::empty(),
+            turbo: false,
+            bytes: Vec::new(),
+        })
+    }
+
+    fn resize(&mut self, size: PhysicalSize<u32>) {
+        if size.width > 0 && size.height > 0 {
+            self.config.width = size.width;
+            self.config.height = size.height;
+            self.surface.configure(&self.device, &self.config);
+        }
+    }
+
+    fn toggle_fullscreen(&self) {
+        let fullscreen = match self.window.fullscreen() {
+            Some(_) => None,
+            None => Some(Fullscreen::Borderless(None)),
+        };
+        self.window.set_fullscreen(fullscreen);
+    }
+
+    fn key_pressed(&mut self, key: KeyCode, repeat: bool, actions: &mut Vec<Action>) {
+        match key {
+            KeyCode::F1 if !repeat => self.view.toggle_keypad(),
+            KeyCode::F2 if !repeat => self.view.next_texture_filter(),
+            KeyCode::F3 if !repeat => self.view.next_post_filter(),
+            KeyCode::F4 if !repeat => actions.push(Action::Invert),
+            KeyCode::F7 => actions.push(Action::Darker),
+            KeyCode::F8 => actions.push(Action::Brighter),
+            KeyCode::Enter if self.modifiers.alt_key() && !repeat => self.toggle_fullscreen(),
+            KeyCode::F11 if !repeat => self.toggle_fullscreen(),
+            _ => {}
+        }
+        for (debug_key, with_shift, command, name) in DEBUGGER_KEYS.iter() {
+            if key == *debug_key && self.modifiers.shift_key() == *with_shift {
+                actions.push(Action::Debug(*command));
+                self.view.message(name.to_string());
+            }
+        }
+        let is_key = |hotkey: Option<KeyCode>| hotkey == Some(key);
+        if !repeat && is_key(self.hotkeys.record) {
+            actions.push(Action::ToggleGif);
+        }
+        if !repeat && is_key(self.hotkeys.pause) {
+            actions.push(Action::Debug(Command::TogglePause));
+        }
+        if !repeat && is_key(self.hotkeys.slow_motion) {
+            actions.push(Action::ToggleSlowMotion);
+        }
+        if is_key(self.hotkeys.rewind) {
+            actions.push(Action::Debug(Command::Rewind));
+            self.view.message("Rewind".to_string());
+        }
+        if !repeat && is_key(self.hotkeys.reset) {
+            actions.push(Action::Debug(Command::Reset));
+            self.view.message("Reset".to_string());
+        }
+        let modifiers = self.modifiers;
+        let slot = SLOT_KEYS
+            .iter()
+            .position(|slot_key| *slot_key == key)
+            .filter(|_| !repeat);
+        if let Some(command) = slot.and_then(|index| {
+            self.hotkeys.slot_command(index as u8 + 1, |modifier| {
+                modifier_held(modifiers, modifier)
+            })
+        }) {
+            actions.push(Action::Debug(command));
+        }
+    }
+}
+
+impl Frontend for GpuFrontend {
+    // Escape or closing the window quits
+    fn poll(&mut self) -> Result<Option<Input>, anyhow::Error> {
+        let mut window_events = Vec::new();
+        let status = self
+            .event_loop
+            .pump_events(Some(Duration::ZERO), |event, _| {
+                if let Event::WindowEvent { event, .. } = event {
+                    window_events.push(event);
+                }
+            });
+        if let PumpStatus::Exit(_) = status {
+            return Ok(None);
+        }
+
+        let mut actions = Vec::new();
+        for event in window_events {
+            match event {
+                WindowEvent::CloseRequested => return Ok(None),
+                WindowEvent::Resized(size) => self.resize(size),
+                WindowEvent::ModifiersChanged(modifiers) => {
+                    self.modifiers = modifiers.state();
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            physical_key: PhysicalKey::Code(key),
+                            state,
+                            repeat,
+                            ..
+                        },
+                    ..
+                } => {
+                    let pressed = state == ElementState::Pressed;
+                    if pressed && key == KeyCode::Escape {
+                        return Ok(None);
+                    }
+                    if let Some(index) = self.key_codes.iter().position(|k| *k == Some(key)) {
+                        self.keys[index] = if pressed { VKey::Down } else { VKey::Up };
+                    }
+                    if self.hotkeys.turbo == Some(key) {
+                        self.turbo = pressed;
+                    }
+                    if pressed {
+                        self.key_pressed(key, repeat, &mut actions);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let modifiers = self.modifiers;
+        let keys = if self
+            .hotkeys
+            .slot_modifier_held(|modifier| modifier_held(modifiers, modifier))
+        {
+            [VKey::Up; 16]
+        } else {
+            self.keys
+        };
+        self.view.set_keys(keys);
+        Ok(Some(Input {
+            keys,
+            actions,
+            turbo: self.turbo,
+        }))
+    }
+
+    fn present(&mut self, frame: &Frame) -> Result<(), anyhow::Error> {
+        let (width, height) = (self.config.width, self.config.height);
+        let buffer = match self.view.render(frame, (width as usize, height as usize)) {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+        self.bytes.clear();
+        for pixel in buffer {
+            let pixel = if self.swap_red_blue {
+                (pixel & 0xFF00) | (pixel >> 16 & 0xFF) | (pixel & 0xFF) << 16
+            } else {
+                *pixel
+            };
+            self.bytes
+                .extend_from_slice(&(pixel | 0xFF00_0000).to_le_bytes());
+        }
+
+        let output = match self.surface.get_current_texture() {
+            Ok(output) => output,
+            // e.g. after a resize, the next frame is drawn again
+            Err(wgpu::SurfaceError::Lost) | Err(wgpu::SurfaceError::Outdated) => {
+                self.surface.configure(&self.device, &self.config);
+                self.view.force_redraw();
+                return Ok(());
+            }
+            Err(err) => return Err(err).context("Could not get the wgpu surface texture"),
+        };
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &output.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &self.bytes,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(width * 4),
+                rows_per_image: Some(height),
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(None);
+        output.present();
+        Ok(())
+    }
+
+    fn message(&mut self, text: String) {
+        self.view.message(text);
+    }
+}
+
+fn modifier_held(modifiers: ModifiersState, modifier: Modifier) -> bool {
+    match modifier {
+        Modifier::Shift => modifiers.shift_key(),
+        Modifier::Ctrl => modifiers.control_key(),
+        Modifier::Alt => modifiers.alt_key(),
+    }
+}
+
+fn hotkey_code(key: HotkeyKey) -> Option<KeyCode> {
+    let function_keys = [
+        KeyCode::F1,
+        KeyCode::F2,
+        KeyCode::F3,
+        KeyCode::F4,
+        KeyCode::F5,
+        KeyCode::F6,
+        KeyCode::F7,
+        KeyCode::F8,
+        KeyCode::F9,
+        KeyCode::F10,
+        KeyCode::F11,
+        KeyCode::F12,
+    ];
+    match key {
+        HotkeyKey::Char(c) => key_code(c),
+        HotkeyKey::F(n) => function_keys.get(n as usize - 1).copied(),
+        HotkeyKey::Tab => Some(KeyCode::Tab),
+        HotkeyKey::Backspace => Some(KeyCode::Backspace),
+        HotkeyKey::Space => Some(KeyCode::Space),
+    }
+}
+
+// The key at the position of a character on a US keyboard
+fn key_code(c: char) -> Option<KeyCode> {
+    let key = match c {
+        '0' => KeyCode::Digit0,
+        '1' => KeyCode::Digit1,
+        '2' => KeyCode::Digit2,
+        '3' => KeyCode::Digit3,
+        '4' => KeyCode::Digit4,
+        '5' => KeyCode::Digit5,
+        '6' => KeyCode::Digit6,
+        '7' => KeyCode::Digit7,
+        '8' => KeyCode::Digit8,
+        '9' => KeyCode::Digit9,
+        'a' => KeyCode::KeyA,
+        'b' => KeyCode::KeyB,
+        'c' => KeyCode::KeyC,
+        'd' => KeyCode::KeyD,
+        'e' => KeyCode::KeyE,
+        'f' => KeyCode::KeyF,
+        'g' => KeyCode::KeyG,
+        'h' => KeyCode::KeyH,
+        'i' => KeyCode::KeyI,
+        'j' => KeyCode::KeyJ,
+        'k' => KeyCode::KeyK,
+        'l' => KeyCode::KeyL,
+        'm' => KeyCode::KeyM,
+        'n' => KeyCode::KeyN,
+        'o' => KeyCode::KeyO,
+        'p' => KeyCode::KeyP,
+        'q' => KeyCode::KeyQ,
+        'r' => KeyCode::KeyR,
+        's' => KeyCode::KeyS,
+        't' => KeyCode::KeyT,
+        'u' => KeyCode::KeyU,
+        'v' => KeyCode::KeyV,
+        'w' => KeyCode::KeyW,
+        'x' => KeyCode::KeyX,
+        'y' => KeyCode::KeyY,
+        'z' => KeyCode::KeyZ,
+        _ => return None,
+    };
+    Some(key)
+}
@@ -0,0 +1,78 @@
+// Recent reads, writes and executed instructions of every memory address, shown
+// as a grid of colors by the debugger ui
+use std::ops::Range;
+
+use super::cpu::Access;
+
+// frames until an access has faded to the color of old accesses
+const FADE_FRAMES: u32 = 60;
+// addresses that were accessed at some time stay visible
+const MIN_HEAT: u32 = 64;
+
+#[derive(Clone)]
+pub struct Heatmap {
+    // frame + 1 of the last access of each address, 0 if it was never accessed
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+    executes: Vec<u32>,
+    // end of the highest accessed address
+    end: usize,
+}
+
+impl Heatmap {
+    pub fn new(len: usize) -> Self {
+        Self {
+            reads: vec![0; len],
+            writes: vec![0; len],
+            executes: vec![0; len],
+            end: 0,
+        }
+    }
+
+    // Mega-CHIP memory above the heatmap is not recorded
+    pub fn record(&mut self, addr: usize, access: Access, frame: u64) {
+        let stamps = match access {
+            Access::Read => &mut self.reads,
+            Access::Write => &mut self.writes,
+        };
+        if addr < stamps.len() {
+            stamps[addr] = frame as u32 + 1;
+            self.end = self.end.max(addr + 1);
+        }
+    }
+
+    // both bytes of the instruction at pc
+    pub fn execute(&mut self, pc: usize, frame: u64) {
+        for addr in pc..pc + 2 {
+            if addr < self.executes.len() {
+                self.executes[addr] = frame as u32 + 1;
+                self.end = self.end.max(addr + 1);
+            }
+        }
+    }
+
+    // The used memory in whole 4K blocks, at least the 4K of the original CHIP-8
+    pub fn extent(&self) -> usize {
+        let end = self.end.max(1);
+        ((end + 0xFFF) & !0xFFF).min(self.reads.len())
+    }
+
+    // Hottest write, read and execute of the addresses as red, green and blue
+    pub fn color(&self, addrs: Range<usize>, frame: u64) -> (u8, u8, u8) {
+        let addrs = addrs.start.min(self.reads.len())..addrs.end.min(self.reads.len());
+        (
+            heat(&self.writes[addrs.clone()], frame),
+            heat(&self.reads[addrs.clone()], frame),
+            heat(&self.executes[addrs], frame),
+        )
+    }
+}
+
+fn heat(stamps: &[u32], frame: u64) -> u8 {
+    let last = match stamps.iter().max() {
+        Some(last) if *last > 0 => *last - 1,
+        _ => return 0,
+    };
+    let age = (frame as u32).saturating_sub(last).min(FADE_FRAMES);
+    (MIN_HEAT + (255 - MIN_HEAT) * (FADE_FRAMES - age) / FADE_FRAMES) as u8
+}
@@ -0,0 +1,296 @@
+// The keys of the emulator controls, remappable with a file. Only hotkeys that
+// are not fixed function keys can be changed.
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use super::debugger::Command;
+use super::keymap::Keymap;
+
+// F1 - F8, F10 and F11 are taken by the overlays, colors, the debugger and fullscreen
+const FIXED_KEYS: [HotkeyKey; 10] = [
+    HotkeyKey::F(1),
+    HotkeyKey::F(2),
+    HotkeyKey::F(3),
+    HotkeyKey::F(4),
+    HotkeyKey::F(5),
+    HotkeyKey::F(6),
+    HotkeyKey::F(7),
+    HotkeyKey::F(8),
+    HotkeyKey::F(10),
+    HotkeyKey::F(11),
+];
+
+// A key by its name on a US keyboard, the windows read it by position and the
+// terminal by the typed character
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HotkeyKey {
+    // a - z and 0 - 9
+    Char(char),
+    // F1 - F12
+    F(u8),
+    Tab,
+    Backspace,
+    Space,
+}
+
+impl FromStr for HotkeyKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_lowercase() || c.is_ascii_digit() => {
+                return Ok(HotkeyKey::Char(c))
+            }
+            _ => {}
+        }
+        let function_key = s
+            .strip_prefix('f')
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|n| (1..=12).contains(n));
+        match (s, function_key) {
+            (_, Some(n)) => Ok(HotkeyKey::F(n)),
+            ("tab", _) => Ok(HotkeyKey::Tab),
+            ("backspace", _) => Ok(HotkeyKey::Backspace),
+            ("space", _) => Ok(HotkeyKey::Space),
+            _ => bail!(
+                "Unknown key {}, expected a - z, 0 - 9, f1 - f12, tab, backspace or space",
+                s
+            ),
+        }
+    }
+}
+
+// Held together with 1 - 9 for the save state slots
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Modifier {
+    Shift,
+    Ctrl,
+    Alt,
+}
+
+impl FromStr for Modifier {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shift" => Ok(Modifier::Shift),
+            "ctrl" => Ok(Modifier::Ctrl),
+            "alt" => Ok(Modifier::Alt),
+            _ => bail!("Unknown modifier {}, expected shift, ctrl or alt", s),
+        }
+    }
+}
+
+// Loaded from a file with one hotkey per line:
+//   pause space
+//   save-state alt
+// A - turns a hotkey off. Hotkeys missing from the file keep their defaults,
+// empty lines and lines starting with # are ignored.
+#[derive(Clone, Debug)]
+pub struct Hotkeys {
+    pub pause: Option<HotkeyKey>,
+    pub reset: Option<HotkeyKey>,
+    pub rewind: Option<HotkeyKey>,
+    // held
+    pub turbo: Option<HotkeyKey>,
+    pub slow_motion: Option<HotkeyKey>,
+    // starts and stops a GIF recording
+    pub record: Option<HotkeyKey>,
+    pub save_state: Option<Modifier>,
+    pub load_state: Option<Modifier>,
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self {
+            pause: Some(HotkeyKey::Char('p')),
+            reset: Some(HotkeyKey::F(12)),
+            rewind: Some(HotkeyKey::Backspace),
+            turbo: Some(HotkeyKey::Tab),
+            slow_motion: Some(HotkeyKey::Char('m')),
+            record: Some(HotkeyKey::F(9)),
+            save_state: Some(Modifier::Shift),
+            load_state: Some(Modifier::Ctrl),
+        }
+    }
+}
+
+impl Hotkeys {
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let input =
+            fs::read_to_string(path).with_context(|| format!("Could not read hotkeys {}", path))?;
+        Self::parse(&input).with_context(|| format!("Invalid hotkeys {}", path))
+    }
+
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let mut hotkeys = Hotkeys::default();
+        let lines = input
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        for (n, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let result = match fields[..] {
+                [name, key] => hotkeys.set(name, key),
+                _ => bail!("Line {} is not of the form <hotkey> <key>", n),
+            };
+            result.with_context(|| format!("In line {}", n))?;
+        }
+        Ok(hotkeys)
+    }
+
+    fn set(&mut self, name: &str, key: &str) -> Result<(), anyhow::Error> {
+        let parse_key = || match key {
+            "-" => Ok(None),
+            _ => key.parse::<HotkeyKey>().map(Some),
+        };
+        let parse_modifier = || match key {
+            "-" => Ok(None),
+            _ => key.parse::<Modifier>().map(Some),
+        };
+        match name {
+            "pause" => self.pause = parse_key()?,
+            "reset" => self.reset = parse_key()?,
+            "rewind" => self.rewind = parse_key()?,
+            "turbo" => self.turbo = parse_key()?,
+            "slow-motion" => self.slow_motion = parse_key()?,
+            "record" => self.record = parse_key()?,
+            "save-state" => self.save_state = parse_modifier()?,
+            "load-state" => self.load_state = parse_modifier()?,
+            _ => bail!(
+                "Unknown hotkey {}, expected pause, reset, rewind, turbo, slow-motion, record, \
+                 save-state or load-state",
+                name
+            ),
+        }
+        Ok(())
+    }
+
+    fn keys(&self) -> [(&'static str, Option<HotkeyKey>); 6] {
+        [
+            ("pause", self.pause),
+            ("reset", self.reset),
+            ("rewind", self.rewind),
+            ("turbo", self.turbo),
+            ("slow-motion", self.slow_motion),
+            ("record", self.record),
+        ]
+    }
+
+    // Hotkeys have to differ from each other, from the fixed hotkeys and from
+    // the keys of the CHIP-8 keypad in the keymap
+    pub fn check(&self, keymap: Keymap) -> Result<(), anyhow::Error> {
+        let keys = self.keys();
+        for (index, (name, key)) in keys.iter().enumerate() {
+            let key = match key {
+                Some(key) => *key,
+                None => continue,
+            };
+            if let Some(chip8_key) = keypad_key(key, keymap) {
+                bail!(
+                    "Hotkey {} is the CHIP-8 key {:X} of the keymap, set another key or - for it",
+                    name,
+                    chip8_key
+                );
+            }
+            if FIXED_KEYS.contains(&key) {
+                bail!("Hotkey {} is a key of a fixed hotkey", name);
+            }
+            if let Some((other, _)) = keys[index + 1..]
+                .iter()
+                .find(|(_, other)| *other == Some(key))
+            {
+                bail!("Hotkeys {} and {} have the same key", name, other);
+            }
+        }
+        if self.save_state.is_some() && self.save_state == self.load_state {
+            bail!("Hotkeys save-state and load-state have the same modifier");
+        }
+        Ok(())
+    }
+
+    // The default hotkeys without the ones that are CHIP-8 keys of the keymap,
+    // e.g. P in Colemak
+    pub fn for_keymap(keymap: Keymap) -> Self {
+        let mut hotkeys = Self::default();
+        for key in [
+            &mut hotkeys.pause,
+            &mut hotkeys.reset,
+            &mut hotkeys.rewind,
+            &mut hotkeys.turbo,
+            &mut hotkeys.slow_motion,
+            &mut hotkeys.record,
+        ] {
+            if matches!(key, Some(key) if keypad_key(*key, keymap).is_some()) {
+                *key = None;
+            }
+        }
+        hotkeys
+    }
+
+    // The hotkeys as keys of a window library or the terminal
+    pub fn bind<K>(&self, native: impl Fn(HotkeyKey) -> Option<K>) -> BoundHotkeys<K> {
+        let bind = |key: Option<HotkeyKey>| key.and_then(&native);
+        BoundHotkeys {
+            pause: bind(self.pause),
+            reset: bind(self.reset),
+            rewind: bind(self.rewind),
+            turbo: bind(self.turbo),
+            slow_motion: bind(self.slow_motion),
+            record: bind(self.record),
+            save_state: self.save_state,
+            load_state: self.load_state,
+        }
+    }
+}
+
+pub struct BoundHotkeys<K> {
+    pub pause: Option<K>,
+    pub reset: Option<K>,
+    pub rewind: Option<K>,
+    pub turbo: Option<K>,
+    pub slow_motion: Option<K>,
+    pub record: Option<K>,
+    pub save_state: Option<Modifier>,
+    pub load_state: Option<Modifier>,
+}
+
+impl<K> BoundHotkeys<K> {
+    // The save or load command of the digit of a slot pressed together with
+    // the modifier of save-state or load-state, the emulator reports the result
+    pub fn slot_command(&self, slot: u8, held: impl Fn(Modifier) -> bool) -> Option<Command> {
+        if matches!(self.save_state, Some(modifier) if held(modifier)) {
+            Some(Command::SaveState(slot))
+        } else if matches!(self.load_state, Some(modifier) if held(modifier)) {
+            Some(Command::LoadState(slot))
+        } else {
+            None
+        }
+    }
+
+    // The digits of the slots are also keypad keys, the frontends release the
+    // keypad while one of the modifiers is held so that the rom does not see them
+    pub fn slot_modifier_held(&self, held: impl Fn(Modifier) -> bool) -> bool {
+        [self.save_state, self.load_state]
+            .iter()
+            .flatten()
+            .any(|modifier| held(*modifier))
+    }
+}
+
+// The CHIP-8 key on the key in the characters of the keymap or at its position
+fn keypad_key(key: HotkeyKey, keymap: Keymap) -> Option<usize> {
+    let c = match key {
+        HotkeyKey::Char(c) => c,
+        _ => return None,
+    };
+    keymap
+        .chars()
+        .iter()
+        .position(|k| *k == c)
+        .or_else(|| keymap.positions().iter().position(|k| *k == c))
+}
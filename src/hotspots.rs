@@ -0,0 +1,48 @@
+use super::cpu::{Cpu, MEMSIZE};
+use super::disasm::disassemble;
+
+// Per address execution counts, shows where a rom spends its instructions
+pub struct Hotspots {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Hotspots {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; MEMSIZE],
+            total: 0,
+        }
+    }
+
+    // Called before every instruction
+    pub fn record(&mut self, pc: u16) {
+        self.counts[pc as usize] += 1;
+        self.total += 1;
+    }
+
+    // The most executed addresses with their instruction and share of all instructions
+    pub fn report(&self, cpu: &Cpu, top: usize) -> Vec<String> {
+        let mut addrs: Vec<usize> = (0..MEMSIZE).filter(|addr| self.counts[*addr] > 0).collect();
+        addrs.sort_by_key(|addr| std::cmp::Reverse(self.counts[*addr]));
+        addrs
+            .into_iter()
+            .take(top)
+            .map(|addr| {
+                let instr = u16::from_be_bytes([
+                    cpu.memory[addr],
+                    cpu.memory[(addr + 1) % cpu.memory.len()],
+                ]);
+                let count = self.counts[addr];
+                format!(
+                    "{:04X}  {:04X}  {:<20}  {:>12}  {:5.1}%",
+                    addr,
+                    instr,
+                    disassemble(instr),
+                    count,
+                    count as f64 * 100.0 / self.total as f64
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,77 @@
+// Keys of the keyboard for the CHIP-8 keys, as presets for keyboard layouts
+use std::str::FromStr;
+
+use anyhow::bail;
+
+// CHIP-8 keys as they are arranged on the COSMAC VIP keypad
+pub const KEYPAD: [[usize; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// The keypad is the 4x4 block at the left of the keyboard in all layouts but
+// natural, only the characters on the keys differ
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Keymap {
+    // 1234 / QWER / ASDF / ZXCV
+    Qwerty,
+    // &é"' / AZER / QSDF / WXCV
+    Azerty,
+    // 1234 / QWER / ASDF / YXCV
+    Qwertz,
+    // 1234 / QWFP / ARST / ZXCV
+    Colemak,
+    // the keys of the hex digits 0 - 9 and A - F
+    Natural,
+}
+
+impl FromStr for Keymap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qwerty" => Ok(Keymap::Qwerty),
+            "azerty" => Ok(Keymap::Azerty),
+            "qwertz" => Ok(Keymap::Qwertz),
+            "colemak" => Ok(Keymap::Colemak),
+            "natural" => Ok(Keymap::Natural),
+            _ => bail!(
+                "Unknown keymap {}, expected qwerty, azerty, qwertz, colemak or natural",
+                s
+            ),
+        }
+    }
+}
+
+impl Keymap {
+    // The characters typed with the keys for the CHIP-8 keys 0 - F in this
+    // layout, for the terminal and the minifb window, which only get characters
+    pub fn chars(self) -> [char; 16] {
+        let rows = match self {
+            Keymap::Qwerty => ["1234", "qwer", "asdf", "zxcv"],
+            Keymap::Azerty => ["&é\"'", "azer", "qsdf", "wxcv"],
+            Keymap::Qwertz => ["1234", "qwer", "asdf", "yxcv"],
+            Keymap::Colemak => ["1234", "qwfp", "arst", "zxcv"],
+            Keymap::Natural => ["123c", "456d", "789e", "a0bf"],
+        };
+        let mut chars = [' '; 16];
+        for (keys, row) in KEYPAD.iter().zip(rows.iter()) {
+            for (key, c) in keys.iter().zip(row.chars()) {
+                chars[*key] = c;
+            }
+        }
+        chars
+    }
+
+    // The keys for the CHIP-8 keys 0 - F by their position, named by the
+    // characters of a US keyboard, for the SDL2 and wgpu windows which read
+    // scancodes. All layouts but natural use the same block there.
+    pub fn positions(self) -> [char; 16] {
+        match self {
+            Keymap::Natural => self.chars(),
+            _ => Keymap::Qwerty.chars(),
+        }
+    }
+}
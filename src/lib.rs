@@ -0,0 +1,48 @@
+// The emulator core of ch8-rs and its frontends, the ch8-rs binary is the
+// command line around it. Embedders create a Cpu from a rom, press keys with
+// cpu.keyboard.set_keys, run it with step or run_frame and read cpu.display.
+// The sound goes to the audio device unless cpu.sound.set_backend replaces it.
+mod chip8x;
+pub mod config;
+pub mod cpu;
+mod crashdump;
+pub mod debugger;
+mod disasm;
+pub mod emulator;
+pub mod flags;
+pub mod fonts;
+pub mod frontend;
+pub mod gamepad;
+mod gif;
+#[cfg(feature = "wgpu-backend")]
+mod gpu;
+mod heatmap;
+pub mod hotkeys;
+mod hotspots;
+pub mod keymap;
+mod megachip;
+pub mod movie;
+mod opstats;
+mod overlay;
+pub mod palette;
+pub mod patch;
+mod perf;
+pub mod quirks;
+mod remote;
+pub mod render;
+mod rewind;
+pub mod rom;
+pub mod savestate;
+mod screenshot;
+#[cfg(feature = "sdl2")]
+mod sdl;
+pub mod sound;
+pub mod sprites;
+pub mod term;
+pub mod tui;
+mod video;
+pub mod wav;
+mod window;
+
+pub use cpu::{ClockMode, Cpu, Display, FrameOutput, Keyboard, VKey};
+pub use quirks::Quirks;
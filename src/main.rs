@@ -1,132 +1,112 @@
-mod cpu;
-mod emulator;
-mod perf;
-mod sound;
-use emulator::Emulator;
+use ch8::config::{self, read_file};
+use ch8::emulator::ExitReason;
+use ch8::movie::Movie;
+use ch8::{patch, rom, sprites};
 
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-use std::u32;
+use std::path::PathBuf;
 
-use anyhow::Context;
-use clap::{crate_version, crate_authors, crate_name, App, Arg};
-
-fn parse_colors(input: &str) -> [u32; 4] {
-    let mut colors = [0u32; 4];
-    for (i, ccode) in input.split(',').take(4).enumerate() {
-        colors[i] = u32::from_str_radix(ccode, 16).unwrap()
-    }
-    colors
-}
+use anyhow::{bail, Context};
 
 fn main() -> Result<(), anyhow::Error> {
-    let matches = App::new(crate_name!())
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about("Chip 8 emulator")
-        .arg(
-            Arg::with_name("rom_path")
-                .help("Path to rom file")
-                .required(true)
-                .index(1),
-        )
-        .arg(
-            Arg::with_name("debug")
-                .short("d")
-                .multiple(true)
-                .help("Turn debugging information on"),
-        )
-        .arg(
-            Arg::with_name("fps-limit")
-                .long("fps-limit")
-                .value_name("FPS")
-                .help("Limit loop that polls input and draws output")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("ips-limit")
-                .long("ips-limit")
-                .value_name("IPS")
-                .help("Limits instructions per second")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("ipf-limit")
-                .long("ipf-limit")
-                .value_name("IPF")
-                .help("Limits instructions per frame")
-                .takes_value(true)
-                .conflicts_with("ips-limit")
-                .requires("fps-limit"),
-        )
-        .arg(
-            Arg::with_name("colors")
-                .long("colors")
-                .value_name("COLORS")
-                .help(
-                    "Four or two colors provided as four or two 6 digit hex numbers separated with a comma. \
-                     For chip8 and super-chip8 programs, two colors can be specified, representing the background \
-                     and foreground colors.\n\
-                     Example: 000000,FFFFFF sets the background color to black and the foreground color to white.\n\
-                     For xo-chip programs, four colors can be provided for the four possible combinations \
-                     of values in the two drawing planes.\n\
-                     Example: 000000,FF0000,00FF00,0000FF sets the colors for \"background, plane1, plane2, both planes blended\" or \
-                     in other words: it sets the \"background, fill1, fill2, blend\" colors\n",
-                )
-                .takes_value(true)
-                .default_value("00AA4400,00FFAA00,00AAAAAA,00000000"),
-        )
-        .arg(
-            Arg::with_name("no-skip-frames")
-                .long("no-skip-frames")
-                .help("Do not skip frames - Frames are skipped by default"),
-        )
-        .arg(
-            Arg::with_name("perf-counter")
-                .long("perf-counter")
-                .short("p")
-                .help("Show performance counter"),
-        )
-        .get_matches();
+    let matches = config::app().get_matches();
 
     let path = matches.value_of("rom_path").unwrap();
-    let debug = matches.occurrences_of("debug");
-    let fps_limit = matches
-        .value_of("fps-limit")
-        .and_then(|string| string.parse::<f64>().ok());
-    let mut ips_limit = matches
-        .value_of("ips-limit")
-        .and_then(|string| string.parse::<f64>().ok());
-    let ipf_limit = matches
-        .value_of("ipf-limit")
-        .and_then(|string| string.parse::<f64>().ok());
-    let skip_frames = !matches.is_present("no-skip-frames");
-
-    if let Some(ipf_limit) = ipf_limit {
-        if let Some(fps_limit) = fps_limit {
-            ips_limit = Some(fps_limit * ipf_limit);
+    let mut code = read_file(path).context("Could not read rom file")?;
+    if matches.is_present("info") {
+        rom::print_info(path, &code);
+        return Ok(());
+    }
+    if let Some(hash) = matches.value_of("verify-sha1") {
+        rom::verify_sha1(&code, hash, matches.is_present("sha1-warn-only"))?;
+    }
+    for patch_path in matches.values_of("patch").into_iter().flatten() {
+        let patch = read_file(patch_path)?;
+        code = patch::apply_patch(&code, &patch)
+            .with_context(|| format!("Could not apply patch {}", patch_path))?;
+    }
+    if let Some(image_path) = matches.value_of("sprites") {
+        let sprites = match matches.value_of("sprite-height") {
+            Some(height) => {
+                let n = height
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n < 16)
+                    .with_context(|| format!("Invalid sprite height {}, expected 0-15", height))?;
+                sprites::cut(&code, n)
+            }
+            None => sprites::find(&code),
+        };
+        if sprites.is_empty() {
+            bail!("No sprites found in the rom");
         }
+        for sprite in sprites.iter() {
+            match sprite.drawn_at {
+                Some(drawn_at) => println!(
+                    "{:04X}  {}x{}  drawn at {:04X}",
+                    sprite.addr, sprite.width, sprite.height, drawn_at
+                ),
+                None => println!("{:04X}  {}x{}", sprite.addr, sprite.width, sprite.height),
+            }
+        }
+        sprites::save_image(&PathBuf::from(image_path), &code, &sprites)?;
+        return Ok(());
     }
 
-    let colors = matches
-        .value_of("colors")
-        .map(|colors| parse_colors(colors));
+    let emulator = config::emulator(&matches, &code)?;
+    let diff_frames = matches
+        .value_of("diff-frames")
+        .unwrap()
+        .parse::<u64>()
+        .context("Invalid number of frames")?;
+    let diff_movie = matches
+        .value_of("diff-movie")
+        .map(Movie::load)
+        .transpose()?;
+    if let Some(list) = matches.value_of("diff-quirks") {
+        let mut other = emulator.quirks;
+        other.apply_list(list)?;
+        emulator.diff_quirks(code, other, diff_frames, diff_movie.as_ref())?;
+        return Ok(());
+    }
+    if matches.is_present("verify-determinism") {
+        emulator.verify_determinism(code, diff_frames, diff_movie.as_ref())?;
+        return Ok(());
+    }
 
-    let f = File::open(path).with_context(|| format!("Rom file {} is cannot be opened", path))?;
-    let mut buf_reader = BufReader::new(f);
-    let mut code = Vec::<u8>::new();
-    buf_reader
-        .read_to_end(&mut code)
-        .context("Could not read rom file to end")?;
+    if let Some(image_path) = matches.value_of("export-frame") {
+        let frames = matches
+            .value_of("export-frames")
+            .unwrap()
+            .parse::<u64>()
+            .context("Invalid number of frames")?;
+        let instructions = matches
+            .value_of("export-instructions")
+            .map(|count| count.parse::<u64>())
+            .transpose()
+            .context("Invalid number of instructions")?;
+        emulator.export_frame(code, &PathBuf::from(image_path), frames, instructions)?;
+        return Ok(());
+    }
 
-    let emulator = Emulator::new()
-        .with_skip_frames(skip_frames)
-        .with_fps_limit(fps_limit)
-        .with_ips_limit(ips_limit)
-        .with_colors(colors)
-        .with_debug(debug);
+    if let Some(movie_path) = matches.value_of("verify-replay") {
+        let movie = Movie::load(movie_path)?;
+        emulator.verify_replay(code, &movie)?;
+        return Ok(());
+    }
 
-    emulator.run(code)?;
+    let exit_reason = if matches.is_present("headless") {
+        let frames = matches
+            .value_of("headless-frames")
+            .map(|count| count.parse::<u64>())
+            .transpose()
+            .context("Invalid number of frames")?;
+        emulator.run_headless(&code, frames, |_| Ok(()))?
+    } else {
+        emulator.run(code)?
+    };
+    if exit_reason == ExitReason::ProgramExit {
+        // distinct status so scripts can tell a program exit from closing the window
+        std::process::exit(2);
+    }
     Ok(())
 }
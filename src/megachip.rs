@@ -0,0 +1,170 @@
+// Mega-CHIP screen: 256x192 pixels with 8 bit palette indices. Sprites are drawn
+// into a back buffer which is only shown when the program clears the screen.
+pub const MEGA_WIDTH: usize = 256;
+pub const MEGA_HEIGHT: usize = 192;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BlendMode {
+    Normal,
+    Alpha25,
+    Alpha50,
+    Alpha75,
+    Add,
+    Multiply,
+}
+
+impl BlendMode {
+    // 080n
+    pub fn from_nibble(n: u8) -> Self {
+        match n {
+            1 => BlendMode::Alpha25,
+            2 => BlendMode::Alpha50,
+            3 => BlendMode::Alpha75,
+            4 => BlendMode::Add,
+            5 => BlendMode::Multiply,
+            _ => BlendMode::Normal,
+        }
+    }
+
+    fn apply(self, dst: u32, src: u32) -> u32 {
+        let mut out = 0;
+        for shift in [0, 8, 16].iter() {
+            let d = (dst >> shift) & 0xFF;
+            let s = (src >> shift) & 0xFF;
+            let c = match self {
+                BlendMode::Normal => s,
+                BlendMode::Alpha25 => (d * 3 + s) / 4,
+                BlendMode::Alpha50 => (d + s) / 2,
+                BlendMode::Alpha75 => (d + s * 3) / 4,
+                BlendMode::Add => (d + s).min(0xFF),
+                BlendMode::Multiply => d * s / 0xFF,
+            };
+            out |= c << shift;
+        }
+        out
+    }
+}
+
+pub struct MegaScreen {
+    // palette index 0 is transparent
+    pub palette: [u32; 256],
+    pub sprite_width: usize,
+    pub sprite_height: usize,
+    pub blend: BlendMode,
+    pub collision_color: u8,
+    pub alpha: u8,
+    indices: Vec<u8>,
+    back: Vec<u32>,
+    front: Vec<u32>,
+}
+
+impl Default for MegaScreen {
+    fn default() -> Self {
+        let mut palette = [0u32; 256];
+        // font sprites are drawn with the last palette entry
+        palette[255] = 0x00FF_FFFF;
+        Self {
+            palette,
+            sprite_width: 0,
+            sprite_height: 0,
+            blend: BlendMode::Normal,
+            collision_color: 0,
+            alpha: 0xFF,
+            indices: vec![0; MEGA_WIDTH * MEGA_HEIGHT],
+            back: vec![0; MEGA_WIDTH * MEGA_HEIGHT],
+            front: vec![0; MEGA_WIDTH * MEGA_HEIGHT],
+        }
+    }
+}
+
+impl MegaScreen {
+    // 02nn, colors are stored as ARGB and fill the palette from index 1 on
+    pub fn load_palette(&mut self, colors: &[u8]) {
+        for (entry, argb) in self.palette[1..].iter_mut().zip(colors.chunks_exact(4)) {
+            *entry = (argb[1] as u32) << 16 | (argb[2] as u32) << 8 | argb[3] as u32;
+        }
+    }
+
+    fn plot(&mut self, x: usize, y: usize, index: u8) -> bool {
+        if x >= MEGA_WIDTH || y >= MEGA_HEIGHT {
+            return false;
+        }
+        let pos = y * MEGA_WIDTH + x;
+        let collision = self.collision_color != 0 && self.indices[pos] == self.collision_color;
+        self.indices[pos] = index;
+        self.back[pos] = self
+            .blend
+            .apply(self.back[pos], self.palette[index as usize]);
+        collision
+    }
+
+    // Draws a sprite of sprite_width x sprite_height palette indices
+    pub fn draw_sprite(&mut self, sprite: &[u8], x: u8, y: u8) -> bool {
+        let mut collision = false;
+        let width = self.sprite_width.max(1);
+        for (row, line) in sprite.chunks(width).enumerate() {
+            for (col, index) in line.iter().enumerate() {
+                if *index != 0 {
+                    collision |= self.plot(x as usize + col, y as usize + row, *index);
+                }
+            }
+        }
+        collision
+    }
+
+    // Font sprites keep their 1 bit per pixel format
+    pub fn draw_mono(&mut self, sprite: &[u8], x: u8, y: u8) -> bool {
+        let mut collision = false;
+        for (row, bits) in sprite.iter().enumerate() {
+            for col in 0..8 {
+                if (bits >> (7 - col)) & 0x1 == 1 {
+                    collision |= self.plot(x as usize + col, y as usize + row, 0xFF);
+                }
+            }
+        }
+        collision
+    }
+
+    // Shifts the back buffer, positive values move the picture right or down
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        let indices = self.indices.clone();
+        let back = self.back.clone();
+        for y in 0..MEGA_HEIGHT {
+            for x in 0..MEGA_WIDTH {
+                let src_x = x as isize - dx;
+                let src_y = y as isize - dy;
+                let pos = y * MEGA_WIDTH + x;
+                if src_x < 0
+                    || src_y < 0
+                    || src_x >= MEGA_WIDTH as isize
+                    || src_y >= MEGA_HEIGHT as isize
+                {
+                    self.indices[pos] = 0;
+                    self.back[pos] = 0;
+                } else {
+                    let src = src_y as usize * MEGA_WIDTH + src_x as usize;
+                    self.indices[pos] = indices[src];
+                    self.back[pos] = back[src];
+                }
+            }
+        }
+    }
+
+    // 00E0 shows the finished back buffer and starts a new one
+    pub fn present_and_clear(&mut self) {
+        let alpha = self.alpha as u32;
+        for (front, back) in self.front.iter_mut().zip(self.back.iter()) {
+            *front = BlendMode::Multiply.apply(*back, alpha << 16 | alpha << 8 | alpha);
+        }
+        for pixel in self.indices.iter_mut() {
+            *pixel = 0;
+        }
+        for pixel in self.back.iter_mut() {
+            *pixel = 0;
+        }
+    }
+
+    pub fn to_buf(&self) -> Vec<u32> {
+        self.front.clone()
+    }
+}
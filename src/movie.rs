@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use super::cpu::{Cpu, VKey};
+
+// frames between two state checksums in recorded movies
+const CHECK_INTERVAL: u64 = 60;
+
+// Input movie, stored as text with one entry per line:
+//   ch8-movie 1
+//   ipf <instructions per frame>
+//   seed <random seed>                      optional, seeds the random number generator
+//   keys <frame> <key bitmask as hex>       key state from the start of the frame on
+//   check <frame> <state checksum as hex>   state checksum at the end of the frame
+// Empty lines and lines starting with # are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct Movie {
+    pub ipf: u64,
+    pub seed: Option<u64>,
+    pub keys: Vec<(u64, u16)>,
+    pub checks: Vec<(u64, u64)>,
+}
+
+impl Movie {
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let input =
+            fs::read_to_string(path).with_context(|| format!("Could not read movie {}", path))?;
+        Self::parse(&input).with_context(|| format!("Invalid movie file {}", path))
+    }
+
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let mut lines = input
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        match lines.next() {
+            Some((_, "ch8-movie 1")) => {}
+            _ => bail!("Missing \"ch8-movie 1\" header"),
+        }
+
+        let mut movie = Movie::default();
+        for (n, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                ["ipf", ipf] => {
+                    movie.ipf = ipf
+                        .parse()
+                        .with_context(|| format!("Invalid ipf in line {}", n))?;
+                }
+                ["seed", seed] => {
+                    movie.seed = Some(
+                        seed.parse()
+                            .with_context(|| format!("Invalid seed in line {}", n))?,
+                    );
+                }
+                ["keys", frame, mask] => {
+                    let frame = frame
+                        .parse()
+                        .with_context(|| format!("Invalid frame in line {}", n))?;
+                    let mask = u16::from_str_radix(mask, 16)
+                        .with_context(|| format!("Invalid key mask in line {}", n))?;
+                    movie.keys.push((frame, mask));
+                }
+                ["check", frame, checksum] => {
+                    let frame = frame
+                        .parse()
+                        .with_context(|| format!("Invalid frame in line {}", n))?;
+                    let checksum = u64::from_str_radix(checksum, 16)
+                        .with_context(|| format!("Invalid checksum in line {}", n))?;
+                    movie.checks.push((frame, checksum));
+                }
+                _ => bail!("Unknown entry in line {}: {}", n, line),
+            }
+        }
+        if movie.ipf == 0 {
+            bail!("Missing or zero ipf entry");
+        }
+        movie.keys.sort_by_key(|(frame, _)| *frame);
+        movie.checks.sort_by_key(|(frame, _)| *frame);
+        Ok(movie)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let mut output = format!("ch8-movie 1\nipf {}\n", self.ipf);
+        if let Some(seed) = self.seed {
+            output.push_str(&format!("seed {}\n", seed));
+        }
+        for (frame, mask) in self.keys.iter() {
+            output.push_str(&format!("keys {} {:04x}\n", frame, mask));
+        }
+        for (frame, checksum) in self.checks.iter() {
+            output.push_str(&format!("check {} {:016x}\n", frame, checksum));
+        }
+        fs::write(path, output).with_context(|| format!("Could not write movie {}", path.display()))
+    }
+}
+
+// Records the keys from the window into a movie or plays the keys of a movie back.
+// Keys only change at frame boundaries, so a replay executes the same instructions.
+pub struct MovieInput {
+    movie: Movie,
+    record_path: Option<PathBuf>,
+    next_keys: usize,
+    next_check: u64,
+    frame: Option<u64>,
+}
+
+impl MovieInput {
+    pub fn record(path: PathBuf, ipf: u64, seed: u64) -> Self {
+        Self {
+            movie: Movie {
+                ipf,
+                seed: Some(seed),
+                ..Movie::default()
+            },
+            record_path: Some(path),
+            next_keys: 0,
+            next_check: CHECK_INTERVAL,
+            frame: None,
+        }
+    }
+
+    pub fn replay(movie: Movie) -> Self {
+        Self {
+            movie,
+            record_path: None,
+            next_keys: 0,
+            next_check: CHECK_INTERVAL,
+            frame: None,
+        }
+    }
+
+    // Called before every instruction with the keys currently pressed in the window
+    pub fn update(&mut self, cpu: &mut Cpu, window_keys: &[VKey; 16]) {
+        let frame = cpu.frame();
+        if self.frame == Some(frame) {
+            return;
+        }
+        self.frame = Some(frame);
+        if self.record_path.is_some() {
+            if frame >= self.next_check {
+                // state at the end of the previous frame, as checked by --verify-replay
+                self.movie.checks.push((frame - 1, cpu.state_checksum()));
+                self.next_check = frame + CHECK_INTERVAL;
+            }
+            let mask = mask_from_keys(window_keys);
+            if self.movie.keys.last().map_or(0, |(_, last)| *last) != mask {
+                self.movie.keys.push((frame, mask));
+            }
+            cpu.keyboard.set_keys(*window_keys);
+        } else {
+            let keys = &self.movie.keys;
+            while self.next_keys < keys.len() && keys[self.next_keys].0 <= frame {
+                cpu.keyboard
+                    .set_keys(keys_from_mask(keys[self.next_keys].1));
+                self.next_keys += 1;
+            }
+        }
+    }
+
+    // Writes the recorded movie, does nothing for a replay
+    pub fn finish(&self) -> Result<(), anyhow::Error> {
+        match self.record_path.as_ref() {
+            Some(path) => {
+                self.movie.save(path)?;
+                println!("Movie written to {}", path.display());
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn mask_from_keys(keys: &[VKey; 16]) -> u16 {
+    keys.iter()
+        .enumerate()
+        .filter(|(_, key)| **key == VKey::Down)
+        .fold(0, |mask, (i, _)| mask | 1 << i)
+}
+
+pub fn keys_from_mask(mask: u16) -> [VKey; 16] {
+    let mut keys = [VKey::Up; 16];
+    for (i, key) in keys.iter_mut().enumerate() {
+        if (mask >> i) & 0x1 == 1 {
+            *key = VKey::Down;
+        }
+    }
+    keys
+}
@@ -0,0 +1,68 @@
+use super::disasm::{instruction_class, INSTRUCTIONS};
+
+// width of the longest histogram bar
+const BAR_WIDTH: u64 = 40;
+
+// Counts executed instruction words, classified when the report is printed
+pub struct OpcodeStats {
+    counts: Vec<u64>,
+}
+
+impl OpcodeStats {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; 1 << 16],
+        }
+    }
+
+    pub fn record(&mut self, instr: u16) {
+        self.counts[instr as usize] += 1;
+    }
+
+    // Histogram of the executed instruction classes and the ones that were never executed
+    pub fn report(&self) -> Vec<String> {
+        let mut classes = vec![0u64; INSTRUCTIONS.len()];
+        let mut unknown = 0;
+        for (instr, count) in self.counts.iter().enumerate().filter(|(_, c)| **c > 0) {
+            match instruction_class(instr as u16) {
+                Some(class) => classes[class] += count,
+                None => unknown += count,
+            }
+        }
+        let total: u64 = classes.iter().sum::<u64>() + unknown;
+        let max = classes.iter().copied().max().unwrap_or(0).max(1);
+
+        let mut executed: Vec<usize> = (0..classes.len()).filter(|c| classes[*c] > 0).collect();
+        executed.sort_by_key(|class| std::cmp::Reverse(classes[*class]));
+        let mut lines: Vec<String> = executed
+            .iter()
+            .map(|class| {
+                let count = classes[*class];
+                format!(
+                    "{:<20}  {:>12}  {:5.1}%  {}",
+                    INSTRUCTIONS[*class].2,
+                    count,
+                    count as f64 * 100.0 / total as f64,
+                    "#".repeat((count * BAR_WIDTH / max).max(1) as usize)
+                )
+            })
+            .collect();
+        if unknown > 0 {
+            lines.push(format!("{:<20}  {:>12}", "unknown", unknown));
+        }
+
+        lines.push(format!(
+            "Coverage: {} of {} instructions executed",
+            executed.len(),
+            INSTRUCTIONS.len()
+        ));
+        let missing: Vec<&str> = (0..classes.len())
+            .filter(|c| classes[*c] == 0)
+            .map(|c| INSTRUCTIONS[c].2)
+            .collect();
+        if !missing.is_empty() {
+            lines.push(format!("Not executed: {}", missing.join(", ")));
+        }
+        lines
+    }
+}
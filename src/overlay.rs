@@ -0,0 +1,252 @@
+// Text, the keypad and the sound icon drawn over the picture in the window
+use std::time::{Duration, Instant};
+
+use super::cpu::VKey;
+use super::keymap::KEYPAD;
+use super::render::darken;
+
+// 3x5 pixel glyphs, one row per byte with the left pixel in bit 2,
+// lowercase letters are drawn as uppercase and unknown characters as ?
+const GLYPHS: [(char, [u8; 5]); 60] = [
+    ('0', [7, 5, 5, 5, 7]),
+    ('1', [2, 6, 2, 2, 7]),
+    ('2', [7, 1, 7, 4, 7]),
+    ('3', [7, 1, 7, 1, 7]),
+    ('4', [5, 5, 7, 1, 1]),
+    ('5', [7, 4, 7, 1, 7]),
+    ('6', [7, 4, 7, 5, 7]),
+    ('7', [7, 1, 2, 2, 2]),
+    ('8', [7, 5, 7, 5, 7]),
+    ('9', [7, 5, 7, 1, 7]),
+    ('A', [2, 5, 7, 5, 5]),
+    ('B', [6, 5, 6, 5, 6]),
+    ('C', [3, 4, 4, 4, 3]),
+    ('D', [6, 5, 5, 5, 6]),
+    ('E', [7, 4, 6, 4, 7]),
+    ('F', [7, 4, 6, 4, 4]),
+    ('G', [3, 4, 5, 5, 3]),
+    ('H', [5, 5, 7, 5, 5]),
+    ('I', [7, 2, 2, 2, 7]),
+    ('J', [1, 1, 1, 5, 2]),
+    ('K', [5, 5, 6, 5, 5]),
+    ('L', [4, 4, 4, 4, 7]),
+    ('M', [5, 7, 7, 5, 5]),
+    ('N', [6, 5, 5, 5, 5]),
+    ('O', [2, 5, 5, 5, 2]),
+    ('P', [6, 5, 6, 4, 4]),
+    ('Q', [2, 5, 5, 6, 3]),
+    ('R', [6, 5, 6, 5, 5]),
+    ('S', [3, 4, 2, 1, 6]),
+    ('T', [7, 2, 2, 2, 2]),
+    ('U', [5, 5, 5, 5, 7]),
+    ('V', [5, 5, 5, 5, 2]),
+    ('W', [5, 5, 7, 7, 5]),
+    ('X', [5, 5, 2, 5, 5]),
+    ('Y', [5, 5, 2, 2, 2]),
+    ('Z', [7, 1, 2, 4, 7]),
+    (' ', [0, 0, 0, 0, 0]),
+    ('.', [0, 0, 0, 0, 2]),
+    (',', [0, 0, 0, 2, 4]),
+    (':', [0, 2, 0, 2, 0]),
+    ('-', [0, 0, 7, 0, 0]),
+    ('+', [0, 2, 7, 2, 0]),
+    ('=', [0, 7, 0, 7, 0]),
+    ('_', [0, 0, 0, 0, 7]),
+    ('/', [1, 1, 2, 4, 4]),
+    ('%', [5, 1, 2, 4, 5]),
+    ('(', [1, 2, 2, 2, 1]),
+    (')', [4, 2, 2, 2, 4]),
+    ('[', [3, 2, 2, 2, 3]),
+    (']', [6, 2, 2, 2, 6]),
+    ('!', [2, 2, 2, 0, 2]),
+    ('?', [6, 1, 2, 0, 2]),
+    ('#', [5, 7, 5, 7, 5]),
+    ('\'', [2, 2, 0, 0, 0]),
+    ('"', [5, 5, 0, 0, 0]),
+    ('&', [2, 5, 2, 5, 3]),
+    ('é', [1, 2, 7, 6, 7]),
+    ('<', [1, 2, 4, 2, 1]),
+    ('×', [0, 5, 2, 5, 0]),
+    ('♪', [3, 2, 2, 6, 6]),
+];
+
+const TEXT_COLOR: u32 = 0x00FFFFFF;
+const LABEL_COLOR: u32 = 0x00A0A0A0;
+const KEY_COLOR: u32 = 0x00404040;
+const PRESSED_COLOR: u32 = 0x00FFAA00;
+// brightness (0 - 256) of the picture behind panels
+const PANEL_BRIGHTNESS: u32 = 80;
+// how long a message stays on the screen
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+
+// Short messages of the hotkeys that disappear after a while
+pub struct Osd {
+    message: Option<(String, Instant)>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Self { message: None }
+    }
+
+    // Replaces the current message
+    pub fn show<S: Into<String>>(&mut self, text: S) {
+        self.message = Some((text.into(), Instant::now()));
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|(text, _)| text.as_str())
+    }
+
+    // Removes an expired message, true when the picture has to be drawn again
+    pub fn expire(&mut self) -> bool {
+        match &self.message {
+            Some((_, shown)) if shown.elapsed() >= MESSAGE_DURATION => {
+                self.message = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// The window buffer that overlays are drawn into
+pub struct Canvas<'a> {
+    pub buffer: &'a mut [u32],
+    pub width: usize,
+    pub height: usize,
+}
+
+impl<'a> Canvas<'a> {
+    pub fn new(buffer: &'a mut [u32], width: usize, height: usize) -> Self {
+        Self {
+            buffer,
+            width,
+            height,
+        }
+    }
+
+    // size of a pixel of the overlays, so that they grow with the window
+    pub fn scale(&self) -> usize {
+        (self.height / 180).max(1)
+    }
+
+    pub fn fill(&mut self, x: usize, y: usize, w: usize, h: usize, color: u32) {
+        for row in y.min(self.height)..(y + h).min(self.height) {
+            let start = row * self.width;
+            let line = &mut self.buffer[start + x.min(self.width)..start + (x + w).min(self.width)];
+            line.iter_mut().for_each(|pixel| *pixel = color);
+        }
+    }
+
+    // Darkens the picture behind a panel
+    pub fn panel(&mut self, x: usize, y: usize, w: usize, h: usize) {
+        for row in y.min(self.height)..(y + h).min(self.height) {
+            let start = row * self.width;
+            let line = &mut self.buffer[start + x.min(self.width)..start + (x + w).min(self.width)];
+            line.iter_mut()
+                .for_each(|pixel| *pixel = darken(*pixel, PANEL_BRIGHTNESS));
+        }
+    }
+
+    pub fn text(&mut self, x: usize, y: usize, text: &str, color: u32) {
+        let scale = self.scale();
+        for (n, c) in text.chars().enumerate() {
+            let glyph = glyph(c);
+            for (row, bits) in glyph.iter().enumerate() {
+                for column in 0..3 {
+                    if bits & (4 >> column) != 0 {
+                        let px = x + (n * 4 + column) * scale;
+                        let py = y + row * scale;
+                        self.fill(px, py, scale, scale, color);
+                    }
+                }
+            }
+        }
+    }
+
+    // Pixel size of a text, 3x5 per character with a pixel between characters
+    pub fn text_size(&self, text: &str) -> (usize, usize) {
+        let chars = text.chars().count();
+        let width = (chars * 4).saturating_sub(1);
+        (width * self.scale(), 5 * self.scale())
+    }
+
+    // Text on a panel in the top left corner
+    pub fn corner_text(&mut self, text: &str) {
+        let margin = 2 * self.scale();
+        self.text_box(margin, margin, text);
+    }
+
+    // Text on a panel in the bottom left corner
+    pub fn message(&mut self, text: &str) {
+        let scale = self.scale();
+        let height = self.text_size(text).1 + 4 * scale;
+        let margin = 2 * scale;
+        if height + margin <= self.height {
+            self.text_box(margin, self.height - height - margin, text);
+        }
+    }
+
+    // A note on a panel in the top right corner while the sound timer runs
+    pub fn sound_icon(&mut self, color: u32) {
+        let scale = self.scale();
+        let padding = 2 * scale;
+        let (width, _) = self.text_size("♪");
+        let x = self.width.saturating_sub(width + 2 * padding + 2 * scale);
+        self.text_box_colored(x, 2 * scale, "♪", color);
+    }
+
+    fn text_box(&mut self, x: usize, y: usize, text: &str) {
+        self.text_box_colored(x, y, text, TEXT_COLOR);
+    }
+
+    fn text_box_colored(&mut self, x: usize, y: usize, text: &str, color: u32) {
+        let padding = 2 * self.scale();
+        let (width, height) = self.text_size(text);
+        self.panel(x, y, width + 2 * padding, height + 2 * padding);
+        self.text(x + padding, y + padding, text, color);
+    }
+
+    // The 4x4 keypad in the bottom right corner, every key shows the CHIP-8 key
+    // and below it the key of the keyboard, pressed keys are highlighted
+    pub fn keypad(&mut self, keys: &[VKey; 16], labels: &[char; 16]) {
+        let scale = self.scale();
+        let (key_width, key_height, gap) = (9 * scale, 14 * scale, scale);
+        let panel_width = 4 * key_width + 5 * gap;
+        let panel_height = 4 * key_height + 5 * gap;
+        let margin = 4 * scale;
+        if panel_width + margin > self.width || panel_height + margin > self.height {
+            return;
+        }
+        let left = self.width - panel_width - margin;
+        let top = self.height - panel_height - margin;
+        self.panel(left, top, panel_width, panel_height);
+        for (row, line) in KEYPAD.iter().enumerate() {
+            for (column, key) in line.iter().enumerate() {
+                let x = left + gap + column * (key_width + gap);
+                let y = top + gap + row * (key_height + gap);
+                let pressed = keys[*key] == VKey::Down;
+                let (fill, text, label) = if pressed {
+                    (PRESSED_COLOR, 0, 0)
+                } else {
+                    (KEY_COLOR, TEXT_COLOR, LABEL_COLOR)
+                };
+                self.fill(x, y, key_width, key_height, fill);
+                self.text(x + 3 * scale, y + 2 * scale, &format!("{:X}", key), text);
+                let host = labels[*key].to_ascii_uppercase().to_string();
+                self.text(x + 3 * scale, y + 8 * scale, &host, label);
+            }
+        }
+    }
+}
+
+fn glyph(c: char) -> [u8; 5] {
+    let c = c.to_ascii_uppercase();
+    GLYPHS
+        .iter()
+        .find(|(glyph, _)| *glyph == c)
+        .or_else(|| GLYPHS.iter().find(|(glyph, _)| *glyph == '?'))
+        .map(|(_, rows)| *rows)
+        .unwrap_or([0; 5])
+}
@@ -0,0 +1,169 @@
+use std::fs;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use super::cpu::DEFAULT_COLORS;
+
+// Built-in palettes as background, fill1, fill2 and blend. The ones for color
+// blindness take colors of the Okabe-Ito palette that differ in brightness as
+// well, so that all four colors stay apart in a simulation of the deficiency.
+pub const PRESETS: [(&str, [u32; 4]); 5] = [
+    ("default", DEFAULT_COLORS),
+    ("octo", [0x00996600, 0x00FFCC00, 0x00FF6600, 0x00662200]),
+    (
+        "deuteranopia",
+        [0x00000000, 0x00E69F00, 0x000072B2, 0x00FFFFFF],
+    ),
+    (
+        "protanopia",
+        [0x00000000, 0x00F0E442, 0x000072B2, 0x00FFFFFF],
+    ),
+    (
+        "tritanopia",
+        [0x00000000, 0x00009E73, 0x00D55E00, 0x00FFFFFF],
+    ),
+];
+
+// buzzer color of Octo, used by --sound-indicator when the palette has none
+pub const BUZZER_COLOR: u32 = 0x00FFAA00;
+
+// Display colors loaded from a file, either as text with one color per line:
+//   background 000000
+//   fill1 FFFFFF
+//   fill2 FF0000
+//   blend 00FF00
+//   buzzer FFAA00      shown around the display while the sound timer runs
+//   quiet 000000       shown around the display otherwise
+// or as the JSON options of an Octo cartridge with backgroundColor, fillColor,
+// fillColor2, blendColor, buzzColor and quietColor, also inside the options of a
+// shared Octo program. Colors are hex numbers with an
+// optional leading #, colors missing from the file keep their defaults. Empty
+// lines and lines starting with # are ignored.
+#[derive(Copy, Clone, Debug)]
+pub struct Palette {
+    pub colors: [u32; 4],
+    pub buzzer: Option<u32>,
+    pub quiet: Option<u32>,
+}
+
+// The color options of Octo, all other options are ignored
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OctoColors {
+    background_color: Option<String>,
+    fill_color: Option<String>,
+    fill_color2: Option<String>,
+    blend_color: Option<String>,
+    buzz_color: Option<String>,
+    quiet_color: Option<String>,
+}
+
+// the options on their own or in a shared program
+#[derive(Deserialize)]
+struct OctoFile {
+    #[serde(flatten)]
+    colors: OctoColors,
+    options: Option<OctoColors>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            colors: DEFAULT_COLORS,
+            buzzer: None,
+            quiet: None,
+        }
+    }
+}
+
+impl Palette {
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let input =
+            fs::read_to_string(path).with_context(|| format!("Could not read palette {}", path))?;
+        let palette = if input.trim_start().starts_with('{') {
+            Self::parse_octo(&input)
+        } else {
+            Self::parse(&input)
+        };
+        palette.with_context(|| format!("Invalid palette file {}", path))
+    }
+
+    pub fn preset(name: &str) -> Result<Self, anyhow::Error> {
+        match PRESETS.iter().find(|(preset, _)| *preset == name) {
+            Some((_, colors)) => Ok(Palette {
+                colors: *colors,
+                ..Palette::default()
+            }),
+            None => bail!("Unknown palette {}", name),
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let mut palette = Palette::default();
+        let lines = input
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        for (n, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [name, color] => {
+                    let color = parse_color(color).with_context(|| format!("In line {}", n))?;
+                    palette
+                        .set(name, color)
+                        .with_context(|| format!("In line {}", n))?;
+                }
+                _ => bail!("Line {} is not of the form <name> <color>", n),
+            }
+        }
+        Ok(palette)
+    }
+
+    pub fn parse_octo(input: &str) -> Result<Self, anyhow::Error> {
+        let file: OctoFile = serde_json::from_str(input).context("Invalid JSON")?;
+        let mut palette = Palette::default();
+        for colors in [Some(file.colors), file.options].iter().flatten() {
+            let options = [
+                ("background", "backgroundColor", &colors.background_color),
+                ("fill1", "fillColor", &colors.fill_color),
+                ("fill2", "fillColor2", &colors.fill_color2),
+                ("blend", "blendColor", &colors.blend_color),
+                ("buzzer", "buzzColor", &colors.buzz_color),
+                ("quiet", "quietColor", &colors.quiet_color),
+            ];
+            for (name, key, value) in options.iter() {
+                if let Some(value) = value {
+                    let color = parse_color(value).with_context(|| format!("In option {}", key))?;
+                    palette.set(name, color)?;
+                }
+            }
+        }
+        Ok(palette)
+    }
+
+    fn set(&mut self, name: &str, color: u32) -> Result<(), anyhow::Error> {
+        match name {
+            "background" => self.colors[0] = color,
+            "fill1" => self.colors[1] = color,
+            "fill2" => self.colors[2] = color,
+            "blend" => self.colors[3] = color,
+            "buzzer" => self.buzzer = Some(color),
+            "quiet" => self.quiet = Some(color),
+            _ => bail!(
+                "Unknown color {}, expected one of background, fill1, fill2, blend, buzzer, quiet",
+                name
+            ),
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_color(input: &str) -> Result<u32, anyhow::Error> {
+    let digits = input.trim_start_matches('#');
+    match u32::from_str_radix(digits, 16) {
+        Ok(color) if digits.len() == 6 => Ok(color),
+        _ => bail!("Invalid color {}, expected 6 hex digits", input),
+    }
+}
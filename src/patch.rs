@@ -0,0 +1,197 @@
+use std::convert::TryFrom;
+
+use anyhow::{bail, Context};
+
+use super::cpu::MEGA_MEMSIZE;
+
+// Applies an IPS or BPS patch to a rom, the format is detected by its header
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch).context("Invalid IPS patch")
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch).context("Invalid BPS patch")
+    } else {
+        bail!("Unknown patch format, expected an IPS or BPS file")
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], anyhow::Error> {
+        if self.pos + n > self.data.len() {
+            bail!("Unexpected end of patch at offset {}", self.pos);
+        }
+        let slice = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<usize, anyhow::Error> {
+        Ok(self
+            .bytes(n)?
+            .iter()
+            .fold(0usize, |acc, byte| acc << 8 | *byte as usize))
+    }
+
+    // BPS variable length integer
+    fn varint(&mut self) -> Result<u64, anyhow::Error> {
+        let mut data = 0u64;
+        let mut shift = 1u64;
+        loop {
+            let x = self.bytes(1)?[0] as u64;
+            data = data
+                .checked_add((x & 0x7F) * shift)
+                .context("Variable length integer overflow")?;
+            if x & 0x80 != 0 {
+                break;
+            }
+            shift <<= 7;
+            data += shift;
+        }
+        Ok(data)
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader::new(patch, 5);
+    loop {
+        let offset = reader.uint(3)?;
+        if offset == 0x454F46 {
+            // "EOF" marker, optionally followed by a truncation size
+            if let Ok(size) = reader.uint(3) {
+                out.truncate(size);
+            }
+            return Ok(out);
+        }
+        let size = reader.uint(2)?;
+        let (size, data) = if size == 0 {
+            // run length encoded record
+            let size = reader.uint(2)?;
+            (size, vec![reader.bytes(1)?[0]; size])
+        } else {
+            (size, reader.bytes(size)?.to_vec())
+        };
+        if out.len() < offset + size {
+            out.resize(offset + size, 0);
+        }
+        out[offset..offset + size].copy_from_slice(&data);
+    }
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    if patch.len() < 4 + 12 {
+        bail!("Patch is too short");
+    }
+    let footer = patch.len() - 12;
+    let mut reader = Reader::new(patch, footer);
+    let source_crc = reader.bytes(4)?;
+    let target_crc = reader.bytes(4)?;
+    let patch_crc = reader.bytes(4)?;
+    if crc32(&patch[..footer + 8]).to_le_bytes() != patch_crc {
+        bail!("Patch checksum mismatch, the patch file is corrupted");
+    }
+    if crc32(rom).to_le_bytes() != source_crc {
+        bail!("Rom checksum mismatch, the patch was made for a different rom");
+    }
+
+    let mut reader = Reader::new(&patch[..footer], 4);
+    let source_size = reader.varint()? as usize;
+    let target_size = reader.varint()? as usize;
+    let metadata_size = reader.varint()? as usize;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        bail!(
+            "Rom size {} does not match expected size {}",
+            rom.len(),
+            source_size
+        );
+    }
+    // the sizes come from the patch, a rom larger than the memory cannot be loaded anyway
+    if target_size > MEGA_MEMSIZE {
+        bail!(
+            "Patched rom size {} is larger than the memory of {} bytes",
+            target_size,
+            MEGA_MEMSIZE
+        );
+    }
+
+    let mut out = Vec::<u8>::with_capacity(target_size);
+    let mut source_offset = 0i64;
+    let mut target_offset = 0i64;
+    while reader.pos < footer {
+        let data = reader.varint()?;
+        let length = (data >> 2) as usize + 1;
+        if length > target_size - out.len() {
+            bail!("Patch writes past the target size {}", target_size);
+        }
+        match data & 0x3 {
+            0 => {
+                // SourceRead
+                let start = out.len();
+                let slice = rom
+                    .get(start..start + length)
+                    .context("SourceRead out of bounds")?;
+                out.extend_from_slice(slice);
+            }
+            1 => {
+                // TargetRead
+                out.extend_from_slice(reader.bytes(length)?);
+            }
+            2 => {
+                // SourceCopy
+                source_offset += signed(reader.varint()?);
+                let start = usize::try_from(source_offset).context("SourceCopy out of bounds")?;
+                let slice = rom
+                    .get(start..start + length)
+                    .context("SourceCopy out of bounds")?;
+                out.extend_from_slice(slice);
+                source_offset += length as i64;
+            }
+            _ => {
+                // TargetCopy, byte by byte because source and destination may overlap
+                target_offset += signed(reader.varint()?);
+                for _ in 0..length {
+                    let index =
+                        usize::try_from(target_offset).context("TargetCopy out of bounds")?;
+                    let byte = *out.get(index).context("TargetCopy out of bounds")?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size || crc32(&out).to_le_bytes() != target_crc {
+        bail!("Patched rom does not match the expected result");
+    }
+    Ok(out)
+}
+
+fn signed(data: u64) -> i64 {
+    let value = (data >> 1) as i64;
+    if data & 1 == 1 {
+        -value
+    } else {
+        value
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
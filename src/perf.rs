@@ -14,25 +14,33 @@ pub struct PerfLimiter {
 
 impl PerfLimiter {
     pub fn new(fps_limit: Option<f64>) -> Self {
+        let time = Instant::now();
+        let mut limiter = Self {
+            last_check: time,
+            last_fps_check: time,
+            fps_limit: 0.0,
+            counter: 0,
+            last_counter: 0,
+            every_nths: 1,
+            nths_counter: 0,
+        };
+        limiter.set_limit(fps_limit);
+        limiter
+    }
+
+    // Changes the rate while running, e.g. to fast forward
+    pub fn set_limit(&mut self, fps_limit: Option<f64>) {
         let fps_limit = match fps_limit {
             Some(fps_limit) => fps_limit,
             None => 0.0,
         };
-        let time = Instant::now();
-        let every_nths = if fps_limit as u64 >= 100 {
+        self.fps_limit = fps_limit;
+        self.every_nths = if fps_limit as u64 >= 100 {
             fps_limit as u64 / 100
         } else {
             1
         };
-        Self {
-            last_check: time,
-            last_fps_check: time,
-            fps_limit,
-            counter: 0,
-            last_counter: 0,
-            every_nths,
-            nths_counter: 0,
-        }
+        self.nths_counter = 0;
     }
 
     pub fn get_fps(&mut self) -> f64 {
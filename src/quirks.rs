@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+// Behaviors that differ between CHIP-8 interpreters, many roms only run
+// correctly with a specific combination
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    // Fx55/Fx65 leave I pointing behind the last register
+    pub load_store_increment_i: bool,
+    // Bnnn jumps to xnn + Vx instead of nnn + V0
+    pub jump_with_vx: bool,
+    // sprites are cut off at the screen edge instead of wrapping around
+    pub clip_sprites: bool,
+    // 8xy1/8xy2/8xy3 reset VF to 0
+    pub vf_reset: bool,
+    // Dxyn waits for the next 60 Hz tick, limiting drawing to one sprite per frame
+    pub display_wait: bool,
+    // Fx0A returns when the key is released instead of when it is pressed
+    pub key_wait_release: bool,
+    // Fx1E sets VF when I + Vx leaves the 12 bit address range (Amiga interpreter)
+    pub fx1e_carry: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increment_i: false,
+            jump_with_vx: false,
+            clip_sprites: false,
+            vf_reset: false,
+            display_wait: false,
+            key_wait_release: false,
+            fx1e_carry: false,
+        }
+    }
+}
+
+impl Quirks {
+    pub const NAMES: [&'static str; 8] = [
+        "shift-vy",
+        "increment-i",
+        "jump-vx",
+        "clip",
+        "vf-reset",
+        "display-wait",
+        "key-release",
+        "fx1e-carry",
+    ];
+
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), anyhow::Error> {
+        let quirk = match name {
+            "shift-vy" => &mut self.shift_uses_vy,
+            "increment-i" => &mut self.load_store_increment_i,
+            "jump-vx" => &mut self.jump_with_vx,
+            "clip" => &mut self.clip_sprites,
+            "vf-reset" => &mut self.vf_reset,
+            "display-wait" => &mut self.display_wait,
+            "key-release" => &mut self.key_wait_release,
+            "fx1e-carry" => &mut self.fx1e_carry,
+            _ => bail!(
+                "Unknown quirk {}, expected one of {}",
+                name,
+                Self::NAMES.join(", ")
+            ),
+        };
+        *quirk = enabled;
+        Ok(())
+    }
+
+    // Applies a comma separated list like "clip,no-shift-vy"
+    pub fn apply_list(&mut self, list: &str) -> Result<(), anyhow::Error> {
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name.strip_prefix("no-") {
+                Some(name) => self.set(name, false)?,
+                None => self.set(name, true)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Quirk and speed presets for the common interpreter variants
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Profile {
+    // original COSMAC VIP interpreter
+    Chip8,
+    // SUPER-CHIP 1.1 on the HP 48
+    Schip,
+    // Octo's XO-CHIP extension
+    XoChip,
+    // Revival Studios' Mega-CHIP, based on SUPER-CHIP
+    MegaChip,
+    // RCA's CHIP-8X for the VIP with the VP-590 color board
+    Chip8X,
+    // two-page hires CHIP-8 with a 64x64 screen
+    HiresChip8,
+}
+
+impl Profile {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Profile::Chip8 | Profile::Chip8X | Profile::HiresChip8 => Quirks {
+                shift_uses_vy: true,
+                load_store_increment_i: true,
+                jump_with_vx: false,
+                clip_sprites: true,
+                vf_reset: true,
+                display_wait: true,
+                key_wait_release: true,
+                fx1e_carry: false,
+            },
+            Profile::Schip | Profile::MegaChip => Quirks {
+                shift_uses_vy: false,
+                load_store_increment_i: false,
+                jump_with_vx: true,
+                clip_sprites: true,
+                vf_reset: false,
+                display_wait: false,
+                key_wait_release: false,
+                fx1e_carry: false,
+            },
+            Profile::XoChip => Quirks {
+                shift_uses_vy: true,
+                load_store_increment_i: true,
+                jump_with_vx: false,
+                clip_sprites: false,
+                vf_reset: false,
+                display_wait: false,
+                key_wait_release: false,
+                fx1e_carry: false,
+            },
+        }
+    }
+
+    // Guesses the variant a rom was written for from the opcodes it uses, only
+    // instructions reachable from 0x200 count so sprite data cannot match
+    pub fn detect(code: &[u8]) -> Self {
+        // hires roms start with a jump over the interpreter patch
+        if code.starts_with(&[0x12, 0x60]) {
+            return Profile::HiresChip8;
+        }
+        // CHIP-8X roms step the background color first, elsewhere 02A0 may be sprite data
+        if code.starts_with(&[0x02, 0xA0]) {
+            return Profile::Chip8X;
+        }
+        let word = |addr: usize| match addr.checked_sub(0x200) {
+            Some(offset) if offset + 1 < code.len() => {
+                Some((code[offset] as u16) << 8 | code[offset + 1] as u16)
+            }
+            _ => None,
+        };
+        let mut profile = Profile::Chip8;
+        let mut visited = HashSet::new();
+        let mut pending = vec![0x200];
+        while let Some(addr) = pending.pop() {
+            let instr = match word(addr) {
+                Some(instr) if visited.insert(addr) => instr,
+                _ => continue,
+            };
+            match instr {
+                // 0011 enables the Mega-CHIP mode
+                0x0011 => return Profile::MegaChip,
+                // F000 nnnn, 5xy2/5xy3, Fn01 plane select, F002 audio, Fx3A pitch
+                0xF000 | 0xF002 => return Profile::XoChip,
+                _ if instr & 0xF00F == 0x5002 || instr & 0xF00F == 0x5003 => {
+                    return Profile::XoChip
+                }
+                _ if instr & 0xF0FF == 0xF001 || instr & 0xF0FF == 0xF03A => {
+                    return Profile::XoChip
+                }
+                // 00FE/00FF resolution switch, 00Cn/00FB/00FC scrolling, Dxy0 16x16 sprites
+                0x00FE | 0x00FF | 0x00FB | 0x00FC => profile = Profile::Schip,
+                _ if instr & 0xFFF0 == 0x00C0 || instr & 0xF00F == 0xD000 => {
+                    profile = Profile::Schip
+                }
+                _ => {}
+            }
+            let nnn = (instr & 0x0FFF) as usize;
+            // skips jump over both words of F000 nnnn
+            let skip = if word(addr + 2) == Some(0xF000) {
+                addr + 6
+            } else {
+                addr + 4
+            };
+            match instr & 0xF000 {
+                0x1000 => pending.push(nnn),
+                0x2000 => pending.extend(&[nnn, addr + 2]),
+                // the target of Bnnn depends on a register, the walk ends there
+                0xB000 => {}
+                0x3000 | 0x4000 => pending.extend(&[addr + 2, skip]),
+                0x5000 | 0x9000 if instr & 0x000F == 0 => pending.extend(&[addr + 2, skip]),
+                0xE000 if instr & 0x00FF == 0x9E || instr & 0x00FF == 0xA1 => {
+                    pending.extend(&[addr + 2, skip])
+                }
+                _ => match instr {
+                    0x00EE | 0x00FD => {}
+                    0xF000 => pending.push(addr + 4),
+                    _ => pending.push(addr + 2),
+                },
+            }
+        }
+        profile
+    }
+
+    // instructions per 60 Hz frame
+    pub fn ipf(self) -> f64 {
+        match self {
+            Profile::Chip8 | Profile::Chip8X | Profile::HiresChip8 => 15.0,
+            Profile::Schip => 30.0,
+            Profile::XoChip => 1000.0,
+            Profile::MegaChip => 1000.0,
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chip8" => Ok(Profile::Chip8),
+            "schip" => Ok(Profile::Schip),
+            "xochip" => Ok(Profile::XoChip),
+            "megachip" => Ok(Profile::MegaChip),
+            "chip8x" => Ok(Profile::Chip8X),
+            "hires" => Ok(Profile::HiresChip8),
+            _ => bail!(
+                "Unknown profile {}, expected chip8, schip, xochip, megachip, chip8x or hires",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Profile::Chip8 => "chip8",
+            Profile::Schip => "schip",
+            Profile::XoChip => "xochip",
+            Profile::MegaChip => "megachip",
+            Profile::Chip8X => "chip8x",
+            Profile::HiresChip8 => "hires",
+        };
+        write!(f, "{}", name)
+    }
+}
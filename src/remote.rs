@@ -0,0 +1,120 @@
+// CHIP-8 keys pressed by other programs over TCP, for automated tests, bots or
+// "Twitch plays" setups
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+
+use super::cpu::VKey;
+
+// shorter presses are lengthened, the emulation only sees keys that stay down
+// for a few frames
+const MIN_PRESS: Duration = Duration::from_millis(50);
+
+// Every client sends one event per line:
+//   down 5
+//   up 5
+// The key is a hex digit, empty lines and lines starting with # are ignored and
+// invalid lines are answered with a line starting with "error". The events of
+// all clients are merged, the keys of a client are released when it disconnects.
+pub struct RemoteKeys {
+    rx: Receiver<(usize, VKey)>,
+    keys: [VKey; 16],
+    pressed_at: [Option<Instant>; 16],
+    // a release that waits for MIN_PRESS, later events wait behind it
+    pending: Option<(usize, VKey)>,
+}
+
+impl RemoteKeys {
+    // Listens on the address in a thread, the thread ends with the program
+    pub fn listen(addr: &str) -> Result<Self, anyhow::Error> {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Could not listen on {}", addr))?;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let tx = tx.clone();
+                thread::spawn(move || read_client(stream, tx));
+            }
+        });
+        Ok(Self {
+            rx,
+            keys: [VKey::Up; 16],
+            pressed_at: [None; 16],
+            pending: None,
+        })
+    }
+
+    // Applies the events since the last call and adds the held keys to keys
+    pub fn merge(&mut self, keys: &mut [VKey; 16]) {
+        while let Some((key, state)) = self.pending.take().or_else(|| self.rx.try_recv().ok()) {
+            let held = self.pressed_at[key].map(|at| at.elapsed());
+            if state == VKey::Up && matches!(held, Some(held) if held < MIN_PRESS) {
+                self.pending = Some((key, state));
+                break;
+            }
+            if state == VKey::Down && self.keys[key] == VKey::Up {
+                self.pressed_at[key] = Some(Instant::now());
+            } else if state == VKey::Up {
+                self.pressed_at[key] = None;
+            }
+            self.keys[key] = state;
+        }
+        for (key, state) in keys.iter_mut().zip(self.keys.iter()) {
+            if *state == VKey::Down {
+                *key = VKey::Down;
+            }
+        }
+    }
+}
+
+fn read_client(stream: TcpStream, tx: Sender<(usize, VKey)>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(..) => return,
+    };
+    let mut held = [false; 16];
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(..) => break,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_event(line) {
+            Ok((key, state)) => {
+                held[key] = state == VKey::Down;
+                if tx.send((key, state)).is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                let reply = format!("error: {:#}\n", err);
+                if writer.write_all(reply.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    for (key, _) in held.iter().enumerate().filter(|(_, held)| **held) {
+        let _ = tx.send((key, VKey::Up));
+    }
+}
+
+fn parse_event(line: &str) -> Result<(usize, VKey), anyhow::Error> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (state, key) = match fields[..] {
+        ["down", key] => (VKey::Down, key),
+        ["up", key] => (VKey::Up, key),
+        _ => bail!("Expected down <key> or up <key>"),
+    };
+    match u8::from_str_radix(key, 16) {
+        Ok(key) if key < 16 => Ok((key as usize, state)),
+        _ => bail!("Invalid key {}, expected a hex digit", key),
+    }
+}
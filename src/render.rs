@@ -0,0 +1,498 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::bail;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TextureFilter {
+    // sharp pixels
+    Nearest,
+    // bilinear interpolation between neighbouring pixels
+    Linear,
+}
+
+impl TextureFilter {
+    pub fn next(self) -> Self {
+        match self {
+            TextureFilter::Nearest => TextureFilter::Linear,
+            TextureFilter::Linear => TextureFilter::Nearest,
+        }
+    }
+}
+
+impl FromStr for TextureFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(TextureFilter::Nearest),
+            "linear" => Ok(TextureFilter::Linear),
+            _ => bail!("Unknown texture filter {}, expected nearest or linear", s),
+        }
+    }
+}
+
+impl fmt::Display for TextureFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TextureFilter::Nearest => "nearest",
+            TextureFilter::Linear => "linear",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Post-processing of the scaled display that imitates old screens
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PostFilter {
+    None,
+    // darker lower half of every display row
+    Scanlines,
+    // dark lines between the display pixels
+    Grid,
+    // bulging picture of a curved CRT
+    Curvature,
+}
+
+impl PostFilter {
+    pub fn next(self) -> Self {
+        match self {
+            PostFilter::None => PostFilter::Scanlines,
+            PostFilter::Scanlines => PostFilter::Grid,
+            PostFilter::Grid => PostFilter::Curvature,
+            PostFilter::Curvature => PostFilter::None,
+        }
+    }
+}
+
+impl FromStr for PostFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(PostFilter::None),
+            "scanlines" => Ok(PostFilter::Scanlines),
+            "grid" => Ok(PostFilter::Grid),
+            "curvature" => Ok(PostFilter::Curvature),
+            _ => bail!(
+                "Unknown filter {}, expected none, scanlines, grid or curvature",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for PostFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PostFilter::None => "none",
+            PostFilter::Scanlines => "scanlines",
+            PostFilter::Grid => "grid",
+            PostFilter::Curvature => "curvature",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// How the display fills the window
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Scaling {
+    // fill the whole window
+    Stretch,
+    // keep the pixels square, the rest of the window stays black
+    Aspect,
+    // like Aspect, but only scale by whole numbers for evenly sized pixels
+    Integer,
+}
+
+impl Scaling {
+    // left, top, width and height of the display in the window
+    fn viewport(
+        self,
+        src_width: usize,
+        src_height: usize,
+        width: usize,
+        height: usize,
+    ) -> (usize, usize, usize, usize) {
+        let factor = (width as f64 / src_width as f64).min(height as f64 / src_height as f64);
+        let factor = match self {
+            Scaling::Stretch => return (0, 0, width, height),
+            // a window smaller than the display cannot be scaled by a whole number
+            Scaling::Integer if factor >= 1.0 => factor.floor(),
+            Scaling::Integer | Scaling::Aspect => factor,
+        };
+        let scaled_width = ((src_width as f64 * factor).round() as usize)
+            .max(1)
+            .min(width);
+        let scaled_height = ((src_height as f64 * factor).round() as usize)
+            .max(1)
+            .min(height);
+        (
+            (width - scaled_width) / 2,
+            (height - scaled_height) / 2,
+            scaled_width,
+            scaled_height,
+        )
+    }
+}
+
+impl FromStr for Scaling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stretch" => Ok(Scaling::Stretch),
+            "aspect" => Ok(Scaling::Aspect),
+            "integer" => Ok(Scaling::Integer),
+            _ => bail!("Unknown scaling {}, expected stretch, aspect or integer", s),
+        }
+    }
+}
+
+// brightness (0 - 256) of the darkened scanlines and grid lines
+const LINE_BRIGHTNESS: u32 = 140;
+// strength of the barrel distortion
+const CURVATURE: f64 = 0.08;
+
+// Scales the emulated display to the size of the window
+pub struct Renderer {
+    pub filter: TextureFilter,
+    pub post_filter: PostFilter,
+    pub scaling: Scaling,
+    // color of the bars around the display
+    pub border: u32,
+    // the display at its size in the window
+    scaled: Vec<u32>,
+    post_buffer: Vec<u32>,
+    buffer: Vec<u32>,
+}
+
+impl Renderer {
+    pub fn new(filter: TextureFilter, post_filter: PostFilter, scaling: Scaling) -> Self {
+        Self {
+            filter,
+            post_filter,
+            scaling,
+            border: 0,
+            scaled: Vec::new(),
+            post_buffer: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn render(
+        &mut self,
+        src: &[u32],
+        src_width: usize,
+        src_height: usize,
+        width: usize,
+        height: usize,
+    ) -> &mut [u32] {
+        let (left, top, scaled_width, scaled_height) =
+            self.scaling.viewport(src_width, src_height, width, height);
+        let (w, h) = (scaled_width, scaled_height);
+        self.scaled.resize(w * h, 0);
+        match self.filter {
+            TextureFilter::Nearest => {
+                scale_nearest(src, src_width, src_height, &mut self.scaled, w, h)
+            }
+            TextureFilter::Linear => {
+                scale_linear(src, src_width, src_height, &mut self.scaled, w, h)
+            }
+        }
+        match self.post_filter {
+            PostFilter::None => {}
+            PostFilter::Scanlines => {
+                for (y, row) in self.scaled.chunks_mut(w).enumerate() {
+                    if (y * src_height * 2 / h) % 2 == 1 {
+                        row.iter_mut()
+                            .for_each(|pixel| *pixel = darken(*pixel, LINE_BRIGHTNESS));
+                    }
+                }
+            }
+            PostFilter::Grid => {
+                // the last destination pixel of every display pixel is darkened
+                let edge = |pos: usize, len: usize, src_len: usize| {
+                    pos * src_len / len != (pos + 1) * src_len / len
+                };
+                for (y, row) in self.scaled.chunks_mut(w).enumerate() {
+                    let edge_row = edge(y, h, src_height);
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        if edge_row || edge(x, w, src_width) {
+                            *pixel = darken(*pixel, LINE_BRIGHTNESS);
+                        }
+                    }
+                }
+            }
+            PostFilter::Curvature => {
+                curve(&self.scaled, &mut self.post_buffer, w, h);
+                std::mem::swap(&mut self.scaled, &mut self.post_buffer);
+            }
+        }
+        if (w, h) == (width, height) {
+            return &mut self.scaled;
+        }
+
+        // bars around the display
+        self.buffer.clear();
+        self.buffer.resize(width * height, self.border);
+        for (y, row) in self.scaled.chunks(w).enumerate() {
+            let start = (top + y) * width + left;
+            self.buffer[start..start + w].copy_from_slice(row);
+        }
+        &mut self.buffer
+    }
+}
+
+pub fn darken(color: u32, brightness: u32) -> u32 {
+    blend(0, color, brightness)
+}
+
+// Color changes for dim screens or a light background, in this order: invert,
+// gamma (values above 1 brighten the mid tones) and brightness
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ColorAdjust {
+    pub gamma: f64,
+    pub brightness: f64,
+    pub invert: bool,
+}
+
+impl Default for ColorAdjust {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            brightness: 1.0,
+            invert: false,
+        }
+    }
+}
+
+impl ColorAdjust {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // The new value of every value of a color channel
+    pub fn table(&self) -> [u8; 256] {
+        let mut table = [0u8; 256];
+        for (value, entry) in table.iter_mut().enumerate() {
+            let mut x = value as f64 / 255.0;
+            if self.invert {
+                x = 1.0 - x;
+            }
+            x = x.powf(1.0 / self.gamma) * self.brightness;
+            // the cast saturates values above 255
+            *entry = (x * 255.0).round() as u8;
+        }
+        table
+    }
+}
+
+pub fn adjust_color(table: &[u8; 256], color: u32) -> u32 {
+    let r = table[(color >> 16 & 0xFF) as usize] as u32;
+    let g = table[(color >> 8 & 0xFF) as usize] as u32;
+    let b = table[(color & 0xFF) as usize] as u32;
+    r << 16 | g << 8 | b
+}
+
+// Barrel distortion, every destination pixel samples the picture further out
+// the further it is from the center, the corners stay black
+fn curve(src: &[u32], dst: &mut Vec<u32>, width: usize, height: usize) {
+    dst.resize(width * height, 0);
+    for (y, row) in dst.chunks_mut(width).enumerate() {
+        let v = (y as f64 + 0.5) / height as f64 * 2.0 - 1.0;
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let u = (x as f64 + 0.5) / width as f64 * 2.0 - 1.0;
+            let su = u * (1.0 + CURVATURE * v * v);
+            let sv = v * (1.0 + CURVATURE * u * u);
+            *pixel = if su.abs() < 1.0 && sv.abs() < 1.0 {
+                let sx = ((su + 1.0) / 2.0 * width as f64) as usize;
+                let sy = ((sv + 1.0) / 2.0 * height as f64) as usize;
+                src[sy.min(height - 1) * width + sx.min(width - 1)]
+            } else {
+                0
+            };
+        }
+    }
+}
+
+// Averages the last displays of the cpu, a sprite that is drawn in every other
+// display shows up at half brightness instead of flickering
+pub struct FrameBlender {
+    count: usize,
+    frames: VecDeque<Vec<u32>>,
+}
+
+impl FrameBlender {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            frames: VecDeque::with_capacity(count),
+        }
+    }
+
+    // Replaces the display with the average of it and the previous displays
+    pub fn blend(&mut self, display: &mut [u32]) {
+        if self.frames.front().map(|frame| frame.len()) != Some(display.len()) {
+            // the resolution changed, older displays do not fit anymore
+            self.frames.clear();
+        }
+        let mut frame = if self.frames.len() == self.count {
+            self.frames.pop_front().unwrap()
+        } else {
+            Vec::new()
+        };
+        frame.clear();
+        frame.extend_from_slice(display);
+        self.frames.push_back(frame);
+
+        let count = self.frames.len() as u32;
+        for (index, pixel) in display.iter_mut().enumerate() {
+            let mut out = 0;
+            for shift in [0, 8, 16].iter() {
+                let sum: u32 = self
+                    .frames
+                    .iter()
+                    .map(|frame| (frame[index] >> shift) & 0xFF)
+                    .sum();
+                out |= (sum / count) << shift;
+            }
+            *pixel = out;
+        }
+    }
+}
+
+// Afterglow of a CRT, lit pixels light up at once and fade out over several frames,
+// which hides the flicker of sprites that are erased and drawn again
+pub struct Phosphor {
+    // share of the brightness that is left after one 60 Hz frame
+    decay: f64,
+    buffer: Vec<u32>,
+    width: usize,
+    height: usize,
+    fading: bool,
+    last_update: Instant,
+}
+
+impl Phosphor {
+    pub fn new(decay: f64) -> Self {
+        Self {
+            decay,
+            buffer: Vec::new(),
+            width: 0,
+            height: 0,
+            fading: false,
+            last_update: Instant::now(),
+        }
+    }
+
+    // Blends the display into the afterglow, the result is in buffer()
+    pub fn update(&mut self, src: &[u32], width: usize, height: usize) {
+        let now = Instant::now();
+        // a steady picture starts fading when the display changes
+        let frames = if self.fading {
+            (now - self.last_update).as_secs_f64() * 60.0
+        } else {
+            0.0
+        };
+        self.last_update = now;
+        if width != self.width || height != self.height {
+            // nothing to fade after a resolution change
+            self.buffer = src.to_vec();
+            self.width = width;
+            self.height = height;
+            self.fading = false;
+            return;
+        }
+        let weight = (self.decay.powf(frames) * 256.0) as u32;
+        self.fading = false;
+        for (pixel, new) in self.buffer.iter_mut().zip(src.iter()) {
+            let mut out = 0;
+            for shift in [0, 8, 16].iter() {
+                let old = (*pixel >> shift) & 0xFF;
+                let new = (*new >> shift) & 0xFF;
+                let channel = if new >= old {
+                    new
+                } else {
+                    (new * (256 - weight) + old * weight) >> 8
+                };
+                out |= channel << shift;
+            }
+            self.fading |= out != *new;
+            *pixel = out;
+        }
+    }
+
+    // true while a pixel is still fading out
+    pub fn is_fading(&self) -> bool {
+        self.fading
+    }
+
+    pub fn buffer(&self) -> &[u32] {
+        &self.buffer
+    }
+}
+
+pub fn scale_nearest(
+    src: &[u32],
+    src_width: usize,
+    src_height: usize,
+    dst: &mut [u32],
+    width: usize,
+    height: usize,
+) {
+    let columns: Vec<usize> = (0..width).map(|x| x * src_width / width).collect();
+    for (y, row) in dst.chunks_mut(width).enumerate() {
+        let src_row = &src[y * src_height / height * src_width..];
+        for (pixel, column) in row.iter_mut().zip(columns.iter()) {
+            *pixel = src_row[*column];
+        }
+    }
+}
+
+// Source position and weight (0 - 256) of the second sample for every destination pixel
+fn linear_samples(src_len: usize, len: usize) -> Vec<(usize, usize, u32)> {
+    (0..len)
+        .map(|pos| {
+            let src_pos = (pos as f64 + 0.5) * src_len as f64 / len as f64 - 0.5;
+            let src_pos = src_pos.max(0.0);
+            let first = (src_pos as usize).min(src_len - 1);
+            let second = (first + 1).min(src_len - 1);
+            let weight = ((src_pos - first as f64) * 256.0) as u32;
+            (first, second, weight.min(256))
+        })
+        .collect()
+}
+
+fn blend(a: u32, b: u32, weight: u32) -> u32 {
+    let mut out = 0;
+    for shift in [0, 8, 16].iter() {
+        let ca = (a >> shift) & 0xFF;
+        let cb = (b >> shift) & 0xFF;
+        out |= ((ca * (256 - weight) + cb * weight) >> 8) << shift;
+    }
+    out
+}
+
+fn scale_linear(
+    src: &[u32],
+    src_width: usize,
+    src_height: usize,
+    dst: &mut [u32],
+    width: usize,
+    height: usize,
+) {
+    let columns = linear_samples(src_width, width);
+    let rows = linear_samples(src_height, height);
+    for (row, (y0, y1, wy)) in dst.chunks_mut(width).zip(rows.iter()) {
+        let line0 = &src[y0 * src_width..];
+        let line1 = &src[y1 * src_width..];
+        for (pixel, (x0, x1, wx)) in row.iter_mut().zip(columns.iter()) {
+            let top = blend(line0[*x0], line0[*x1], *wx);
+            let bottom = blend(line1[*x0], line1[*x1], *wx);
+            *pixel = blend(top, bottom, *wy);
+        }
+    }
+}
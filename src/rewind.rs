@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+
+use anyhow::bail;
+
+use super::cpu::{ClockMode, Cpu, SavedState, VKey};
+
+// frames between two saved states
+const INTERVAL: u64 = 15;
+// states are kept for the last 60 seconds
+const CAPACITY: usize = 60 * 60 / INTERVAL as usize;
+
+struct Entry {
+    frame: u64,
+    keys: [VKey; 16],
+    state: SavedState,
+}
+
+// Ring buffer of periodic machine states to step back in time, together with
+// the key changes since the oldest state to execute forward to any frame again
+pub struct Rewind {
+    states: VecDeque<Entry>,
+    // clock step from which on the keys were pressed
+    keys: VecDeque<(u64, [VKey; 16])>,
+    last_keys: [VKey; 16],
+    last_frame: Option<u64>,
+}
+
+impl Rewind {
+    pub fn new() -> Self {
+        Self {
+            states: VecDeque::with_capacity(CAPACITY),
+            keys: VecDeque::new(),
+            last_keys: [VKey::Up; 16],
+            last_frame: None,
+        }
+    }
+
+    // Called before every instruction
+    pub fn record_keys(&mut self, cpu: &Cpu) {
+        if cpu.keyboard.keys != self.last_keys {
+            self.last_keys = cpu.keyboard.keys;
+            self.keys.push_back((cpu.clock_steps, cpu.keyboard.keys));
+        }
+    }
+
+    // Saves the state every INTERVAL frames, called after every instruction
+    pub fn record(&mut self, cpu: &Cpu) {
+        // a 16 MB Mega-CHIP memory is too large to keep around
+        if cpu.display.mega.is_some() {
+            return;
+        }
+        let frame = cpu.frame();
+        if let Some(last_frame) = self.last_frame {
+            if frame < last_frame + INTERVAL {
+                return;
+            }
+        }
+        if self.states.len() == CAPACITY {
+            self.states.pop_front();
+        }
+        self.states.push_back(Entry {
+            frame,
+            keys: cpu.keyboard.keys,
+            state: cpu.save_state(),
+        });
+        self.last_frame = Some(frame);
+        // key changes before the oldest state are never replayed
+        if let Some(oldest) = self.states.front() {
+            let steps = oldest.state.clock_steps();
+            while let Some((step, _)) = self.keys.front() {
+                if *step >= steps {
+                    break;
+                }
+                self.keys.pop_front();
+            }
+        }
+    }
+
+    // Restores the state from about the given number of seconds ago, returns
+    // the message for the user
+    pub fn rewind(&mut self, cpu: &mut Cpu, seconds: f64) -> String {
+        let count = ((seconds * 60.0 / INTERVAL as f64).round() as usize).max(1);
+        let keep = self.states.len().saturating_sub(count - 1).max(1);
+        self.states.truncate(keep);
+        let message = match self.states.back() {
+            Some(entry) => {
+                cpu.load_state(&entry.state);
+                self.last_keys = entry.keys;
+                format!("Rewound to {:04X}", cpu.pc)
+            }
+            None => "Nothing to rewind".to_string(),
+        };
+        self.forget_future(cpu);
+        message
+    }
+
+    // Restores the nearest earlier state and executes the instructions up to the
+    // start of the frame again, with the keys that were pressed at the time.
+    // Only instruction counted timers make this repeat the same execution.
+    pub fn goto_frame(&mut self, cpu: &mut Cpu, frame: u64) -> Result<(), anyhow::Error> {
+        if let ClockMode::WallClock = cpu.clock.mode {
+            bail!("Going to a frame needs --timer-mode instructions");
+        }
+        if frame < cpu.frame() {
+            let entry = match self.states.iter().rev().find(|entry| entry.frame <= frame) {
+                Some(entry) => entry,
+                None => bail!("Frame {} is no longer kept", frame),
+            };
+            cpu.load_state(&entry.state);
+            cpu.keyboard.set_keys(entry.keys);
+        }
+
+        // executing to the frame is not logged and does not trigger watchpoints
+        let access_log = cpu.access_log.take();
+        let result = self.execute_to(cpu, frame);
+        cpu.access_log = access_log;
+        cpu.watch_hit = None;
+        self.last_keys = cpu.keyboard.keys;
+        self.forget_future(cpu);
+        result
+    }
+
+    fn execute_to(&self, cpu: &mut Cpu, frame: u64) -> Result<(), anyhow::Error> {
+        let mut next_keys = self
+            .keys
+            .iter()
+            .position(|(step, _)| *step >= cpu.clock_steps)
+            .unwrap_or(self.keys.len());
+        while cpu.frame() < frame && !cpu.exited && !cpu.halted {
+            while next_keys < self.keys.len() && self.keys[next_keys].0 <= cpu.clock_steps {
+                cpu.keyboard.set_keys(self.keys[next_keys].1);
+                next_keys += 1;
+            }
+            cpu.step()?;
+        }
+        Ok(())
+    }
+
+    // execution continues from the current state, later states and keys are outdated
+    fn forget_future(&mut self, cpu: &Cpu) {
+        let frame = cpu.frame();
+        while let Some(entry) = self.states.back() {
+            if entry.frame <= frame {
+                break;
+            }
+            self.states.pop_back();
+        }
+        while let Some((step, _)) = self.keys.back() {
+            if *step < cpu.clock_steps {
+                break;
+            }
+            self.keys.pop_back();
+        }
+        self.last_frame = Some(frame);
+    }
+}
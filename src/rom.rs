@@ -0,0 +1,28 @@
+use anyhow::bail;
+use sha1::{Digest, Sha1};
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    hex::encode(Sha1::digest(data))
+}
+
+pub fn verify_sha1(data: &[u8], expected: &str, warn_only: bool) -> Result<(), anyhow::Error> {
+    let actual = sha1_hex(data);
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        return Ok(());
+    }
+    if warn_only {
+        eprintln!(
+            "Warning: rom sha1 {} does not match expected {}",
+            actual, expected
+        );
+        Ok(())
+    } else {
+        bail!("Rom sha1 {} does not match expected {}", actual, expected)
+    }
+}
+
+pub fn print_info(path: &str, data: &[u8]) {
+    println!("path: {}", path);
+    println!("size: {} bytes", data.len());
+    println!("sha1: {}", sha1_hex(data));
+}
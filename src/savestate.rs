@@ -0,0 +1,81 @@
+// Machine states in numbered slots on disk, kept per rom like the flags, e.g.
+// ~/.local/share/ch8-rs/states/<rom sha1>-1.state
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use super::cpu::SavedState;
+use super::rom::sha1_hex;
+
+// raised when SavedState changes, files of other versions cannot be loaded
+const VERSION: u32 = 3;
+// written on exit with --autosave and loaded by --resume, not one of the
+// slots 1 - 9 of the hotkeys
+pub const AUTOSAVE_SLOT: u8 = 0;
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    // sha1 of the rom, a renamed file is not loaded into another rom
+    rom: String,
+    state: SavedState,
+}
+
+pub fn default_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("ch8-rs").join("states"))
+}
+
+#[derive(Clone)]
+pub struct StateSlots {
+    dir: PathBuf,
+    rom: String,
+}
+
+impl StateSlots {
+    pub fn new(dir: PathBuf, code: &[u8]) -> Self {
+        Self {
+            dir,
+            rom: sha1_hex(code),
+        }
+    }
+
+    pub fn path(&self, slot: u8) -> PathBuf {
+        self.dir.join(format!("{}-{}.state", self.rom, slot))
+    }
+
+    pub fn save(&self, slot: u8, state: SavedState) -> Result<(), anyhow::Error> {
+        let path = self.path(slot);
+        let file = StateFile {
+            version: VERSION,
+            rom: self.rom.clone(),
+            state,
+        };
+        let data = bincode::serialize(&file).context("Could not serialize state")?;
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Could not create directory {}", self.dir.display()))?;
+        fs::write(&path, data).with_context(|| format!("Could not write state {}", path.display()))
+    }
+
+    pub fn load(&self, slot: u8) -> Result<SavedState, anyhow::Error> {
+        let path = self.path(slot);
+        if !path.exists() {
+            bail!("Slot {} is empty", slot);
+        }
+        let data =
+            fs::read(&path).with_context(|| format!("Could not read state {}", path.display()))?;
+        let file: StateFile = bincode::deserialize(&data)
+            .with_context(|| format!("Invalid state {}", path.display()))?;
+        if file.version != VERSION {
+            bail!(
+                "State {} was saved by another version of ch8-rs",
+                path.display()
+            );
+        }
+        if file.rom != self.rom {
+            bail!("State {} was saved for another rom", path.display());
+        }
+        Ok(file.state)
+    }
+}
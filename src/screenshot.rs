@@ -0,0 +1,98 @@
+// Still images of the display as PPM or PNG files, one image pixel per display pixel
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+// largest block of uncompressed deflate data
+const STORED_BLOCK: usize = 65535;
+
+pub fn save(
+    path: &Path,
+    display: &[u32],
+    width: usize,
+    height: usize,
+) -> Result<(), anyhow::Error> {
+    let mut rgb = Vec::with_capacity(display.len() * 3);
+    for color in display {
+        rgb.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, *color as u8]);
+    }
+    let data = match path.extension().and_then(OsStr::to_str) {
+        Some("ppm") => {
+            let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+            data.extend_from_slice(&rgb);
+            data
+        }
+        Some("png") => png(&rgb, width, height),
+        _ => bail!(
+            "Unknown image type of {}, expected .png or .ppm",
+            path.display()
+        ),
+    };
+    fs::write(path, data).with_context(|| format!("Could not write image {}", path.display()))
+}
+
+// 8 bit RGB PNG, the pixels are stored without compression
+fn png(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth, RGB, deflate, no filter, no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // every row starts with its filter type
+    let mut rows = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks(width * 3) {
+        rows.push(0);
+        rows.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = rows.chunks(STORED_BLOCK).count();
+    for (n, block) in rows.chunks(STORED_BLOCK).enumerate() {
+        zlib.push(if n + 1 == blocks { 1 } else { 0 });
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&rows).to_be_bytes());
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut out, b"IHDR", &header);
+    write_chunk(&mut out, b"IDAT", &zlib);
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
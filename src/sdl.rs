@@ -0,0 +1,293 @@
+// The display in an SDL2 window, only built with the sdl2 feature. The picture
+// is the same as in the minifb window, SDL2 only shows it and reads the keys
+// and game controllers.
+use anyhow::{anyhow, Context};
+use sdl2::controller::{Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod, Scancode};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{TextureCreator, WindowCanvas};
+use sdl2::video::{FullscreenType, WindowContext};
+use sdl2::{EventPump, GameControllerSubsystem};
+
+use super::cpu::{VKey, HEIGHT, WIDTH};
+use super::debugger::Command;
+use super::emulator::Emulator;
+use super::frontend::{Action, Frame, Frontend, Input};
+use super::hotkeys::{BoundHotkeys, HotkeyKey, Modifier};
+use super::window::View;
+
+// the flag asks for shift to be held, F11 is left to fullscreen
+const DEBUGGER_KEYS: [(Keycode, bool, Command, &str); 4] = [
+    (Keycode::F5, false, Command::Resume, "Running"),
+    (Keycode::F6, false, Command::Pause, "Paused"),
+    (Keycode::F10, false, Command::Step, "Step"),
+    (Keycode::F10, true, Command::StepFrame, "Step frame"),
+];
+// the save state slots 1 - 9 with the modifiers of the hotkeys
+const SLOT_KEYS: [Keycode; 9] = [
+    Keycode::Num1,
+    Keycode::Num2,
+    Keycode::Num3,
+    Keycode::Num4,
+    Keycode::Num5,
+    Keycode::Num6,
+    Keycode::Num7,
+    Keycode::Num8,
+    Keycode::Num9,
+];
+
+pub struct SdlFrontend {
+    canvas: WindowCanvas,
+    creator: TextureCreator<WindowContext>,
+    events: EventPump,
+    fullscreen: bool,
+    view: View,
+    // the CHIP-8 keys 0 - F by their position on the keyboard
+    keys: [Option<Scancode>; 16],
+    hotkeys: BoundHotkeys<Scancode>,
+    controller_subsystem: GameControllerSubsystem,
+    // connected controllers, opened when SDL2 reports them
+    controllers: Vec<GameController>,
+    buttons: Vec<(Button, usize)>,
+    // the picture as bytes of the texture, 0x00RRGGBB in native byte order
+    bytes: Vec<u8>,
+}
+
+impl SdlFrontend {
+    // Fullscreen uses the desktop resolution, so --screen-size is not needed
+    pub fn new(emulator: &Emulator) -> Result<Self, anyhow::Error> {
+        let sdl = sdl2::init().map_err(|err| anyhow!("Could not initialize SDL2: {}", err))?;
+        let video = sdl
+            .video()
+            .map_err(|err| anyhow!("Could not initialize SDL2 video: {}", err))?;
+        // the window size is in points, on high DPI screens it has more pixels
+        let scale = emulator.window_scale as u32;
+        let mut builder = video.window(
+            "CHIP8 - ESC to exit",
+            WIDTH as u32 * scale,
+            HEIGHT as u32 * scale,
+        );
+        builder.resizable().position_centered().allow_highdpi();
+        if emulator.fullscreen {
+            builder.fullscreen_desktop();
+        }
+        let window = builder.build().context("Could not create SDL2 window")?;
+        let canvas = window
+            .into_canvas()
+            .build()
+            .context("Could not create SDL2 renderer")?;
+        let controller_subsystem = sdl
+            .game_controller()
+            .map_err(|err| anyhow!("Could not initialize SDL2 game controllers: {}", err))?;
+        let events = sdl
+            .event_pump()
+            .map_err(|err| anyhow!("Could not read SDL2 events: {}", err))?;
+        Ok(Self {
+            creator: canvas.texture_creator(),
+            canvas,
+            events,
+            fullscreen: emulator.fullscreen,
+            view: View::new(emulator),
+            keys: emulator
+                .keymap
+                .positions()
+                .map(|c| Scancode::from_name(&c.to_ascii_uppercase().to_string())),
+            hotkeys: emulator.hotkeys.bind(hotkey_scancode),
+            controller_subsystem,
+            controllers: Vec::new(),
+            buttons: emulator
+                .gamepad_map
+                .buttons()
+                .filter_map(|(name, key)| Button::from_string(name).map(|button| (button, key)))
+                .collect(),
+            bytes: Vec::new(),
+        })
+    }
+
+    fn toggle_fullscreen(&mut self) -> Result<(), anyhow::Error> {
+        self.fullscreen = !self.fullscreen;
+        let mode = if self.fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        self.canvas
+            .window_mut()
+            .set_fullscreen(mode)
+            .map_err(|err| anyhow!("Could not change to fullscreen: {}", err))?;
+        self.view.force_redraw();
+        Ok(())
+    }
+
+    // SDL2 also reports the controllers connected at the start
+    fn open_controller(&mut self, index: u32) {
+        if !self.controller_subsystem.is_game_controller(index) {
+            return;
+        }
+        match self.controller_subsystem.open(index) {
+            Ok(controller) => {
+                self.view
+                    .message(format!("Controller {}", controller.name()));
+                self.controllers.push(controller);
+            }
+            Err(err) => eprintln!("Warning: could not open game controller: {}", err),
+        }
+    }
+}
+
+impl Frontend for SdlFrontend {
+    // Escape or closing the window quits
+    fn poll(&mut self) -> Result<Option<Input>, anyhow::Error> {
+        let mut actions = Vec::new();
+        let events: Vec<Event> = self.events.poll_iter().collect();
+        for event in events {
+            let (keycode, scancode, keymod, repeat) = match event {
+                Event::Quit { .. } => return Ok(None),
+                Event::ControllerDeviceAdded { which, .. } => {
+                    self.open_controller(which);
+                    continue;
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers
+                        .retain(|controller| controller.instance_id() != which);
+                    continue;
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    scancode,
+                    keymod,
+                    repeat,
+                    ..
+                } => (keycode, scancode, keymod, repeat),
+                _ => continue,
+            };
+            let alt = keymod.intersects(Mod::LALTMOD | Mod::RALTMOD);
+            let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+            match keycode {
+                Keycode::Escape => return Ok(None),
+                Keycode::F1 if !repeat => self.view.toggle_keypad(),
+                Keycode::F2 if !repeat => self.view.next_texture_filter(),
+                Keycode::F3 if !repeat => self.view.next_post_filter(),
+                Keycode::F4 if !repeat => actions.push(Action::Invert),
+                Keycode::F7 => actions.push(Action::Darker),
+                Keycode::F8 => actions.push(Action::Brighter),
+                Keycode::Return if alt && !repeat => self.toggle_fullscreen()?,
+                Keycode::F11 if !repeat => self.toggle_fullscreen()?,
+                _ => {}
+            }
+            for (key, with_shift, command, name) in DEBUGGER_KEYS.iter() {
+                if keycode == *key && shift == *with_shift {
+                    actions.push(Action::Debug(*command));
+                    self.view.message(name.to_string());
+                }
+            }
+            // the hotkeys are read by position like the CHIP-8 keys
+            let is_key = |key: Option<Scancode>| key.is_some() && key == scancode;
+            if !repeat && is_key(self.hotkeys.record) {
+                actions.push(Action::ToggleGif);
+            }
+            if !repeat && is_key(self.hotkeys.pause) {
+                actions.push(Action::Debug(Command::TogglePause));
+            }
+            if !repeat && is_key(self.hotkeys.slow_motion) {
+                actions.push(Action::ToggleSlowMotion);
+            }
+            if is_key(self.hotkeys.rewind) {
+                actions.push(Action::Debug(Command::Rewind));
+                self.view.message("Rewind".to_string());
+            }
+            if !repeat && is_key(self.hotkeys.reset) {
+                actions.push(Action::Debug(Command::Reset));
+                self.view.message("Reset".to_string());
+            }
+            let held = |modifier| match modifier {
+                Modifier::Shift => shift,
+                Modifier::Ctrl => keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD),
+                Modifier::Alt => alt,
+            };
+            let slot = SLOT_KEYS
+                .iter()
+                .position(|key| *key == keycode)
+                .filter(|_| !repeat);
+            if let Some(command) =
+                slot.and_then(|index| self.hotkeys.slot_command(index as u8 + 1, held))
+            {
+                actions.push(Action::Debug(command));
+            }
+        }
+
+        let state = self.events.keyboard_state();
+        let mut keys = [VKey::Up; 16];
+        for (key, scancode) in keys.iter_mut().zip(self.keys.iter()) {
+            if matches!(scancode, Some(scancode) if state.is_scancode_pressed(*scancode)) {
+                *key = VKey::Down;
+            }
+        }
+        for controller in self.controllers.iter() {
+            for (button, key) in self.buttons.iter() {
+                if controller.button(*button) {
+                    keys[*key] = VKey::Down;
+                }
+            }
+        }
+        let down =
+            |left, right| state.is_scancode_pressed(left) || state.is_scancode_pressed(right);
+        let held = |modifier| match modifier {
+            Modifier::Shift => down(Scancode::LShift, Scancode::RShift),
+            Modifier::Ctrl => down(Scancode::LCtrl, Scancode::RCtrl),
+            Modifier::Alt => down(Scancode::LAlt, Scancode::RAlt),
+        };
+        if self.hotkeys.slot_modifier_held(held) {
+            keys = [VKey::Up; 16];
+        }
+        self.view.set_keys(keys);
+        Ok(Some(Input {
+            keys,
+            actions,
+            turbo: matches!(self.hotkeys.turbo, Some(key) if state.is_scancode_pressed(key)),
+        }))
+    }
+
+    fn present(&mut self, frame: &Frame) -> Result<(), anyhow::Error> {
+        // the size in pixels, which is larger than the window size on high DPI screens
+        let (width, height) = self
+            .canvas
+            .output_size()
+            .map_err(|err| anyhow!("Could not get the SDL2 window size: {}", err))?;
+        let buffer = match self.view.render(frame, (width as usize, height as usize)) {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+        self.bytes.clear();
+        for pixel in buffer {
+            self.bytes.extend_from_slice(&pixel.to_ne_bytes());
+        }
+        let mut texture = self
+            .creator
+            .create_texture_streaming(PixelFormatEnum::RGB888, width, height)
+            .context("Could not create SDL2 texture")?;
+        texture
+            .update(None, &self.bytes, width as usize * 4)
+            .context("Could not update SDL2 texture")?;
+        self.canvas
+            .copy(&texture, None, None)
+            .map_err(|err| anyhow!("Could not draw SDL2 texture: {}", err))?;
+        self.canvas.present();
+        Ok(())
+    }
+
+    fn message(&mut self, text: String) {
+        self.view.message(text);
+    }
+}
+
+fn hotkey_scancode(key: HotkeyKey) -> Option<Scancode> {
+    match key {
+        HotkeyKey::Char(c) => Scancode::from_name(&c.to_ascii_uppercase().to_string()),
+        HotkeyKey::F(n) => Scancode::from_name(&format!("F{}", n)),
+        HotkeyKey::Tab => Some(Scancode::Tab),
+        HotkeyKey::Backspace => Some(Scancode::Backspace),
+        HotkeyKey::Space => Some(Scancode::Space),
+    }
+}
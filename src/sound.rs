@@ -1,72 +1,306 @@
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use blip_buf::BlipBuf;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use std::sync::mpsc::{self, Receiver, SendError, SyncSender};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use super::wav::Wav;
+
+// output samples rendered at once by the audio callback
+const CHUNK: usize = 512;
+// pauses between attempts to open the audio device
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
 
 pub struct Sound {
     fs_input: f64,
+    voice: Arc<Mutex<Voice>>,
+    backend: Box<dyn SoundBackend>,
+    // played by the sound timer instead of the pattern
+    buzzer: Option<(Arc<Vec<i16>>, f64)>,
+}
+
+// Where the sound goes, by default the audio device through cpal. Embedders
+// without cpal, e.g. in the browser, can take the samples themselves, either
+// with CallbackBackend or by rendering a synth in their own audio callback.
+pub trait SoundBackend: Send {
+    // Called once when the emulation starts
+    fn start(&mut self, source: SoundSource) -> Result<(), anyhow::Error>;
+
+    fn stop(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+// The voice of the emulator handed to a backend
+#[derive(Clone)]
+pub struct SoundSource {
+    voice: Arc<Mutex<Voice>>,
+}
+
+impl SoundSource {
+    // A synth that renders mono samples at the sample rate of the output
+    pub fn synth(&self, sample_rate: f64) -> Synth {
+        Synth::new(self.voice.clone(), sample_rate)
+    }
+}
+
+// The default output device through cpal
+pub struct CpalBackend {
     audio_stream: Option<AudioStream>,
 }
 
-pub struct AudioStream {
-    blip: Arc<Mutex<BlipBuf>>,
-    tx_stop: SyncSender<()>,
+struct AudioStream {
+    tx_events: SyncSender<StreamEvent>,
     thread: thread::JoinHandle<Result<(), anyhow::Error>>,
 }
 
+enum StreamEvent {
+    // the stream failed and is opened again
+    Error,
+    Stop,
+}
+
+// What is played, shared with the audio callback which pulls the samples while
+// the tone lasts, so pattern and pitch changes are heard immediately
+struct Voice {
+    // input samples per second
+    rate: f64,
+    // looped while the tone lasts
+    samples: Arc<Vec<i16>>,
+    position: usize,
+    // seconds until the tone ends
+    remaining: f64,
+    // cutoff frequency of the low-pass filter
+    lowpass: Option<f64>,
+}
+
+impl Voice {
+    fn next(&mut self) -> i16 {
+        if self.remaining <= 0.0 || self.samples.is_empty() {
+            return 0;
+        }
+        self.remaining -= 1.0 / self.rate;
+        let index = self.position % self.samples.len();
+        self.position = index + 1;
+        self.samples[index]
+    }
+}
+
+// Band limits the voice to the sample rate of the device, owned by the audio callback
+pub struct Synth {
+    voice: Arc<Mutex<Voice>>,
+    blip: BlipBuf,
+    sample_rate: f64,
+    rate: f64,
+    level: i16,
+    lowpass: Option<LowPass>,
+}
+
+// Two one-pole low-pass filters in a row, the treble falls off by 12 dB per octave
+struct LowPass {
+    cutoff: f64,
+    alpha: f64,
+    stages: [f64; 2],
+}
+
+impl LowPass {
+    fn new(cutoff: f64, sample_rate: f64) -> Self {
+        Self {
+            cutoff,
+            alpha: 1.0 - (-2.0 * std::f64::consts::PI * cutoff / sample_rate).exp(),
+            stages: [0.0; 2],
+        }
+    }
+
+    fn filter(&mut self, sample: i16) -> i16 {
+        let mut value = sample as f64;
+        for stage in self.stages.iter_mut() {
+            *stage += self.alpha * (value - *stage);
+            value = *stage;
+        }
+        value as i16
+    }
+}
+
+impl Synth {
+    fn new(voice: Arc<Mutex<Voice>>, sample_rate: f64) -> Self {
+        Self {
+            voice,
+            blip: BlipBuf::new(CHUNK as u32 * 2),
+            sample_rate,
+            rate: 0.0,
+            level: 0,
+            lowpass: None,
+        }
+    }
+
+    // Fills the output with the next samples, silence when no tone plays
+    pub fn render(&mut self, output: &mut [i16]) {
+        let mut voice = self.voice.lock().unwrap();
+        for chunk in output.chunks_mut(CHUNK) {
+            if voice.rate != self.rate {
+                self.rate = voice.rate;
+                self.blip.set_rates(self.rate, self.sample_rate);
+            }
+            let clocks = self.blip.clocks_needed(chunk.len() as u32);
+            for time in 0..clocks {
+                // blip takes the changes of the signal
+                let sample = voice.next();
+                if sample != self.level {
+                    self.blip.add_delta(time, sample as i32 - self.level as i32);
+                    self.level = sample;
+                }
+            }
+            self.blip.end_frame(clocks);
+            self.blip.read_samples(chunk, false);
+
+            if self.lowpass.as_ref().map(|lowpass| lowpass.cutoff) != voice.lowpass {
+                self.lowpass = voice
+                    .lowpass
+                    .map(|cutoff| LowPass::new(cutoff, self.sample_rate));
+            }
+            if let Some(lowpass) = self.lowpass.as_mut() {
+                for sample in chunk.iter_mut() {
+                    *sample = lowpass.filter(*sample);
+                }
+            }
+        }
+    }
+}
+
 impl Sound {
     pub fn new(fs_input: f64) -> Self {
         Self {
             fs_input,
-            audio_stream: None,
+            voice: Arc::new(Mutex::new(Voice {
+                rate: fs_input,
+                samples: Arc::new(Vec::new()),
+                position: 0,
+                remaining: 0.0,
+                lowpass: None,
+            })),
+            backend: Box::new(CpalBackend::new()),
+            buzzer: None,
         }
     }
 
+    // Replaces the audio device, must be called before start
+    pub fn set_backend(&mut self, backend: Box<dyn SoundBackend>) {
+        self.backend = backend;
+    }
+
+    // The sample is looped while the sound timer runs, pitch and pattern are ignored
+    pub fn set_buzzer(&mut self, wav: Wav) {
+        self.buzzer = Some((Arc::new(wav.samples), wav.rate));
+    }
+
+    // A synth of the voice for a caller that renders the sound itself instead
+    // of a backend, e.g. Cpu::step_frame
+    pub fn synth(&self, sample_rate: f64) -> Synth {
+        Synth::new(self.voice.clone(), sample_rate)
+    }
+
     pub fn start(&mut self) -> Result<(), anyhow::Error> {
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .expect("failed to find a default output device");
-        let config = device
-            .default_output_config()
-            .context("Could not find default output config")?;
-
-        // setup blip with enough sample space for the maximum tone duration of 255/60 seconds.
-        let mut blip = BlipBuf::new(config.sample_rate().0 * 256 / 60);
-        blip.set_rates(self.fs_input, config.sample_rate().0 as f64);
-        let blip = Arc::new(Mutex::new(blip));
+        self.backend.start(SoundSource {
+            voice: self.voice.clone(),
+        })
+    }
 
-        let (tx_stop, rx_stop) = mpsc::sync_channel::<()>(1);
-        // Create second sender to stop stream thread from cpal error callback function
-        let tx_stop2 = tx_stop.clone();
+    pub fn stop(&mut self) -> Result<(), anyhow::Error> {
+        self.backend.stop()
+    }
 
-        let thread = match config.sample_format() {
-            cpal::SampleFormat::F32 => {
-                self._run::<f32>(device, config.into(), rx_stop, tx_stop2, blip.clone())
-            }
-            cpal::SampleFormat::I16 => {
-                self._run::<i16>(device, config.into(), rx_stop, tx_stop2, blip.clone())
+    // Changes the rate at which the pattern buffer is played back, also while it plays
+    pub fn set_rate(&mut self, fs_input: f64) {
+        self.fs_input = fs_input;
+        if self.buzzer.is_none() {
+            self.voice.lock().unwrap().rate = fs_input;
+        }
+    }
+
+    // Softens the sound above the cutoff frequency, e.g. the edges of the 1-bit patterns
+    pub fn set_lowpass(&mut self, cutoff: Option<f64>) {
+        self.voice.lock().unwrap().lowpass = cutoff;
+    }
+
+    // Changes the pattern buffer, also while it plays
+    pub fn set_pattern_1bit(&mut self, samples: &[u8]) {
+        if self.buzzer.is_some() {
+            return;
+        }
+        let mut samples_conv = vec![0i16; 16 * 8];
+        for (batch, inp) in samples_conv.chunks_mut(8).zip(samples.iter()) {
+            for (i, outp) in batch.iter_mut().enumerate() {
+                *outp = (((*inp >> (7 - i)) & 0x1) as i16 * 2 - 1) * 10000;
             }
-            cpal::SampleFormat::U16 => {
-                self._run::<u16>(device, config.into(), rx_stop, tx_stop2, blip.clone())
+        }
+        self.voice.lock().unwrap().samples = Arc::new(samples_conv);
+    }
+
+    // Plays the pattern for the duration of the sound timer, a tone that still
+    // plays continues without restarting the pattern
+    pub fn play_samples_1bit(&mut self, samples: &[u8], duration: Duration) {
+        self.set_pattern_1bit(samples);
+        let mut voice = self.voice.lock().unwrap();
+        if let Some((samples, rate)) = self.buzzer.as_ref() {
+            // a new beep starts the sample from the beginning
+            if voice.remaining <= 0.0 {
+                voice.position = 0;
             }
-        }?;
-        self.audio_stream = Some(AudioStream {
-            blip,
-            tx_stop,
-            thread,
-        });
+            voice.samples = samples.clone();
+            voice.rate = *rate;
+        }
+        voice.remaining = duration.as_secs_f64();
+    }
+
+    // Ends the tone at once, the audio callback renders silence from its next
+    // chunk on and nothing of the tone is left buffered
+    pub fn silence(&mut self) {
+        self.voice.lock().unwrap().remaining = 0.0;
+    }
+
+    // Plays the samples from the start
+    pub fn play_samples(&mut self, samples: &[i16], duration: Duration) {
+        let mut voice = self.voice.lock().unwrap();
+        voice.samples = Arc::new(samples.to_vec());
+        voice.rate = self.fs_input;
+        voice.position = 0;
+        voice.remaining = duration.as_secs_f64();
+    }
+}
+
+impl Default for CpalBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpalBackend {
+    pub fn new() -> Self {
+        Self { audio_stream: None }
+    }
+}
+
+impl SoundBackend for CpalBackend {
+    // Without an audio device the emulator runs silently, like with --no-audio,
+    // until a device is found
+    fn start(&mut self, source: SoundSource) -> Result<(), anyhow::Error> {
+        let (tx_events, rx_events) = mpsc::sync_channel::<StreamEvent>(1);
+        let tx_errors = tx_events.clone();
+        // Create streams in their own thread so that we can keep them in scope and
+        // do not need to save them in Sound, which would make both Sound and CPU !Send
+        let thread = thread::spawn(move || run_streams(source, rx_events, tx_errors));
+        self.audio_stream = Some(AudioStream { tx_events, thread });
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn stop(&mut self) -> Result<(), anyhow::Error> {
+    fn stop(&mut self) -> Result<(), anyhow::Error> {
         let audio_stream = self.audio_stream.take();
         if let Some(audio_stream) = audio_stream {
-            match audio_stream.tx_stop.send(()) {
+            match audio_stream.tx_events.send(StreamEvent::Stop) {
                 Ok(..) => {}
                 Err(SendError(..)) => {}
             };
@@ -74,88 +308,185 @@ impl Sound {
         }
         Ok(())
     }
+}
 
-    pub fn play_samples_1bit(&mut self, samples: &[u8], duration: Duration) {
-        let mut samples_conv = [0i16; 16 * 8];
-        for (batch, inp) in samples_conv.chunks_mut(8).zip(samples.iter()) {
-            for (i, outp) in batch.iter_mut().enumerate() {
-                *outp = (((*inp >> (7 - i)) & 0x1) as i16 * 2 - 1) * 10000;
-            }
+// No sound at all, for --no-audio
+pub struct NullBackend;
+
+impl SoundBackend for NullBackend {
+    fn start(&mut self, _source: SoundSource) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+// receives the rendered samples of CallbackBackend
+type SampleCallback = Box<dyn FnMut(&[i16]) + Send>;
+
+// Hands the samples to a callback in chunks of CHUNK, as fast as they would be
+// played, e.g. to an audio sink of an embedder. The callback runs on its own
+// thread, a stopped backend cannot be started again.
+pub struct CallbackBackend {
+    sample_rate: f64,
+    callback: Option<SampleCallback>,
+    thread: Option<(SyncSender<()>, thread::JoinHandle<()>)>,
+}
+
+impl CallbackBackend {
+    pub fn new<F>(sample_rate: f64, callback: F) -> Self
+    where
+        F: FnMut(&[i16]) + Send + 'static,
+    {
+        Self {
+            sample_rate,
+            callback: Some(Box::new(callback)),
+            thread: None,
         }
-        self.play_samples(&samples_conv[..], duration);
     }
+}
 
-    pub fn play_samples(&mut self, samples: &[i16], duration: Duration) {
-        let audio_stream = self.audio_stream.as_ref().unwrap();
-        let mut blip = audio_stream.blip.lock().unwrap();
-
-        blip.clear();
-        let mut time = 0usize; // takes count of how many samples were written in the current frame
-        let samples_needed = (duration.as_secs_f64() * self.fs_input) as usize;
-        let samples_chunksize = (0.00166 * self.fs_input) as usize;
-        let mut samples_written = 0usize;
-
-        while samples_written < samples_needed {
-            while time < samples_chunksize && samples_written < samples_needed {
-                blip.add_delta(time as u32, samples[samples_written % samples.len()] as i32);
-                time += 1;
-                samples_written += 1;
+impl SoundBackend for CallbackBackend {
+    fn start(&mut self, source: SoundSource) -> Result<(), anyhow::Error> {
+        let mut callback = self
+            .callback
+            .take()
+            .context("The sound backend was already started")?;
+        let mut synth = source.synth(self.sample_rate);
+        let chunk_duration = Duration::from_secs_f64(CHUNK as f64 / self.sample_rate);
+        let (tx_stop, rx_stop) = mpsc::sync_channel::<()>(1);
+        let thread = thread::spawn(move || {
+            let mut buf = vec![0i16; CHUNK];
+            // the chunks are timed from the start, so that they do not drift
+            let mut next = Instant::now();
+            loop {
+                synth.render(&mut buf);
+                callback(&buf);
+                next += chunk_duration;
+                let wait = next.saturating_duration_since(Instant::now());
+                match rx_stop.recv_timeout(wait) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+                }
             }
-            blip.end_frame(time as u32);
-            time = 0;
+        });
+        self.thread = Some((tx_stop, thread));
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), anyhow::Error> {
+        if let Some((tx_stop, thread)) = self.thread.take() {
+            let _ = tx_stop.send(());
+            thread
+                .join()
+                .map_err(|_| anyhow!("The sound callback panicked"))?;
         }
+        Ok(())
     }
+}
 
-    fn _run<T>(
-        &mut self,
-        device: cpal::Device,
-        config: cpal::StreamConfig,
-        rx_stop: Receiver<()>,
-        tx_stop: SyncSender<()>,
-        blip: Arc<Mutex<BlipBuf>>,
-    ) -> Result<thread::JoinHandle<Result<(), anyhow::Error>>, anyhow::Error>
-    where
-        T: cpal::Sample,
-    {
-        let err_fn = move |err| {
-            match tx_stop.send(()) {
-                Ok(..) => {}
-                Err(SendError(..)) => {}
-            };
-            eprintln!("an error occurred on stream: {}", err)
-        };
-
-        let channels = config.channels as usize;
-
-        let thread = thread::spawn(move || -> Result<(), anyhow::Error> {
-            // Create stream in its own thread so that we can safe it in scope and do not
-            // need to save it in Sound, which would make both Sound and CPU !Send
-            let stream = device.build_output_stream(
-                &config,
-                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                    write_data(data, channels as usize, blip.clone())
-                },
-                err_fn,
-            )?;
-            stream.play()?;
-            rx_stop.recv()?;
-            Ok(())
-        });
-        Ok(thread)
+// Keeps a stream on the default output device open, when it fails, e.g. because
+// the headphones were unplugged, it is opened again with growing pauses. Machines
+// that have no device at the start, like headless servers, are not polled.
+fn run_streams(
+    source: SoundSource,
+    rx_events: Receiver<StreamEvent>,
+    tx_errors: SyncSender<StreamEvent>,
+) -> Result<(), anyhow::Error> {
+    let mut backoff = MIN_BACKOFF;
+    let mut opened = false;
+    let mut warned = false;
+    loop {
+        match open_stream(&source, tx_errors.clone()) {
+            Ok(stream) => {
+                if warned {
+                    eprintln!("Audio output device found, sound is enabled again");
+                    warned = false;
+                }
+                opened = true;
+                backoff = MIN_BACKOFF;
+                match rx_events.recv() {
+                    Ok(StreamEvent::Error) => drop(stream),
+                    Ok(StreamEvent::Stop) | Err(..) => return Ok(()),
+                }
+            }
+            Err(err) if !opened => {
+                eprintln!("Warning: sound is disabled: {:#}", err);
+                return Ok(());
+            }
+            Err(err) => {
+                if !warned {
+                    eprintln!(
+                        "Warning: sound is disabled until the audio device works again: {:#}",
+                        err
+                    );
+                    warned = true;
+                }
+            }
+        }
+        match rx_events.recv_timeout(backoff) {
+            Ok(StreamEvent::Error) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(StreamEvent::Stop) | Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
     }
 }
 
-fn write_data<T>(output: &mut [T], channels: usize, blip: Arc<Mutex<BlipBuf>>)
+// The sample format and rate are those of the device, they can change when it
+// is opened again
+fn open_stream(
+    source: &SoundSource,
+    tx_errors: SyncSender<StreamEvent>,
+) -> Result<cpal::Stream, anyhow::Error> {
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .context("No audio output device found")?;
+    let config = device
+        .default_output_config()
+        .context("Could not open the audio output device")?;
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => build_stream::<f32>(&device, config.into(), source, tx_errors),
+        cpal::SampleFormat::I16 => build_stream::<i16>(&device, config.into(), source, tx_errors),
+        cpal::SampleFormat::U16 => build_stream::<u16>(&device, config.into(), source, tx_errors),
+    }?;
+    stream.play().context("Could not start the audio stream")?;
+    Ok(stream)
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: cpal::StreamConfig,
+    source: &SoundSource,
+    tx_errors: SyncSender<StreamEvent>,
+) -> Result<cpal::Stream, anyhow::Error>
 where
     T: cpal::Sample,
 {
-    let mut blip = blip.lock().unwrap();
+    let err_fn = move |err| {
+        eprintln!("an error occurred on stream: {}", err);
+        // the stream is opened again, further errors of this stream are not needed
+        let _ = tx_errors.try_send(StreamEvent::Error);
+    };
 
-    let mut buf = vec![0i16; output.len() / 2];
-    let mut read = 0usize;
-    while blip.samples_avail() > 0 && !buf[read..].is_empty() {
-        read += blip.read_samples(&mut buf[read..], false);
-    }
+    let channels = config.channels as usize;
+    let mut synth = source.synth(config.sample_rate.0 as f64);
+    let stream = device
+        .build_output_stream(
+            &config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                write_data(data, channels, &mut synth)
+            },
+            err_fn,
+        )
+        .context("Could not create the audio stream")?;
+    Ok(stream)
+}
+
+fn write_data<T>(output: &mut [T], channels: usize, synth: &mut Synth)
+where
+    T: cpal::Sample,
+{
+    let mut buf = vec![0i16; output.len() / channels];
+    synth.render(&mut buf);
 
     output
         .chunks_mut(channels)
@@ -0,0 +1,155 @@
+// Probable sprites of a rom for reverse engineering, either the data I points
+// to before a DRW or the whole rom cut into sprites of one height
+use std::path::Path;
+
+use super::screenshot;
+
+// instructions after LD I, nnn that are searched for the DRW
+const LOOKAHEAD: usize = 8;
+// sprites per row of the image
+const ROW_LENGTH: usize = 16;
+// image pixels per sprite pixel
+const IMAGE_SCALE: usize = 4;
+
+const ON: u32 = 0xFFFFFF;
+const OFF: u32 = 0x000000;
+const GAP: u32 = 0x404040;
+
+pub struct Sprite {
+    pub addr: u16,
+    // 16 for the 16x16 SCHIP sprites of Dxy0
+    pub width: usize,
+    pub height: usize,
+    // address of the DRW instruction, None for sprites cut from the rom
+    pub drawn_at: Option<u16>,
+}
+
+impl Sprite {
+    // Dxyn draws n rows, Dxy0 a 16x16 sprite
+    fn new(addr: u16, n: usize, drawn_at: Option<u16>) -> Self {
+        let (width, height) = if n == 0 { (16, 16) } else { (8, n) };
+        Self {
+            addr,
+            width,
+            height,
+            drawn_at,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.height * self.width / 8
+    }
+}
+
+// Sprites drawn by the rom, found by a LD I, nnn shortly before a DRW. Data can
+// be mixed with the code, so every byte is tried as start of an instruction.
+pub fn find(code: &[u8]) -> Vec<Sprite> {
+    let instr_at = |offset: usize| (code[offset] as u16) << 8 | code[offset + 1] as u16;
+    let end = code.len().saturating_sub(1);
+    let mut sprites: Vec<Sprite> = Vec::new();
+    for offset in 0..end {
+        let instr = instr_at(offset);
+        let addr = instr & 0x0FFF;
+        // the font is not part of the rom
+        if instr & 0xF000 != 0xA000 || addr < 0x200 || addr as usize >= 0x200 + code.len() {
+            continue;
+        }
+        for next in (offset + 2..end).step_by(2).take(LOOKAHEAD) {
+            let next_instr = instr_at(next);
+            match next_instr & 0xF000 {
+                0xD000 => {
+                    let sprite =
+                        Sprite::new(addr, (next_instr & 0xF) as usize, Some(0x200 + next as u16));
+                    if !sprites
+                        .iter()
+                        .any(|other| other.addr == sprite.addr && other.height == sprite.height)
+                    {
+                        sprites.push(sprite);
+                    }
+                    break;
+                }
+                // another sprite or the program continues elsewhere
+                0xA000 | 0x1000 | 0xB000 => break,
+                _ if next_instr == 0x00EE => break,
+                _ => {}
+            }
+        }
+    }
+    sprites.sort_by_key(|sprite| (sprite.addr, sprite.height));
+    sprites
+}
+
+// The whole rom as sprites of n rows, n = 0 for 16x16 sprites
+pub fn cut(code: &[u8], n: usize) -> Vec<Sprite> {
+    let len = Sprite::new(0, n, None).len();
+    (0..code.len())
+        .step_by(len)
+        .map(|offset| Sprite::new(0x200 + offset as u16, n, None))
+        .collect()
+}
+
+// The rows of a sprite with the leftmost pixel in the highest bit, memory
+// after the end of data is empty
+fn rows(data: &[u8], width: usize, height: usize) -> Vec<u16> {
+    let byte = |index: usize| *data.get(index).unwrap_or(&0) as u16;
+    (0..height)
+        .map(|row| {
+            if width == 16 {
+                byte(row * 2) << 8 | byte(row * 2 + 1)
+            } else {
+                byte(row) << 8
+            }
+        })
+        .collect()
+}
+
+// The sprite as text, # for set pixels
+pub fn ascii(data: &[u8], n: usize) -> Vec<String> {
+    let sprite = Sprite::new(0, n, None);
+    rows(data, sprite.width, sprite.height)
+        .into_iter()
+        .map(|row| {
+            (0..sprite.width)
+                .map(|x| if row & 0x8000 >> x != 0 { '#' } else { '.' })
+                .collect()
+        })
+        .collect()
+}
+
+// All sprites in rows of ROW_LENGTH, each in a cell of the size of the largest
+// sprite and separated by a gray line
+pub fn save_image(path: &Path, code: &[u8], sprites: &[Sprite]) -> Result<(), anyhow::Error> {
+    let cell_width = sprites.iter().map(|sprite| sprite.width).max().unwrap_or(8) + 1;
+    let cell_height = sprites
+        .iter()
+        .map(|sprite| sprite.height)
+        .max()
+        .unwrap_or(1)
+        + 1;
+    let columns = sprites.len().min(ROW_LENGTH);
+    let lines = sprites.chunks(ROW_LENGTH).count();
+    let width = columns * cell_width + 1;
+    let height = lines * cell_height + 1;
+    let mut pixels = vec![GAP; width * height];
+    for (index, sprite) in sprites.iter().enumerate() {
+        let left = index % ROW_LENGTH * cell_width + 1;
+        let top = index / ROW_LENGTH * cell_height + 1;
+        let data = &code[(sprite.addr - 0x200) as usize..];
+        for (y, row) in rows(data, sprite.width, sprite.height).iter().enumerate() {
+            for x in 0..sprite.width {
+                pixels[(top + y) * width + left + x] =
+                    if row & 0x8000 >> x != 0 { ON } else { OFF };
+            }
+        }
+    }
+
+    let mut image = Vec::with_capacity(pixels.len() * IMAGE_SCALE * IMAGE_SCALE);
+    for row in pixels.chunks(width) {
+        for _ in 0..IMAGE_SCALE {
+            for pixel in row {
+                image.extend_from_slice(&[*pixel; IMAGE_SCALE]);
+            }
+        }
+    }
+    screenshot::save(path, &image, width * IMAGE_SCALE, height * IMAGE_SCALE)
+}
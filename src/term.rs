@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+
+use ratatui::crossterm::cursor::{Hide, MoveTo, Show};
+use ratatui::crossterm::event::{
+    self, Event, KeyCode, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use ratatui::crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use ratatui::crossterm::terminal::{
+    self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::crossterm::{execute, queue};
+
+use super::cpu::VKey;
+use super::debugger::Command;
+use super::frontend::{Action, Frame, Frontend, Input};
+use super::gif::quantize;
+use super::hotkeys::{BoundHotkeys, HotkeyKey, Hotkeys};
+use super::keymap::Keymap;
+use super::overlay::Osd;
+use super::render::scale_nearest;
+
+// most terminals only report presses, a key counts as held this long after the
+// last press or key repeat
+const HOLD: Duration = Duration::from_millis(150);
+
+// bits of the dots of a Braille character by position in its 2x4 cell
+const BRAILLE_DOTS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+// bytes of base64 image data in one Kitty graphics command
+const KITTY_CHUNK: usize = 4096;
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TermStyle {
+    // two pixels on top of each other as a half block, with the upper pixel as
+    // foreground and the lower one as background color
+    HalfBlock,
+    // 2x4 pixels as the dots of a Braille character, in one color per character
+    Braille,
+    // the display as a sixel image, scaled by whole numbers to fill the terminal
+    Sixel,
+    // the display as an image of the Kitty graphics protocol, scaled like Sixel
+    Kitty,
+}
+
+impl FromStr for TermStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-block" => Ok(TermStyle::HalfBlock),
+            "braille" => Ok(TermStyle::Braille),
+            "sixel" => Ok(TermStyle::Sixel),
+            "kitty" => Ok(TermStyle::Kitty),
+            _ => bail!(
+                "Unknown terminal style {}, expected half-block, braille, sixel or kitty",
+                s
+            ),
+        }
+    }
+}
+
+// Display in the terminal
+pub struct TermScreen {
+    out: BufWriter<Stdout>,
+    style: TermStyle,
+    // characters of the CHIP-8 keys 0 - F, the terminal cannot read scancodes
+    keys: [char; 16],
+    // terminal reports key releases
+    releases: bool,
+    pressed: [Option<Instant>; 16],
+    hotkeys: BoundHotkeys<KeyCode>,
+    // last press of the turbo key
+    turbo: Option<Instant>,
+    actions: Vec<Action>,
+    size: (u16, u16),
+    // tmux draws sixel images itself, Kitty images have to pass through it
+    tmux: bool,
+    image: Vec<u32>,
+    // color of the note in the top right corner while the sound timer runs
+    sound_icon: Option<u32>,
+    buzzing: bool,
+    // message in the bottom row, e.g. of a save state
+    osd: Osd,
+    message_shown: bool,
+    redraw: bool,
+}
+
+impl TermScreen {
+    pub fn new(
+        style: TermStyle,
+        keymap: Keymap,
+        hotkeys: &Hotkeys,
+        sound_icon: Option<u32>,
+    ) -> Result<Self, anyhow::Error> {
+        terminal::enable_raw_mode()?;
+        let mut out = BufWriter::new(io::stdout());
+        execute!(out, EnterAlternateScreen, Hide)?;
+        let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if releases {
+            execute!(
+                out,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        Ok(Self {
+            out,
+            style,
+            keys: keymap.chars(),
+            releases,
+            pressed: [None; 16],
+            hotkeys: hotkeys.bind(hotkey_code),
+            turbo: None,
+            actions: Vec::new(),
+            size: (0, 0),
+            tmux: env::var_os("TMUX").is_some(),
+            image: Vec::new(),
+            sound_icon,
+            buzzing: false,
+            osd: Osd::new(),
+            message_shown: false,
+            redraw: false,
+        })
+    }
+
+    // Current state of the CHIP-8 keys, None when Escape or Ctrl+C was pressed
+    fn read_keys(&mut self) -> Result<Option<[VKey; 16]>, anyhow::Error> {
+        while event::poll(Duration::from_millis(0))? {
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                Event::Resize(..) => {
+                    // the next draw clears the screen
+                    self.size = (0, 0);
+                    continue;
+                }
+                _ => continue,
+            };
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.code == KeyCode::Esc || ctrl_c {
+                return Ok(None);
+            }
+            let code = match key.code {
+                KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+                code => code,
+            };
+            if let KeyCode::Char(c) = code {
+                if let Some(index) = self.keys.iter().position(|k| *k == c) {
+                    self.pressed[index] = match key.kind {
+                        KeyEventKind::Release => None,
+                        _ => Some(Instant::now()),
+                    };
+                }
+            }
+            if self.hotkeys.turbo == Some(code) {
+                self.turbo = match key.kind {
+                    KeyEventKind::Release => None,
+                    _ => Some(Instant::now()),
+                };
+            }
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            let hotkeys = &self.hotkeys;
+            let action = if hotkeys.pause == Some(code) {
+                Action::Debug(Command::TogglePause)
+            } else if hotkeys.reset == Some(code) {
+                Action::Debug(Command::Reset)
+            } else if hotkeys.rewind == Some(code) {
+                Action::Debug(Command::Rewind)
+            } else if hotkeys.slow_motion == Some(code) {
+                Action::ToggleSlowMotion
+            } else if hotkeys.record == Some(code) {
+                Action::ToggleGif
+            } else {
+                continue;
+            };
+            self.actions.push(action);
+        }
+
+        let mut keys = [VKey::Up; 16];
+        for (key, pressed) in keys.iter_mut().zip(self.pressed.iter()) {
+            if self.held(*pressed) {
+                *key = VKey::Down;
+            }
+        }
+        Ok(Some(keys))
+    }
+
+    fn held(&self, pressed: Option<Instant>) -> bool {
+        match pressed {
+            Some(_) if self.releases => true,
+            Some(time) => time.elapsed() < HOLD,
+            None => false,
+        }
+    }
+
+    fn draw(&mut self, display: &[u32], width: usize, height: usize) -> Result<(), anyhow::Error> {
+        let size = terminal::size()?;
+        if size != self.size {
+            self.size = size;
+            queue!(self.out, ResetColor, Clear(ClearType::All))?;
+        }
+        // the note is erased first, a display that reaches the corner covers it
+        let corner = MoveTo(size.0.saturating_sub(1), 0);
+        if self.sound_icon.is_some() {
+            queue!(self.out, corner, Print(' '))?;
+        }
+        let bottom = MoveTo(0, size.1.saturating_sub(1));
+        if self.message_shown {
+            queue!(self.out, ResetColor, bottom, Clear(ClearType::CurrentLine))?;
+            self.message_shown = false;
+        }
+        match self.style {
+            TermStyle::HalfBlock => self.draw_half_blocks(display, width, height)?,
+            TermStyle::Braille => self.draw_braille(display, width, height)?,
+            TermStyle::Sixel | TermStyle::Kitty => self.draw_image(display, width, height)?,
+        }
+        if let Some(color) = self.sound_icon.filter(|_| self.buzzing) {
+            queue!(
+                self.out,
+                ResetColor,
+                corner,
+                SetForegroundColor(rgb(color)),
+                Print('\u{266A}')
+            )?;
+        }
+        if let Some(text) = self.osd.message() {
+            let text: String = text.chars().take(size.0 as usize).collect();
+            queue!(self.out, ResetColor, bottom, Print(text))?;
+            self.message_shown = true;
+        }
+        queue!(self.out, ResetColor)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn draw_half_blocks(
+        &mut self,
+        display: &[u32],
+        width: usize,
+        height: usize,
+    ) -> Result<(), anyhow::Error> {
+        let size = self.size;
+        // a smaller terminal cuts off the right and bottom of the display
+        let columns = width.min(size.0 as usize);
+        let rows = (0..height).step_by(2).take(size.1 as usize);
+        let mut colors = None;
+        for (row, y) in rows.enumerate() {
+            queue!(self.out, MoveTo(0, row as u16))?;
+            for x in 0..columns {
+                let upper = display[y * width + x];
+                let lower = display.get((y + 1) * width + x).copied().unwrap_or(0);
+                // only color changes are sent to the terminal
+                if colors != Some((upper, lower)) {
+                    colors = Some((upper, lower));
+                    queue!(
+                        self.out,
+                        SetForegroundColor(rgb(upper)),
+                        SetBackgroundColor(rgb(lower))
+                    )?;
+                }
+                queue!(self.out, Print('\u{2580}'))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Pixels of the most common color are the background and stay empty, the
+    // dots of a character take the color of its first other pixel
+    fn draw_braille(
+        &mut self,
+        display: &[u32],
+        width: usize,
+        height: usize,
+    ) -> Result<(), anyhow::Error> {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for color in display {
+            *counts.entry(*color).or_insert(0) += 1;
+        }
+        let background = counts
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(color, _)| color)
+            .unwrap_or(0);
+        queue!(self.out, SetBackgroundColor(rgb(background)))?;
+
+        let columns = (0..width).step_by(2).take(self.size.0 as usize);
+        let rows = (0..height).step_by(4).take(self.size.1 as usize);
+        let mut foreground = None;
+        for (row, top) in rows.enumerate() {
+            queue!(self.out, MoveTo(0, row as u16))?;
+            for left in columns.clone() {
+                let mut dots = 0;
+                let mut color = None;
+                for (dy, bits) in BRAILLE_DOTS.iter().enumerate() {
+                    for (dx, bit) in bits.iter().enumerate() {
+                        let (x, y) = (left + dx, top + dy);
+                        if x >= width || y >= height {
+                            continue;
+                        }
+                        let pixel = display[y * width + x];
+                        if pixel != background {
+                            dots |= bit;
+                            color = color.or(Some(pixel));
+                        }
+                    }
+                }
+                if let Some(color) = color {
+                    if foreground != Some(color) {
+                        foreground = Some(color);
+                        queue!(self.out, SetForegroundColor(rgb(color)))?;
+                    }
+                }
+                let cell = std::char::from_u32(0x2800 + dots as u32).unwrap_or(' ');
+                queue!(self.out, Print(cell))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn draw_image(
+        &mut self,
+        display: &[u32],
+        width: usize,
+        height: usize,
+    ) -> Result<(), anyhow::Error> {
+        let size = terminal::window_size()?;
+        // terminals that do not tell their size in pixels get 8x16 pixel cells
+        let (pixel_width, pixel_height) = if size.width > 0 && size.height > 0 {
+            (size.width as usize, size.height as usize)
+        } else {
+            (size.columns as usize * 8, size.rows as usize * 16)
+        };
+        // the last line stays free, an image reaching into it scrolls the screen
+        let pixel_height = pixel_height - pixel_height / (size.rows as usize).max(1);
+        let factor = (pixel_width / width).min(pixel_height / height).max(1);
+        let (w, h) = (width * factor, height * factor);
+        self.image.resize(w * h, 0);
+        scale_nearest(display, width, height, &mut self.image, w, h);
+
+        queue!(self.out, MoveTo(0, 0))?;
+        let command = match self.style {
+            TermStyle::Sixel => sixel(&self.image, w, h),
+            _ => kitty(&self.image, w, h),
+        };
+        for part in command {
+            if self.tmux && self.style == TermStyle::Kitty {
+                // tmux passes on sequences wrapped in its own one with escapes doubled
+                self.out.write_all(b"\x1bPtmux;")?;
+                for byte in part {
+                    if byte == 0x1B {
+                        self.out.write_all(&[0x1B])?;
+                    }
+                    self.out.write_all(&[byte])?;
+                }
+                self.out.write_all(b"\x1b\\")?;
+            } else {
+                self.out.write_all(&part)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Escape or Ctrl+C quits, the hotkeys of --hotkeys without the save states work,
+// the other hotkeys are not available in the terminal
+impl Frontend for TermScreen {
+    fn poll(&mut self) -> Result<Option<Input>, anyhow::Error> {
+        let input = self.read_keys()?.map(|keys| Input {
+            keys,
+            actions: std::mem::take(&mut self.actions),
+            turbo: self.held(self.turbo),
+        });
+        Ok(input)
+    }
+
+    // the display is drawn again when the note or a message appears or disappears
+    fn present(&mut self, frame: &Frame) -> Result<(), anyhow::Error> {
+        let buzzing = self.sound_icon.is_some() && frame.buzzing;
+        let toggled = buzzing != self.buzzing;
+        self.buzzing = buzzing;
+        let expired = self.osd.expire();
+        if frame.changed || toggled || expired || self.redraw {
+            self.redraw = false;
+            self.draw(frame.display, frame.width, frame.height)?;
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, text: String) {
+        self.osd.show(text);
+        self.redraw = true;
+    }
+}
+
+impl Drop for TermScreen {
+    fn drop(&mut self) {
+        if self.releases {
+            let _ = execute!(self.out, PopKeyboardEnhancementFlags);
+        }
+        if self.style == TermStyle::Kitty {
+            // delete the image
+            let _ = self.out.write_all(b"\x1b_Ga=d,d=I,i=1,q=2\x1b\\");
+        }
+        let _ = execute!(self.out, ResetColor, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn rgb(color: u32) -> Color {
+    Color::Rgb {
+        r: (color >> 16) as u8,
+        g: (color >> 8) as u8,
+        b: color as u8,
+    }
+}
+
+// Sixel image with one color register per color, in bands of 6 pixel rows
+fn sixel(image: &[u32], width: usize, height: usize) -> Vec<Vec<u8>> {
+    let (palette, indices) = quantize(image);
+    let mut out = Vec::new();
+    // square pixels and the size of the image
+    out.extend_from_slice(format!("\x1bPq\"1;1;{};{}", width, height).as_bytes());
+    for (index, color) in palette.iter().enumerate() {
+        // the color components are given in percent
+        let percent = |shift: u32| ((color >> shift) & 0xFF) * 100 / 255;
+        let register = format!("#{};2;{};{};{}", index, percent(16), percent(8), percent(0));
+        out.extend_from_slice(register.as_bytes());
+    }
+    let mut sixels = Vec::with_capacity(width);
+    for top in (0..height).step_by(6) {
+        let bottom = (top + 6).min(height);
+        for index in 0..palette.len() {
+            // every bit is one of the pixels of the band in this color
+            sixels.clear();
+            sixels.extend((0..width).map(|x| {
+                (top..bottom)
+                    .filter(|y| indices[y * width + x] as usize == index)
+                    .fold(0u8, |bits, y| bits | 1 << (y - top))
+            }));
+            if sixels.iter().all(|bits| *bits == 0) {
+                continue;
+            }
+            out.extend_from_slice(format!("#{}", index).as_bytes());
+            // runs of the same sixel are repeated with !<count>
+            let mut x = 0;
+            while x < sixels.len() {
+                let run = sixels[x..]
+                    .iter()
+                    .take_while(|bits| **bits == sixels[x])
+                    .count();
+                let sixel = 0x3F + sixels[x];
+                if run > 3 {
+                    out.extend_from_slice(format!("!{}", run).as_bytes());
+                    out.push(sixel);
+                } else {
+                    for _ in 0..run {
+                        out.push(sixel);
+                    }
+                }
+                x += run;
+            }
+            // back to the start of the band for the next color
+            out.push(b'$');
+        }
+        out.push(b'-');
+    }
+    out.extend_from_slice(b"\x1b\\");
+    vec![out]
+}
+
+// Kitty graphics commands that replace the image with id 1 by the RGB pixels,
+// the data is split into several commands
+fn kitty(image: &[u32], width: usize, height: usize) -> Vec<Vec<u8>> {
+    let mut rgb = Vec::with_capacity(image.len() * 3);
+    for color in image {
+        rgb.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, *color as u8]);
+    }
+    let data = base64(&rgb);
+    let count = data.chunks(KITTY_CHUNK).count();
+    data.chunks(KITTY_CHUNK)
+        .enumerate()
+        .map(|(n, chunk)| {
+            let more = if n + 1 < count { 1 } else { 0 };
+            let mut command = if n == 0 {
+                // keep the cursor where it is, do not answer
+                format!(
+                    "\x1b_Ga=T,f=24,s={},v={},i=1,p=1,C=1,q=2,m={};",
+                    width, height, more
+                )
+            } else {
+                format!("\x1b_Gm={};", more)
+            }
+            .into_bytes();
+            command.extend_from_slice(chunk);
+            command.extend_from_slice(b"\x1b\\");
+            command
+        })
+        .collect()
+}
+
+fn base64(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for n in 0..4 {
+            if n <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * n)) as usize & 0x3F]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+    out
+}
+
+fn hotkey_code(key: HotkeyKey) -> Option<KeyCode> {
+    let code = match key {
+        HotkeyKey::Char(c) => KeyCode::Char(c),
+        HotkeyKey::F(n) => KeyCode::F(n),
+        HotkeyKey::Tab => KeyCode::Tab,
+        HotkeyKey::Backspace => KeyCode::Backspace,
+        HotkeyKey::Space => KeyCode::Char(' '),
+    };
+    Some(code)
+}
@@ -0,0 +1,270 @@
+use std::io;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use super::cpu::{Cpu, MEMSIZE};
+use super::disasm::disassemble;
+use super::heatmap::Heatmap;
+
+// Copy of the cpu state that is sent from the cpu thread to the debugger ui
+pub struct Snapshot {
+    pub pc: u16,
+    pub i: u32,
+    pub v: [u8; 16],
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub dt: u8,
+    pub st: u8,
+    pub frame: u64,
+    pub clock_steps: u64,
+    pub memory: Vec<u8>,
+    pub heatmap: Option<Heatmap>,
+}
+
+impl Snapshot {
+    pub fn from_cpu(cpu: &Cpu) -> Self {
+        let (dt, st) = cpu.timers();
+        Self {
+            pc: cpu.pc,
+            i: cpu.i,
+            v: cpu.v,
+            sp: cpu.sp,
+            stack: cpu.stack,
+            dt,
+            st,
+            frame: cpu.frame(),
+            clock_steps: cpu.clock_steps,
+            // the program counter only reaches the first 64K
+            memory: cpu.memory[..MEMSIZE].to_vec(),
+            heatmap: cpu.heatmap.clone(),
+        }
+    }
+
+    fn byte(&self, addr: usize) -> u8 {
+        self.memory[addr % self.memory.len()]
+    }
+
+    fn word(&self, addr: usize) -> u16 {
+        (self.byte(addr) as u16) << 8 | self.byte(addr + 1) as u16
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Watch {
+    pub addr: u16,
+    pub len: u16,
+}
+
+// Runs the terminal debugger ui until the snapshot channel is closed or q is
+// pressed, h switches between the disassembly and the memory heatmap
+pub fn run(rx_snapshot: Receiver<Snapshot>, watches: Vec<Watch>) -> Result<(), anyhow::Error> {
+    terminal::enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let result = event_loop(&mut terminal, rx_snapshot, &watches);
+    terminal::disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+fn event_loop(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    rx_snapshot: Receiver<Snapshot>,
+    watches: &[Watch],
+) -> Result<(), anyhow::Error> {
+    let mut snapshot = None;
+    let mut show_heatmap = false;
+    loop {
+        match rx_snapshot.recv_timeout(Duration::from_millis(50)) {
+            Ok(new_snapshot) => snapshot = Some(new_snapshot),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        if let Some(snapshot) = snapshot.as_ref() {
+            terminal.draw(|f| draw(f, snapshot, watches, show_heatmap))?;
+        }
+        while event::poll(Duration::from_millis(0))? {
+            if let Event::Key(key) = event::read()? {
+                match key.code {
+                    KeyCode::Char('q') => return Ok(()),
+                    KeyCode::Char('h') => show_heatmap = !show_heatmap,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn draw(f: &mut Frame, snapshot: &Snapshot, watches: &[Watch], show_heatmap: bool) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(12), Constraint::Length(10)])
+        .split(f.area());
+    let top = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(32), Constraint::Length(24)])
+        .split(rows[0]);
+    let bottom = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Length(14),
+            Constraint::Min(40),
+            Constraint::Length(40),
+        ])
+        .split(rows[1]);
+
+    match snapshot.heatmap.as_ref() {
+        Some(heatmap) if show_heatmap => draw_heatmap(f, top[0], snapshot, heatmap),
+        _ => draw_disassembly(f, top[0], snapshot),
+    }
+    draw_registers(f, top[1], snapshot);
+    draw_stack(f, bottom[0], snapshot);
+    draw_memory(f, bottom[1], snapshot);
+    draw_watches(f, bottom[2], snapshot, watches);
+}
+
+fn panel(title: &str) -> Block<'_> {
+    Block::default().borders(Borders::ALL).title(title)
+}
+
+fn draw_disassembly(f: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let rows = area.height.saturating_sub(2) as usize;
+    let start = snapshot.pc as usize + snapshot.memory.len() - (rows / 2) * 2;
+    let lines: Vec<Line> = (0..rows)
+        .map(|row| {
+            let addr = (start + row * 2) % snapshot.memory.len();
+            let instr = snapshot.word(addr);
+            let text = format!("{:04X}  {:04X}  {}", addr, instr, disassemble(instr));
+            if addr == snapshot.pc as usize {
+                Line::styled(
+                    format!("> {}", text),
+                    Style::default().add_modifier(Modifier::REVERSED),
+                )
+            } else {
+                Line::raw(format!("  {}", text))
+            }
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines).block(panel("Disassembly")), area);
+}
+
+// Each character shows two cells of memory, the upper and the lower half block,
+// colored by writes (red), reads (green) and executed instructions (blue)
+fn draw_heatmap(f: &mut Frame, area: Rect, snapshot: &Snapshot, heatmap: &Heatmap) {
+    let rows = area.height.saturating_sub(2) as usize;
+    // the address of a line is in front of it
+    let columns = area.width.saturating_sub(2 + 6) as usize;
+    let extent = heatmap.extent();
+    let mut cells_per_line = 64;
+    while cells_per_line > 1 && cells_per_line > columns {
+        cells_per_line /= 2;
+    }
+    let mut cell_size = 1;
+    while extent > cell_size * cells_per_line * rows.max(1) * 2 {
+        cell_size *= 2;
+    }
+    let line_size = cell_size * cells_per_line;
+    let color = |addr: usize| {
+        let (r, g, b) = heatmap.color(addr..addr + cell_size, snapshot.frame);
+        Color::Rgb(r, g, b)
+    };
+
+    let lines: Vec<Line> = (0..extent)
+        .step_by(line_size * 2)
+        .map(|upper| {
+            let lower = upper + line_size;
+            let mut spans = vec![Span::raw(format!("{:04X}  ", upper))];
+            for cell in 0..cells_per_line {
+                let offset = cell * cell_size;
+                let mut style = Style::default().fg(color(upper + offset));
+                if lower < extent {
+                    style = style.bg(color(lower + offset));
+                }
+                spans.push(Span::styled("\u{2580}", style));
+            }
+            Line::from(spans)
+        })
+        .collect();
+    let title = format!(
+        "Heatmap, {} bytes per cell: red write, green read, blue execute",
+        cell_size
+    );
+    f.render_widget(Paragraph::new(lines).block(panel(&title)), area);
+}
+
+fn draw_registers(f: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let mut lines: Vec<Line> = (0..8)
+        .map(|x| {
+            Line::raw(format!(
+                "V{:X} {:02X}    V{:X} {:02X}",
+                x,
+                snapshot.v[x],
+                x + 8,
+                snapshot.v[x + 8]
+            ))
+        })
+        .collect();
+    lines.push(Line::raw(""));
+    lines.push(Line::raw(format!(
+        "PC {:04X}   I {:04X}",
+        snapshot.pc, snapshot.i
+    )));
+    lines.push(Line::raw(format!(
+        "DT {:02X}     ST {:02X}",
+        snapshot.dt, snapshot.st
+    )));
+    lines.push(Line::raw(format!("frame  {}", snapshot.frame)));
+    lines.push(Line::raw(format!("cycles {}", snapshot.clock_steps)));
+    f.render_widget(Paragraph::new(lines).block(panel("Registers")), area);
+}
+
+fn draw_stack(f: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let lines: Vec<Line> = (1..=snapshot.sp as usize)
+        .rev()
+        .map(|level| Line::raw(format!("{:2} {:04X}", level, snapshot.stack[level])))
+        .collect();
+    f.render_widget(Paragraph::new(lines).block(panel("Stack")), area);
+}
+
+fn draw_memory(f: &mut Frame, area: Rect, snapshot: &Snapshot) {
+    let rows = area.height.saturating_sub(2) as usize;
+    let start = snapshot.i as usize & !0x7;
+    let lines: Vec<Line> = (0..rows)
+        .map(|row| {
+            let addr = start + row * 8;
+            let bytes: Vec<String> = (addr..addr + 8)
+                .map(|addr| format!("{:02X}", snapshot.byte(addr)))
+                .collect();
+            Line::raw(format!(
+                "{:04X}  {}",
+                addr % snapshot.memory.len(),
+                bytes.join(" ")
+            ))
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines).block(panel("Memory [I]")), area);
+}
+
+fn draw_watches(f: &mut Frame, area: Rect, snapshot: &Snapshot, watches: &[Watch]) {
+    let lines: Vec<Line> = watches
+        .iter()
+        .map(|watch| {
+            let bytes: Vec<String> = (0..watch.len as usize)
+                .map(|offset| format!("{:02X}", snapshot.byte(watch.addr as usize + offset)))
+                .collect();
+            Line::raw(format!("{:04X}  {}", watch.addr, bytes.join(" ")))
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines).block(panel("Watches")), area);
+}
@@ -0,0 +1,130 @@
+// Uncompressed video of the display in the YUV4MPEG2 format, one picture per
+// timer frame. Files not ending in .y4m are encoded by an ffmpeg child process.
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use anyhow::{bail, Context};
+
+use super::render::scale_nearest;
+
+// small displays are scaled up by whole numbers to about this width
+const TARGET_WIDTH: usize = 512;
+// ffmpeg reads the video from stdin, the output file follows
+const FFMPEG_ARGS: &[&str] = &["-loglevel", "error", "-y", "-f", "yuv4mpegpipe", "-i", "-"];
+
+enum Output {
+    File(BufWriter<File>),
+    Ffmpeg(Child, BufWriter<ChildStdin>),
+}
+
+pub struct VideoWriter {
+    output: Output,
+    width: usize,
+    height: usize,
+    scaled: Vec<u32>,
+    // Y, U and V planes without subsampling
+    picture: Vec<u8>,
+}
+
+impl VideoWriter {
+    // The size of the first display sets the size of the video
+    pub fn create(
+        path: &Path,
+        display_width: usize,
+        display_height: usize,
+        fps: f64,
+    ) -> Result<Self, anyhow::Error> {
+        let output = if path.extension() == Some(OsStr::new("y4m")) {
+            let file = File::create(path)
+                .with_context(|| format!("Could not create video {}", path.display()))?;
+            Output::File(BufWriter::new(file))
+        } else {
+            let mut child = Command::new("ffmpeg")
+                .args(FFMPEG_ARGS)
+                .arg(path)
+                .stdin(Stdio::piped())
+                .spawn()
+                .context("Could not start ffmpeg, use a .y4m file to record without it")?;
+            let stdin = child.stdin.take().context("Could not write to ffmpeg")?;
+            Output::Ffmpeg(child, BufWriter::new(stdin))
+        };
+        let factor = (TARGET_WIDTH / display_width).max(1);
+        let mut writer = Self {
+            output,
+            width: display_width * factor,
+            height: display_height * factor,
+            scaled: Vec::new(),
+            picture: Vec::new(),
+        };
+        let header = format!(
+            "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C444\n",
+            writer.width,
+            writer.height,
+            (fps * 1000.0).round() as u64
+        );
+        writer.out().write_all(header.as_bytes())?;
+        Ok(writer)
+    }
+
+    fn out(&mut self) -> &mut dyn Write {
+        match &mut self.output {
+            Output::File(out) => out,
+            Output::Ffmpeg(_, out) => out,
+        }
+    }
+
+    pub fn add_frame(
+        &mut self,
+        display: &[u32],
+        display_width: usize,
+        display_height: usize,
+    ) -> Result<(), anyhow::Error> {
+        let size = self.width * self.height;
+        self.scaled.resize(size, 0);
+        scale_nearest(
+            display,
+            display_width,
+            display_height,
+            &mut self.scaled,
+            self.width,
+            self.height,
+        );
+        // BT.601 with the video range of 16 - 235
+        self.picture.resize(size * 3, 0);
+        for (index, color) in self.scaled.iter().enumerate() {
+            let r = ((color >> 16) & 0xFF) as i32;
+            let g = ((color >> 8) & 0xFF) as i32;
+            let b = (color & 0xFF) as i32;
+            self.picture[index] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            self.picture[size + index] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+            self.picture[2 * size + index] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+        }
+        let picture = std::mem::take(&mut self.picture);
+        let out = self.out();
+        let result = out
+            .write_all(b"FRAME\n")
+            .and_then(|_| out.write_all(&picture));
+        self.picture = picture;
+        result.context("Could not write video frame")
+    }
+
+    // Flushes the file or waits until ffmpeg has encoded the video
+    pub fn finish(self) -> Result<(), anyhow::Error> {
+        match self.output {
+            Output::File(mut out) => out.flush().context("Could not write video")?,
+            Output::Ffmpeg(mut child, mut stdin) => {
+                stdin.flush().context("Could not write to ffmpeg")?;
+                // closing stdin ends the input of ffmpeg
+                drop(stdin);
+                let status = child.wait().context("ffmpeg failed")?;
+                if !status.success() {
+                    bail!("ffmpeg failed with {}", status);
+                }
+            }
+        }
+        Ok(())
+    }
+}
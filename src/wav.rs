@@ -0,0 +1,88 @@
+// Uncompressed WAV files for --buzzer-wav, decoded into mono 16 bit samples
+use std::convert::TryInto;
+
+use anyhow::{bail, Context};
+
+// WAVE_FORMAT_PCM and WAVE_FORMAT_EXTENSIBLE, which also holds PCM samples
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+#[derive(Clone, Debug)]
+pub struct Wav {
+    pub samples: Vec<i16>,
+    pub rate: f64,
+}
+
+impl Wav {
+    // 8, 16 or 24 bit PCM, the channels are mixed into one
+    pub fn parse(data: &[u8]) -> Result<Self, anyhow::Error> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            bail!("Not a WAV file");
+        }
+        let mut format = None;
+        let mut samples = None;
+        let mut pos = 12;
+        while pos + 8 <= data.len() {
+            let id = &data[pos..pos + 4];
+            let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let chunk = data
+                .get(pos + 8..pos + 8 + len)
+                .context("WAV file is truncated")?;
+            match id {
+                b"fmt " if len >= 16 => format = Some(chunk),
+                b"data" => samples = Some(chunk),
+                _ => {}
+            }
+            // chunks are padded to an even length
+            pos += 8 + len + len % 2;
+        }
+        let format = format.context("WAV file has no fmt chunk")?;
+        let samples = samples.context("WAV file has no data chunk")?;
+
+        let word = |offset: usize| u16::from_le_bytes([format[offset], format[offset + 1]]);
+        let tag = word(0);
+        let channels = word(2) as usize;
+        let rate = u32::from_le_bytes(format[4..8].try_into().unwrap());
+        let bits = word(14) as usize;
+        if tag != FORMAT_PCM && tag != FORMAT_EXTENSIBLE {
+            bail!(
+                "Unsupported WAV format {:#X}, expected uncompressed PCM",
+                tag
+            );
+        }
+        if bits != 8 && bits != 16 && bits != 24 {
+            bail!(
+                "Unsupported WAV sample size of {} bits, expected 8, 16 or 24",
+                bits
+            );
+        }
+        if channels == 0 || rate == 0 {
+            bail!("WAV file has no channels or no sample rate");
+        }
+
+        let size = bits / 8;
+        let samples = samples
+            .chunks_exact(size * channels)
+            .map(|frame| {
+                let sum: i32 = frame.chunks_exact(size).map(sample).sum();
+                (sum / channels as i32) as i16
+            })
+            .collect::<Vec<_>>();
+        if samples.is_empty() {
+            bail!("WAV file has no samples");
+        }
+        Ok(Self {
+            samples,
+            rate: rate as f64,
+        })
+    }
+}
+
+// 8 bit samples are unsigned, larger ones signed, all are reduced to 16 bit
+fn sample(bytes: &[u8]) -> i32 {
+    match bytes.len() {
+        1 => (bytes[0] as i32 - 128) << 8,
+        2 => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+        _ => i16::from_le_bytes([bytes[1], bytes[2]]) as i32,
+    }
+}
@@ -0,0 +1,442 @@
+// The display in a minifb window, scaled and filtered by the renderer with the
+// overlays drawn on top
+use anyhow::Context;
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+
+use super::cpu::{VKey, HEIGHT, WIDTH};
+use super::debugger::Command;
+use super::emulator::Emulator;
+use super::frontend::{Action, Frame, Frontend, Input, SoundIndicator};
+use super::hotkeys::{BoundHotkeys, HotkeyKey, Modifier};
+use super::overlay::{Canvas, Osd};
+use super::palette::BUZZER_COLOR;
+use super::render::{Phosphor, Renderer};
+
+// the flag asks for shift to be held, F11 is left to fullscreen
+const DEBUGGER_KEYS: [(Key, bool, Command, &str); 4] = [
+    (Key::F5, false, Command::Resume, "Running"),
+    (Key::F6, false, Command::Pause, "Paused"),
+    (Key::F10, false, Command::Step, "Step"),
+    (Key::F10, true, Command::StepFrame, "Step frame"),
+];
+// the save state slots 1 - 9 with the modifiers of the hotkeys
+const SLOT_KEYS: [Key; 9] = [
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+];
+
+// The picture of a window, the display scaled by the renderer with the overlays
+// on top, shared by the window libraries
+pub struct View {
+    renderer: Renderer,
+    phosphor: Option<Phosphor>,
+    buzzer_color: Option<u32>,
+    quiet_color: u32,
+    // color of the note shown while the sound timer runs
+    sound_icon: Option<u32>,
+    buzzing: bool,
+    osd: Osd,
+    show_keypad: bool,
+    keys: [VKey; 16],
+    // the keyboard keys shown on the keypad
+    labels: [char; 16],
+    size: (usize, usize),
+    redraw: bool,
+}
+
+impl View {
+    pub fn new(emulator: &Emulator) -> Self {
+        let mut renderer = Renderer::new(
+            emulator.texture_filter,
+            emulator.post_filter,
+            emulator.scaling,
+        );
+        renderer.border = emulator.quiet_color;
+        let indicator_color = emulator.buzzer_color.unwrap_or(BUZZER_COLOR);
+        let buzzer_color = match emulator.sound_indicator {
+            Some(SoundIndicator::Border) => Some(indicator_color),
+            _ => emulator.buzzer_color,
+        };
+        let sound_icon = match emulator.sound_indicator {
+            Some(SoundIndicator::Icon) => Some(indicator_color),
+            _ => None,
+        };
+        Self {
+            renderer,
+            phosphor: emulator.phosphor_decay.map(Phosphor::new),
+            buzzer_color,
+            quiet_color: emulator.quiet_color,
+            sound_icon,
+            buzzing: false,
+            osd: Osd::new(),
+            show_keypad: emulator.show_keypad,
+            keys: [VKey::Up; 16],
+            labels: emulator.keymap.chars(),
+            size: (0, 0),
+            redraw: true,
+        }
+    }
+
+    // F1
+    pub fn toggle_keypad(&mut self) {
+        self.show_keypad = !self.show_keypad;
+        self.redraw = true;
+    }
+
+    // F2
+    pub fn next_texture_filter(&mut self) {
+        self.renderer.filter = self.renderer.filter.next();
+        self.message(format!("Texture filter {}", self.renderer.filter));
+    }
+
+    // F3
+    pub fn next_post_filter(&mut self) {
+        self.renderer.post_filter = self.renderer.post_filter.next();
+        self.message(format!("Filter {}", self.renderer.post_filter));
+    }
+
+    // The pressed keys are highlighted on the keypad
+    pub fn set_keys(&mut self, keys: [VKey; 16]) {
+        if self.show_keypad && keys != self.keys {
+            self.redraw = true;
+        }
+        self.keys = keys;
+    }
+
+    pub fn message(&mut self, text: String) {
+        self.osd.show(text);
+        self.redraw = true;
+    }
+
+    // e.g. for a new window
+    pub fn force_redraw(&mut self) {
+        self.redraw = true;
+    }
+
+    // The picture for a window of the given size, None when the window still
+    // shows the current one
+    pub fn render(&mut self, frame: &Frame, size: (usize, usize)) -> Option<&[u32]> {
+        if frame.changed || self.osd.expire() {
+            self.redraw = true;
+        }
+        if let Some(buzzer_color) = self.buzzer_color {
+            let border = if frame.buzzing {
+                buzzer_color
+            } else {
+                self.quiet_color
+            };
+            if border != self.renderer.border {
+                self.renderer.border = border;
+                self.redraw = true;
+            }
+        }
+        if self.sound_icon.is_some() && frame.buzzing != self.buzzing {
+            self.redraw = true;
+        }
+        self.buzzing = frame.buzzing;
+        if size != self.size {
+            self.size = size;
+            self.redraw = true;
+        }
+        if !self.redraw || size.0 == 0 || size.1 == 0 {
+            return None;
+        }
+
+        if let Some(phosphor) = self.phosphor.as_mut() {
+            phosphor.update(frame.display, frame.width, frame.height);
+        }
+        // keep drawing until the afterglow has faded
+        self.redraw = matches!(&self.phosphor, Some(phosphor) if phosphor.is_fading());
+        let src = match self.phosphor.as_ref() {
+            Some(phosphor) => phosphor.buffer(),
+            None => frame.display,
+        };
+        let buffer = self
+            .renderer
+            .render(src, frame.width, frame.height, size.0, size.1);
+        let mut canvas = Canvas::new(buffer, size.0, size.1);
+        if self.show_keypad {
+            canvas.keypad(&self.keys, &self.labels);
+        }
+        if !frame.perf_text.is_empty() {
+            canvas.corner_text(frame.perf_text);
+        }
+        if let Some(color) = self.sound_icon.filter(|_| frame.buzzing) {
+            canvas.sound_icon(color);
+        }
+        if let Some(message) = self.osd.message() {
+            canvas.message(message);
+        }
+        Some(buffer)
+    }
+}
+
+pub struct WindowFrontend {
+    window: Window,
+    // minifb reads the keys by the characters of the layout, not by position
+    keys: [Option<Key>; 16],
+    hotkeys: BoundHotkeys<Key>,
+    fullscreen: bool,
+    screen_size: (usize, usize),
+    scale: usize,
+    view: View,
+}
+
+impl WindowFrontend {
+    pub fn new(emulator: &Emulator) -> Result<Self, anyhow::Error> {
+        let window = create_window(
+            emulator.fullscreen,
+            emulator.screen_size,
+            emulator.window_scale,
+        )?;
+        let keys = emulator.keymap.chars().map(char_key);
+        let missing: String = emulator
+            .keymap
+            .chars()
+            .iter()
+            .zip(keys.iter())
+            .filter(|(_, key)| key.is_none())
+            .map(|(c, _)| *c)
+            .collect();
+        if !missing.is_empty() {
+            eprintln!(
+                "Warning: the minifb window cannot read the keys {}, --backend sdl2 or wgpu \
+                 read the keys by position",
+                missing
+            );
+        }
+        Ok(Self {
+            window,
+            keys,
+            hotkeys: emulator.hotkeys.bind(hotkey_key),
+            fullscreen: emulator.fullscreen,
+            screen_size: emulator.screen_size,
+            scale: emulator.window_scale,
+            view: View::new(emulator),
+        })
+    }
+}
+
+impl Frontend for WindowFrontend {
+    // Escape or closing the window quits
+    fn poll(&mut self) -> Result<Option<Input>, anyhow::Error> {
+        if !self.window.is_open() || self.window.is_key_down(Key::Escape) {
+            return Ok(None);
+        }
+        let mut keys = convert_keys(&self.window, &self.keys);
+        if self.window.is_key_pressed(Key::F1, KeyRepeat::No) {
+            self.view.toggle_keypad();
+        }
+        if self.window.is_key_pressed(Key::F2, KeyRepeat::No) {
+            self.view.next_texture_filter();
+        }
+        if self.window.is_key_pressed(Key::F3, KeyRepeat::No) {
+            self.view.next_post_filter();
+        }
+        let alt = self.window.is_key_down(Key::LeftAlt) || self.window.is_key_down(Key::RightAlt);
+        let enter = alt && self.window.is_key_pressed(Key::Enter, KeyRepeat::No);
+        if enter || self.window.is_key_pressed(Key::F11, KeyRepeat::No) {
+            self.fullscreen = !self.fullscreen;
+            // minifb cannot change a window, it is replaced by a new one
+            self.window = create_window(self.fullscreen, self.screen_size, self.scale)?;
+            self.view.force_redraw();
+        }
+
+        let mut actions = Vec::new();
+        if pressed(&self.window, self.hotkeys.record, KeyRepeat::No) {
+            actions.push(Action::ToggleGif);
+        }
+        if self.window.is_key_pressed(Key::F4, KeyRepeat::No) {
+            actions.push(Action::Invert);
+        }
+        if pressed(&self.window, self.hotkeys.pause, KeyRepeat::No) {
+            actions.push(Action::Debug(Command::TogglePause));
+        }
+        if pressed(&self.window, self.hotkeys.slow_motion, KeyRepeat::No) {
+            actions.push(Action::ToggleSlowMotion);
+        }
+        if self.window.is_key_pressed(Key::F7, KeyRepeat::Yes) {
+            actions.push(Action::Darker);
+        }
+        if self.window.is_key_pressed(Key::F8, KeyRepeat::Yes) {
+            actions.push(Action::Brighter);
+        }
+        let shift =
+            self.window.is_key_down(Key::LeftShift) || self.window.is_key_down(Key::RightShift);
+        for (key, with_shift, command, name) in DEBUGGER_KEYS.iter() {
+            if self.window.is_key_pressed(*key, KeyRepeat::Yes) && shift == *with_shift {
+                actions.push(Action::Debug(*command));
+                self.view.message(name.to_string());
+            }
+        }
+        if pressed(&self.window, self.hotkeys.rewind, KeyRepeat::Yes) {
+            actions.push(Action::Debug(Command::Rewind));
+            self.view.message("Rewind".to_string());
+        }
+        if pressed(&self.window, self.hotkeys.reset, KeyRepeat::No) {
+            actions.push(Action::Debug(Command::Reset));
+            self.view.message("Reset".to_string());
+        }
+        let ctrl =
+            self.window.is_key_down(Key::LeftCtrl) || self.window.is_key_down(Key::RightCtrl);
+        let held = |modifier| match modifier {
+            Modifier::Shift => shift,
+            Modifier::Ctrl => ctrl,
+            Modifier::Alt => alt,
+        };
+        for (slot, key) in (1..).zip(SLOT_KEYS.iter()) {
+            if !self.window.is_key_pressed(*key, KeyRepeat::No) {
+                continue;
+            }
+            if let Some(command) = self.hotkeys.slot_command(slot, held) {
+                actions.push(Action::Debug(command));
+            }
+        }
+        if self.hotkeys.slot_modifier_held(held) {
+            keys = [VKey::Up; 16];
+        }
+        self.view.set_keys(keys);
+        Ok(Some(Input {
+            keys,
+            actions,
+            turbo: matches!(self.hotkeys.turbo, Some(key) if self.window.is_key_down(key)),
+        }))
+    }
+
+    fn present(&mut self, frame: &Frame) -> Result<(), anyhow::Error> {
+        let size = self.window.get_size();
+        match self.view.render(frame, size) {
+            Some(buffer) => self
+                .window
+                .update_with_buffer(buffer, size.0, size.1)
+                .context("Updating minifb display buffer failed")?,
+            None => self.window.update(),
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, text: String) {
+        self.view.message(text);
+    }
+}
+
+// minifb has no fullscreen mode, a borderless window of --screen-size in the
+// top left corner stands in for it because minifb does not know the size of the
+// screen
+fn create_window(
+    fullscreen: bool,
+    screen_size: (usize, usize),
+    scale: usize,
+) -> Result<Window, anyhow::Error> {
+    let options = WindowOptions {
+        scale: Scale::X1,
+        resize: !fullscreen,
+        borderless: fullscreen,
+        title: !fullscreen,
+        topmost: fullscreen,
+        ..WindowOptions::default()
+    };
+    let (width, height) = if fullscreen {
+        screen_size
+    } else {
+        (WIDTH * scale, HEIGHT * scale)
+    };
+    let mut window = Window::new("CHIP8 - ESC to exit", width, height, options)
+        .context("Could not create minifb window")?;
+    if fullscreen {
+        window.set_position(0, 0);
+    }
+    window.limit_update_rate(None);
+    Ok(window)
+}
+
+fn convert_keys(window: &Window, keys: &[Option<Key>; 16]) -> [VKey; 16] {
+    let mut cpu_keys = [VKey::Up; 16];
+    for (cpu_key, key) in cpu_keys.iter_mut().zip(keys.iter()) {
+        if matches!(key, Some(key) if window.is_key_down(*key)) {
+            *cpu_key = VKey::Down;
+        }
+    }
+    cpu_keys
+}
+
+fn pressed(window: &Window, key: Option<Key>, repeat: KeyRepeat) -> bool {
+    matches!(key, Some(key) if window.is_key_pressed(key, repeat))
+}
+
+fn hotkey_key(key: HotkeyKey) -> Option<Key> {
+    let function_keys = [
+        Key::F1,
+        Key::F2,
+        Key::F3,
+        Key::F4,
+        Key::F5,
+        Key::F6,
+        Key::F7,
+        Key::F8,
+        Key::F9,
+        Key::F10,
+        Key::F11,
+        Key::F12,
+    ];
+    match key {
+        HotkeyKey::Char(c) => char_key(c),
+        HotkeyKey::F(n) => function_keys.get(n as usize - 1).copied(),
+        HotkeyKey::Tab => Some(Key::Tab),
+        HotkeyKey::Backspace => Some(Key::Backspace),
+        HotkeyKey::Space => Some(Key::Space),
+    }
+}
+
+// The minifb key of a character of the keymap, minifb only knows the keys of a
+// US keyboard
+fn char_key(c: char) -> Option<Key> {
+    let key = match c {
+        '0' => Key::Key0,
+        '1' => Key::Key1,
+        '2' => Key::Key2,
+        '3' => Key::Key3,
+        '4' => Key::Key4,
+        '5' => Key::Key5,
+        '6' => Key::Key6,
+        '7' => Key::Key7,
+        '8' => Key::Key8,
+        '9' => Key::Key9,
+        'a' => Key::A,
+        'b' => Key::B,
+        'c' => Key::C,
+        'd' => Key::D,
+        'e' => Key::E,
+        'f' => Key::F,
+        'g' => Key::G,
+        'h' => Key::H,
+        'i' => Key::I,
+        'j' => Key::J,
+        'k' => Key::K,
+        'l' => Key::L,
+        'm' => Key::M,
+        'n' => Key::N,
+        'o' => Key::O,
+        'p' => Key::P,
+        'q' => Key::Q,
+        'r' => Key::R,
+        's' => Key::S,
+        't' => Key::T,
+        'u' => Key::U,
+        'v' => Key::V,
+        'w' => Key::W,
+        'x' => Key::X,
+        'y' => Key::Y,
+        'z' => Key::Z,
+        '\'' => Key::Apostrophe,
+        _ => return None,
+    };
+    Some(key)
+}